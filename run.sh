@@ -1,5 +1,15 @@
 #!/bin/bash
 
-rm -rf ./blockchain_data/
-cargo run
-rm -rf ./blockchain_data/
+DATADIR=./demo_data
+CLI="cargo run -q -- --datadir $DATADIR"
+
+rm -rf $DATADIR
+ALICE=$($CLI createwallet | awk '{print $NF}')
+BOB=$($CLI createwallet | awk '{print $NF}')
+$CLI createblockchain $ALICE
+$CLI send $ALICE $BOB 3
+$CLI getbalance $ALICE
+$CLI getbalance $BOB
+$CLI listunspent $ALICE
+$CLI printchain
+rm -rf $DATADIR
@@ -0,0 +1,189 @@
+/* # Address
+ *
+ * An address is the text form of a public key hash, which users copy around:
+ *   base58(version (1 byte) + pub_key_hash (20 bytes) + checksum (4 bytes))
+ *
+ * The version byte tells the network, so coins for the test network can not be sent
+ * to a main network address by accident.
+ * The checksum is the first 4 bytes of sha256(sha256(version + pub_key_hash)).
+ * A mistyped character changes the checksum, so the address is rejected instead of
+ * locking coins to a hash nobody has the key for.
+ */
+use crate::error::{Error, Result};
+use crate::utils::{base58_encode, ripemd160_digest, sha256_digest};
+use std::fmt;
+use std::str::FromStr;
+
+pub const ADDRESS_CHECK_SUM_LENGTH: usize = 4;
+/// Length of ripemd160 hash of the public key
+pub const PUB_KEY_HASH_LENGTH: usize = 20;
+/// Length of the decoded address: version + pub_key_hash + checksum
+pub const ADDRESS_LENGTH: usize = 1 + PUB_KEY_HASH_LENGTH + ADDRESS_CHECK_SUM_LENGTH;
+
+/// The network an address belongs to, each one has its own version byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Network {
+    Main,
+    Test,
+}
+
+/// The network of the addresses of our wallets, and of the outputs in our chain
+pub const DEFAULT_NETWORK: Network = Network::Main;
+
+impl Network {
+    /// The version byte: 0x00 for the main network, 0x6f for the test network
+    pub fn get_version(&self) -> u8 {
+        match self {
+            Network::Main => 0x00,
+            Network::Test => 0x6f,
+        }
+    }
+
+    pub fn from_version(version: u8) -> Option<Network> {
+        match version {
+            0x00 => Some(Network::Main),
+            0x6f => Some(Network::Test),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Main => write!(f, "main"),
+            Network::Test => write!(f, "test"),
+        }
+    }
+}
+
+/// The reason why a text is not a valid address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    /// A character is not in the base58 alphabet
+    InvalidBase58,
+    /// The decoded address is not `ADDRESS_LENGTH` bytes
+    InvalidLength { found: usize },
+    /// The version byte is not one of a known network
+    UnknownVersion { version: u8 },
+    /// The checksum does not match, usually a typo
+    InvalidChecksum,
+    /// The address is valid, but for another network
+    WrongNetwork { expected: Network, found: Network },
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::InvalidBase58 => write!(f, "not base58"),
+            AddressError::InvalidLength { found } => write!(
+                f,
+                "decoded length must be {} bytes, found {}",
+                ADDRESS_LENGTH, found
+            ),
+            AddressError::UnknownVersion { version } => {
+                write!(f, "unknown version byte 0x{:02x}", version)
+            }
+            AddressError::InvalidChecksum => write!(f, "checksum does not match"),
+            AddressError::WrongNetwork { expected, found } => write!(
+                f,
+                "address of the {} network, expected the {} network",
+                found, expected
+            ),
+        }
+    }
+}
+
+/// A decoded and checked address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+    network: Network,
+    pub_key_hash: [u8; PUB_KEY_HASH_LENGTH],
+}
+
+impl Address {
+    pub fn new(network: Network, pub_key_hash: [u8; PUB_KEY_HASH_LENGTH]) -> Address {
+        Address {
+            network,
+            pub_key_hash,
+        }
+    }
+
+    /// The address of a public key: ripemd160(sha256(pub_key))
+    pub fn from_pub_key(network: Network, pub_key: &[u8]) -> Address {
+        let mut pub_key_hash = [0u8; PUB_KEY_HASH_LENGTH];
+        pub_key_hash.copy_from_slice(&ripemd160_digest(&sha256_digest(pub_key)));
+        Address::new(network, pub_key_hash)
+    }
+
+    pub fn get_network(&self) -> Network {
+        self.network
+    }
+
+    pub fn get_pub_key_hash(&self) -> &[u8] {
+        &self.pub_key_hash
+    }
+
+    /// Fails if the address is not of `network`
+    pub fn require_network(self, network: Network) -> Result<Address> {
+        if self.network != network {
+            return Err(AddressError::WrongNetwork {
+                expected: network,
+                found: self.network,
+            }
+            .into());
+        }
+        Ok(self)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut payload = vec![self.network.get_version()];
+        payload.extend(self.pub_key_hash);
+        let checksum = checksum(&payload);
+        payload.extend(checksum);
+        write!(f, "{}", base58_encode(&payload))
+    }
+}
+
+/// Decode the base58 text, then check the length, the version byte and the checksum
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Address> {
+        let payload = bs58::decode(s)
+            .into_vec()
+            .map_err(|_| AddressError::InvalidBase58)?;
+        if payload.len() != ADDRESS_LENGTH {
+            return Err(AddressError::InvalidLength {
+                found: payload.len(),
+            }
+            .into());
+        }
+        let (body, expected) = payload.split_at(1 + PUB_KEY_HASH_LENGTH);
+        if checksum(body) != expected {
+            return Err(AddressError::InvalidChecksum.into());
+        }
+        let network = Network::from_version(body[0])
+            .ok_or(AddressError::UnknownVersion { version: body[0] })?;
+        let mut pub_key_hash = [0u8; PUB_KEY_HASH_LENGTH];
+        pub_key_hash.copy_from_slice(&body[1..]);
+        Ok(Address::new(network, pub_key_hash))
+    }
+}
+
+/// Check that `address` is a valid address of the `DEFAULT_NETWORK`
+pub fn validate_address(address: &str) -> Result<()> {
+    address
+        .parse::<Address>()?
+        .require_network(DEFAULT_NETWORK)?;
+    Ok(())
+}
+
+/// First 4 bytes of sha256(sha256(payload))
+pub fn checksum(payload: &[u8]) -> Vec<u8> {
+    let first_sha = sha256_digest(payload);
+    let second_sha = sha256_digest(first_sha.as_slice());
+    second_sha[0..ADDRESS_CHECK_SUM_LENGTH].to_vec()
+}
@@ -1,156 +1,319 @@
+use crate::error::{Error, Result};
+use crate::hash::{BlockHash, MerkleRoot, Txid};
+use crate::merkle::{MerkleProof, MerkleTree};
 use crate::transaction::Transaction;
-use crate::{utils::hex_encode, utils::sha256_digest};
 use num_bigint::BigInt;
 use serde::{Deserialize, Serialize};
-use sled::IVec;
+use std::ops::RangeInclusive;
 
-const DIFFICULTY: usize = 2;
+/// Difficulty of the genesis block
+pub const INITIAL_BITS: u32 = 2;
+/// Bounds of the difficulty, so retargeting can never make mining impossible or free
+pub const MIN_BITS: u32 = 1;
+pub const MAX_BITS: u32 = 64;
+/// The difficulty is adjusted every `RETARGET_INTERVAL` blocks
+pub const RETARGET_INTERVAL: usize = 10;
+/// Expected seconds between two blocks
+pub const TARGET_BLOCK_INTERVAL: u64 = 10;
+/// A block timestamp must be greater than the median timestamp of the last `MEDIAN_TIME_SPAN` blocks
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// A block timestamp can be at most `MAX_FUTURE_BLOCK_TIME` seconds ahead of the local clock
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+/// Version of the block header format
+pub const BLOCK_VERSION: u32 = 1;
 
 // fields:
-//   - timestamp: Timestamp of the block
-//   - pre_block_hash: Previous block hash
-//   - hash: Current block hash
-//   - transactions: Vector of transactions
+//   - version: Version of the header format
+//   - pre_block_hash: Previous block hash, all zero for the genesis block
+//   - merkle_root: Merkle root of the transaction ids in the block body
+//   - timestamp: Timestamp of the block, seconds since the unix epoch
+//   - bits: difficulty of the block, the hash must be less than 2^(256 - bits),
+//     which means it starts with at least `bits` zero bits
 //   - nonce: miner need modify this value to get a hash that less than target
 //   - height: Height of the block, it is the index of the block in the chain
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct Block {
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct BlockHeader {
+    version: u32,
+    pre_block_hash: BlockHash,
+    merkle_root: MerkleRoot,
     timestamp: u64,
-    pre_block_hash: String,
-    hash: String,
-    transactions: Vec<Transaction>,
+    bits: u32,
     nonce: i64,
     height: usize,
 }
 
-impl Block {
-    pub fn new(pre_block_hash: String, transactions: &[Transaction], height: usize) -> Block {
-        let mut block = Block {
-            timestamp: 0,
+impl BlockHeader {
+    /// A header for `transactions` with nonce 0, see `ProofOfWork` to find the nonce
+    pub fn new(
+        pre_block_hash: BlockHash,
+        transactions: &[Transaction],
+        height: usize,
+        bits: u32,
+        timestamp: u64,
+    ) -> BlockHeader {
+        BlockHeader {
+            version: BLOCK_VERSION,
             pre_block_hash,
-            hash: String::new(),
-            transactions: transactions.to_vec(),
+            merkle_root: merkle_root(transactions),
+            timestamp,
+            bits,
             nonce: 0,
             height,
-        };
-        // Proof of Work
-        // The miner need modify the nonce from 0 to N,
-        //   until the hash of the block is less than target
-        let pow = ProofOfWork::new(block.clone());
-        let (nonce, hash) = pow.run();
-        block.hash = hash;
-        block.nonce = nonce;
-        block
+        }
     }
 
-    pub fn generate_genesis_block(coinbase_tx: Transaction) -> Block {
-        let transactions = vec![coinbase_tx.clone()];
-        Block::new(String::from("None"), &transactions, 0)
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn get_pre_block_hash(&self) -> BlockHash {
+        self.pre_block_hash
+    }
+
+    pub fn get_merkle_root(&self) -> MerkleRoot {
+        self.merkle_root
     }
 
     pub fn get_timestamp(&self) -> u64 {
         self.timestamp
     }
 
-    pub fn get_pre_block_hash(&self) -> String {
-        self.pre_block_hash.clone()
+    pub fn get_bits(&self) -> u32 {
+        self.bits
     }
 
-    pub fn get_hash(&self) -> &str {
-        self.hash.as_str()
+    pub fn get_nonce(&self) -> i64 {
+        self.nonce
     }
 
     pub fn get_height(&self) -> usize {
         self.height
     }
 
-    pub fn get_transactions(&self) -> &[Transaction] {
-        &self.transactions
+    /// The block hash only depends on the header,
+    /// the transactions are committed to by the merkle root
+    pub fn hash(&self) -> BlockHash {
+        BlockHash::digest(&self.prepare_data(self.nonce))
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        if let Ok(serialized) = bincode::serialize(&self) {
-            serialized.to_vec()
-        } else {
-            panic!("Failed to serialize block");
+    fn prepare_data(&self, nonce: i64) -> Vec<u8> {
+        let mut data_bytes = vec![];
+        data_bytes.extend(self.version.to_be_bytes());
+        data_bytes.extend(self.pre_block_hash.as_bytes());
+        data_bytes.extend(self.merkle_root.as_bytes());
+        data_bytes.extend(self.timestamp.to_be_bytes());
+        // the hash commits to the difficulty, so it can not be changed after mining
+        data_bytes.extend(self.bits.to_be_bytes());
+        data_bytes.extend((self.height as u64).to_be_bytes());
+        data_bytes.extend(nonce.to_be_bytes());
+        data_bytes
+    }
+
+    /// Expected number of hashes to find a block with these `bits`: 2^bits.
+    /// `bits` is at most `MAX_BITS`, so the work of a long chain still fits into u128.
+    pub fn get_work(&self) -> u128 {
+        1u128 << self.bits.min(MAX_BITS)
+    }
+
+    /// Check the header hash is less than the target of its `bits`
+    pub fn validate_pow(&self) -> bool {
+        if self.bits < MIN_BITS || self.bits > MAX_BITS {
+            return false;
         }
+        ProofOfWork::new(self.clone()).validate()
     }
 
-    pub fn deserialize(data: &[u8]) -> Block {
-        if let Ok(deserialized) = bincode::deserialize(data) {
-            deserialized
-        } else {
-            panic!("Failed to deserialize block");
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self)?)
+    }
+
+    /// Bad data is reported as `Error::Corruption`, since headers are read back from the db
+    pub fn deserialize(data: &[u8]) -> Result<BlockHeader> {
+        bincode::deserialize(data)
+            .map_err(|err| Error::Corruption(format!("failed to deserialize header: {}", err)))
+    }
+}
+
+// fields:
+//   - header: the part of the block which is hashed, see `BlockHeader`
+//   - hash: Current block hash, the hash of the header
+//   - transactions: Vector of transactions, the block body
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Block {
+    header: BlockHeader,
+    hash: BlockHash,
+    transactions: Vec<Transaction>,
+}
+
+impl Block {
+    pub fn new(
+        pre_block_hash: BlockHash,
+        transactions: &[Transaction],
+        height: usize,
+        bits: u32,
+        timestamp: u64,
+    ) -> Block {
+        let mut header = BlockHeader::new(pre_block_hash, transactions, height, bits, timestamp);
+        // Proof of Work
+        // The miner need modify the nonce from 0 to N,
+        //   until the hash of the block is less than target.
+        // If no nonce works, the timestamp goes up by one second, which gives new hashes.
+        // See `MiningJob` for a miner which uses several threads.
+        loop {
+            let pow = ProofOfWork::new(header);
+            if let Some((nonce, hash)) = pow.run(0..=i64::MAX) {
+                return Block {
+                    header: pow.into_header(nonce),
+                    hash,
+                    transactions: transactions.to_vec(),
+                };
+            }
+            header = pow.into_header(0);
+            header.timestamp += 1;
         }
     }
 
-    pub fn hash_transactions(&self) -> Vec<u8> {
-        let mut tx_hashes = Vec::new();
-        for tx in &self.transactions {
-            tx_hashes.extend(tx.get_id());
+    /// Put a header and a body back together, e.g. after reading them from the db
+    pub fn from_parts(header: BlockHeader, transactions: Vec<Transaction>) -> Block {
+        Block {
+            hash: header.hash(),
+            header,
+            transactions,
         }
-        sha256_digest(&tx_hashes)
+    }
+
+    pub fn generate_genesis_block(coinbase_tx: Transaction, timestamp: u64) -> Block {
+        let transactions = vec![coinbase_tx.clone()];
+        Block::new(
+            BlockHash::default(),
+            &transactions,
+            0,
+            INITIAL_BITS,
+            timestamp,
+        )
+    }
+
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    pub fn get_timestamp(&self) -> u64 {
+        self.header.timestamp
+    }
+
+    pub fn get_pre_block_hash(&self) -> BlockHash {
+        self.header.pre_block_hash
+    }
+
+    pub fn get_hash(&self) -> BlockHash {
+        self.hash
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.header.height
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.header.bits
+    }
+
+    pub fn get_transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self)?)
+    }
+
+    /// Bad data is reported as `Error::Corruption`, since blocks are read back from the db
+    pub fn deserialize(data: &[u8]) -> Result<Block> {
+        bincode::deserialize(data)
+            .map_err(|err| Error::Corruption(format!("failed to deserialize block: {}", err)))
+    }
+
+    /// Recompute the hash from the header,
+    /// then check it is the stored hash and less than target
+    pub fn validate_pow(&self) -> bool {
+        self.header.validate_pow() && self.header.hash() == self.hash
+    }
+
+    /// Merkle root of the transaction ids, recomputed from the body.
+    /// A valid block has the same root in its header.
+    pub fn hash_transactions(&self) -> MerkleRoot {
+        merkle_root(&self.transactions)
+    }
+
+    /// Proof that the transaction is in this block, checked against `hash_transactions`
+    pub fn merkle_proof(&self, txid: &Txid) -> Option<MerkleProof> {
+        merkle_tree(&self.transactions).proof(txid)
     }
 
     pub fn print(&self) {
-        println!("timestamp: {}", self.timestamp);
-        println!("pre_block_hash: {}", self.pre_block_hash);
+        println!("version: {}", self.header.version);
+        println!("timestamp: {}", self.header.timestamp);
+        println!("pre_block_hash: {}", self.header.pre_block_hash);
         println!("hash: {}", self.hash);
-        println!("nonce: {}", self.nonce);
-        println!("height: {}", self.height);
+        println!("merkle_root: {}", self.header.merkle_root);
+        println!("nonce: {}", self.header.nonce);
+        println!("height: {}", self.header.height);
+        println!("bits: {}", self.header.bits);
         for tx in &self.transactions {
             tx.print();
         }
     }
 }
 
-impl From<Block> for IVec {
-    fn from(block: Block) -> IVec {
-        let bytes = block.serialize();
-        Self::from(bytes)
-    }
+fn merkle_tree(transactions: &[Transaction]) -> MerkleTree {
+    let txids: Vec<Txid> = transactions.iter().map(|tx| tx.get_id()).collect();
+    MerkleTree::new(&txids)
 }
 
-struct ProofOfWork {
-    block: Block,
+fn merkle_root(transactions: &[Transaction]) -> MerkleRoot {
+    merkle_tree(transactions).root()
+}
+
+/// The search for a nonce which makes the header hash less than the target of its `bits`
+pub struct ProofOfWork {
+    header: BlockHeader,
     target: BigInt,
 }
 
 impl ProofOfWork {
-    pub fn new(block: Block) -> ProofOfWork {
-        let target = BigInt::from(1) << (256 - DIFFICULTY);
-        ProofOfWork { block, target }
+    pub fn new(header: BlockHeader) -> ProofOfWork {
+        let target = BigInt::from(1) << (256 - header.bits as usize);
+        ProofOfWork { header, target }
     }
 
-    fn prepare_data(&self, nonce: i64) -> Vec<u8> {
-        let pre_block_hash = self.block.get_pre_block_hash();
-        let transactions_hash = self.block.hash_transactions();
-        let timestamp = self.block.get_timestamp();
-        let mut data_bytes = vec![];
-        data_bytes.extend(pre_block_hash.as_bytes());
-        data_bytes.extend(transactions_hash);
-        data_bytes.extend(timestamp.to_be_bytes());
-        data_bytes.extend(DIFFICULTY.to_be_bytes()); // TODO: why add difficulty?
-        data_bytes.extend(nonce.to_be_bytes());
-        return data_bytes;
+    /// The hash of the header with `nonce`, if it is less than the target
+    pub fn try_nonce(&self, nonce: i64) -> Option<BlockHash> {
+        let hash = BlockHash::digest(&self.header.prepare_data(nonce));
+        let hash_int = BigInt::from_bytes_be(num_bigint::Sign::Plus, hash.as_bytes());
+        if hash_int < self.target {
+            Some(hash)
+        } else {
+            None
+        }
     }
 
-    pub fn run(&self) -> (i64, String) {
-        let mut nonce = 0;
-        let mut hash: Vec<u8>;
-        println!("Target: {}, Mining block.......", self.target);
-        loop {
-            let data = self.prepare_data(nonce);
-            hash = sha256_digest(&data);
-            let hash_int = BigInt::from_bytes_be(num_bigint::Sign::Plus, &hash);
-            if hash_int < self.target {
-                println!("Found hash: {}", hex_encode(&hash));
-                break;
-            } else {
-                nonce += 1;
-            }
+    /// Try the nonces in order, return the first one which works with its hash
+    pub fn run(&self, nonces: RangeInclusive<i64>) -> Option<(i64, BlockHash)> {
+        nonces
+            .into_iter()
+            .find_map(|nonce| self.try_nonce(nonce).map(|hash| (nonce, hash)))
+    }
+
+    /// The header with the nonce that was found
+    pub fn into_header(self, nonce: i64) -> BlockHeader {
+        BlockHeader {
+            nonce,
+            ..self.header
         }
-        println!();
-        return (nonce, hex_encode(&hash));
+    }
+
+    pub fn validate(&self) -> bool {
+        let hash = self.header.hash();
+        let hash_int = BigInt::from_bytes_be(num_bigint::Sign::Plus, hash.as_bytes());
+        hash_int < self.target
     }
 }
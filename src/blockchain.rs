@@ -1,165 +1,1204 @@
-use crate::block::Block;
-use crate::transaction::{TXOutput, Transaction};
-use data_encoding::HEXLOWER;
-use sled::transaction::TransactionResult;
+use crate::block::{
+    Block, BlockHeader, MAX_BITS, MAX_FUTURE_BLOCK_TIME, MEDIAN_TIME_SPAN, MIN_BITS,
+    RETARGET_INTERVAL, TARGET_BLOCK_INTERVAL,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::error::{Error, Result};
+use crate::hash::{BlockHash, Txid};
+use crate::miner::{BlockTemplate, MinerConfig, MiningJob};
+use crate::transaction::{get_block_subsidy, PrevOutputs, TXOutput, Transaction};
+use crate::utxo_set::UtxoSet;
+use crate::wallet::{decode_address, Wallet};
+use sled::transaction::{ConflictableTransactionError, Transactional};
 /// BlockChain
-use sled::{Db, Tree};
-use std::collections::HashMap;
-use std::env::current_dir;
-use std::sync::{Arc, RwLock};
+use sled::Db;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Height of the block with a transaction, and its unspent (vout, TXOutput)
+pub type HeightAndOutputs = (usize, Vec<(usize, TXOutput)>);
 
 pub const DB_NAME: &str = "blockchain_data";
-pub const BLOCKS_TREE_NAME: &str = "blockchain";
+/// Block headers by block hash, and the tip block hash under `TIP_BLOCK_HASH_KEY`
+pub const HEADERS_TREE_NAME: &str = "headers";
+/// Block bodies (the transactions) by block hash
+pub const BODIES_TREE_NAME: &str = "bodies";
 pub const TIP_BLOCK_HASH_KEY: &str = "tip_block_hash";
+/// Whole blocks by block hash, written by versions before headers and bodies were split.
+/// Those blocks are hashed in another way, so they can not be moved into the new trees.
+pub const LEGACY_BLOCKS_TREE_NAME: &str = "blockchain";
+/// Cumulative work of the chain up to and including each block, by block hash, u128 big endian
+pub const CHAIN_WORK_TREE_NAME: &str = "chain_work";
+/// Blocks which failed validation when a reorg tried to connect them, by block hash
+pub const INVALID_BLOCKS_TREE_NAME: &str = "invalid_blocks";
+/// Number of consecutive hashes at the start of a block locator, see `get_block_locator`
+pub const LOCATOR_DENSE_COUNT: usize = 10;
+/// Number of headers before a header which `check_header` needs:
+/// the median time past and the retarget interval
+pub const HEADER_CONTEXT_LENGTH: usize = if MEDIAN_TIME_SPAN > RETARGET_INTERVAL {
+    MEDIAN_TIME_SPAN
+} else {
+    RETARGET_INTERVAL
+};
+
+/// The reason why a block is rejected by `BlockChain::validate_block`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockValidationError {
+    /// The block hash is not the hash of its data, or it is not less than the target
+    InvalidProofOfWork,
+    /// `pre_block_hash` is not the hash of the current tip block,
+    /// or for `add_block`, not the hash of any stored block
+    PrevHashMismatch {
+        expected: BlockHash,
+        found: BlockHash,
+    },
+    /// `height` is not the height of the previous block plus one
+    InvalidHeight { expected: usize, found: usize },
+    /// The timestamp is not greater than the median timestamp of the last blocks
+    TimestampTooOld { median_time_past: u64, found: u64 },
+    /// The timestamp is too far in the future of the local clock
+    TimestampTooNew { max: u64, found: u64 },
+    /// `bits` is not the difficulty given by the retarget rule
+    InvalidBits { expected: u32, found: u32 },
+    /// The id of a transaction is not the hash of the transaction
+    InvalidTxid { txid: Txid },
+    /// The merkle root in the header is not the root of the transactions in the body
+    InvalidMerkleRoot,
+    /// Only one coinbase transaction is allowed in a block
+    MultipleCoinbase,
+    /// The coinbase must be the first transaction of the block
+    CoinbaseNotFirst,
+    /// The coinbase does not have the height of the block
+    InvalidCoinbaseHeight {
+        expected: usize,
+        found: Option<usize>,
+    },
+    /// The coinbase pays more than the subsidy plus the fees of the block
+    InvalidCoinbaseValue { max: i64, found: i64 },
+    /// An output has a negative value
+    NegativeOutput { txid: Txid },
+    /// A transaction other than the coinbase has no inputs or no outputs
+    EmptyInputsOrOutputs { txid: Txid },
+    /// The outputs of the transaction are worth more than its inputs, the fee would be negative
+    OutputsExceedInputs { txid: Txid },
+    /// An input refers to an output which is neither in the UTXO set nor created by
+    /// an earlier transaction of the block, so it does not exist or it is already spent
+    MissingInput { txid: Txid, vout: usize },
+    /// The signature of an input does not match the output it spends
+    InvalidSignature { txid: Txid },
+    /// An output is spent twice inside the block
+    DoubleSpend { txid: Txid, vout: usize },
+    /// The block is, or builds on, a block which failed validation before
+    InvalidParent { hash: BlockHash },
+}
+
+impl fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockValidationError::InvalidProofOfWork => write!(f, "invalid proof of work"),
+            BlockValidationError::PrevHashMismatch { expected, found } => write!(
+                f,
+                "previous block hash mismatch, expected {}, found {}",
+                expected, found
+            ),
+            BlockValidationError::InvalidHeight { expected, found } => {
+                write!(f, "invalid height, expected {}, found {}", expected, found)
+            }
+            BlockValidationError::TimestampTooOld {
+                median_time_past,
+                found,
+            } => write!(
+                f,
+                "timestamp {} is not after median time past {}",
+                found, median_time_past
+            ),
+            BlockValidationError::TimestampTooNew { max, found } => {
+                write!(f, "timestamp {} is later than {}", found, max)
+            }
+            BlockValidationError::InvalidBits { expected, found } => {
+                write!(f, "invalid bits, expected {}, found {}", expected, found)
+            }
+            BlockValidationError::InvalidTxid { txid } => {
+                write!(
+                    f,
+                    "transaction id {} is not the hash of the transaction",
+                    txid
+                )
+            }
+            BlockValidationError::InvalidMerkleRoot => write!(f, "invalid merkle root"),
+            BlockValidationError::MultipleCoinbase => write!(f, "more than one coinbase"),
+            BlockValidationError::CoinbaseNotFirst => {
+                write!(f, "coinbase is not the first transaction")
+            }
+            BlockValidationError::InvalidCoinbaseHeight { expected, found } => write!(
+                f,
+                "invalid coinbase height, expected {}, found {:?}",
+                expected, found
+            ),
+            BlockValidationError::InvalidCoinbaseValue { max, found } => {
+                write!(f, "coinbase pays {}, but at most {} is allowed", found, max)
+            }
+            BlockValidationError::NegativeOutput { txid } => {
+                write!(f, "transaction {} has a negative output", txid)
+            }
+            BlockValidationError::EmptyInputsOrOutputs { txid } => {
+                write!(f, "transaction {} has no inputs or no outputs", txid)
+            }
+            BlockValidationError::OutputsExceedInputs { txid } => {
+                write!(f, "outputs of transaction {} exceed its inputs", txid)
+            }
+            BlockValidationError::MissingInput { txid, vout } => {
+                write!(f, "input {}:{} does not exist or is spent", txid, vout)
+            }
+            BlockValidationError::InvalidSignature { txid } => {
+                write!(f, "invalid signature in transaction {}", txid)
+            }
+            BlockValidationError::DoubleSpend { txid, vout } => {
+                write!(f, "output {}:{} is already spent", txid, vout)
+            }
+            BlockValidationError::InvalidParent { hash } => {
+                write!(f, "block {} failed validation before", hash)
+            }
+        }
+    }
+}
+
+/// What `add_block` did with a valid block
+#[derive(Debug, Clone)]
+pub enum BlockStatus {
+    /// The block extends the best chain, it is the new tip
+    Connected,
+    /// The block is stored in a side chain, which does not have more work than the best chain
+    SideChain,
+    /// The block's branch has more work, the best chain is switched to it.
+    /// `disconnected` goes from the old tip down, `connected` from the fork up to the new tip.
+    Reorganized {
+        disconnected: Vec<Block>,
+        connected: Vec<Block>,
+    },
+    /// The block is already stored, and its chain does not have more work than the best chain
+    AlreadyKnown,
+}
 
-/// In BlockChain struct, we record two fileds:
-///   1. tip_hash: the hash of the last block
+/// In BlockChain struct, we record five fields:
+///   1. tip_hash: the hash of the last block of the best chain
 ///   2. db: sled::Db, the database to store the blockchain data
-/// Once we want to iterator the blockchain, we chould search the block data from the db by the hash
+///   3. clock: the time source to stamp and check blocks
+///   4. update_lock: only one block is added at a time, e.g. by the miner and by the network
+///   5. utxo_set_rebuilt: the UTXO set was rebuilt when the db was opened, see `UtxoSet::migrate`
+///
+/// Once we want to iterator the blockchain, we chould search the block data from the db by the hash.
+/// Headers and bodies are in different trees, so the chain can be walked by headers only.
+/// Blocks of side chains are stored in the same trees, only the tip decides which chain is the best.
 pub struct BlockChain {
-    tip_hash: Arc<RwLock<String>>, // the hash of the last block
+    tip_hash: Arc<RwLock<BlockHash>>, // the hash of the last block
     db: Db,
+    clock: Arc<dyn Clock>,
+    update_lock: Mutex<()>,
+    utxo_set_rebuilt: bool,
 }
 
 impl BlockChain {
-    /// If we already have DB on current_dir, we will open it and get the tip block hash.
-    /// If not, create a new DB and generate a genesis block.
-    pub fn create_blockchain(genesis_address: &str) -> BlockChain {
-        let db = sled::open(current_dir().unwrap().join(DB_NAME)).unwrap();
-        let blocks_tree = db.open_tree(BLOCKS_TREE_NAME).unwrap();
-        let block_data = blocks_tree.get(TIP_BLOCK_HASH_KEY).unwrap();
-
-        let tip_hash = if block_data.is_none() {
-            println!("Database not found, Create a new blockchain");
-            println!("using address: {} as the genesis address", genesis_address);
-            let coinbase_tx = Transaction::new_coinbase_tx(genesis_address);
-            let genesis_block = Block::generate_genesis_block(coinbase_tx);
-            Self::update_blocks_tree(&blocks_tree, &genesis_block);
-            String::from(genesis_block.get_hash())
-        } else {
-            String::from_utf8(block_data.unwrap().to_vec()).unwrap()
-        };
+    /// Open the DB at `path` and get the tip block hash.
+    /// If there is no blockchain in the DB yet, generate a genesis block for `genesis_address`.
+    pub fn create<P: AsRef<Path>>(path: P, genesis_address: &str) -> Result<BlockChain> {
+        // the db directory is only created for a valid address
+        decode_address(genesis_address)?;
+        let db = sled::open(path)?;
+        Self::create_with_db(db, genesis_address, Arc::new(SystemClock))
+    }
 
-        BlockChain {
+    /// Open the blockchain which is already at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<BlockChain> {
+        let db = sled::open(path)?;
+        Self::check_legacy_layout(&db)?;
+        let headers_tree = db.open_tree(HEADERS_TREE_NAME)?;
+        let tip_hash = headers_tree
+            .get(TIP_BLOCK_HASH_KEY)?
+            .ok_or(Error::BlockchainNotFound)?;
+        let tip_hash = Self::decode_tip_hash(&tip_hash)?;
+        let mut blockchain = BlockChain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
             db,
+            clock: Arc::new(SystemClock),
+            update_lock: Mutex::new(()),
+            utxo_set_rebuilt: false,
+        };
+        blockchain.utxo_set_rebuilt = UtxoSet::new(&blockchain).migrate()?;
+        Ok(blockchain)
+    }
+
+    /// Create a blockchain in a temporary DB, which is removed when the blockchain is dropped.
+    /// Each temporary blockchain has its own DB, so tests and nodes can run side by side.
+    pub fn create_temporary(genesis_address: &str) -> Result<BlockChain> {
+        Self::create_temporary_with_clock(genesis_address, Arc::new(SystemClock))
+    }
+
+    /// Same as `create_temporary`, but blocks are stamped and checked with `clock`
+    pub fn create_temporary_with_clock(
+        genesis_address: &str,
+        clock: Arc<dyn Clock>,
+    ) -> Result<BlockChain> {
+        let db = sled::Config::new().temporary(true).open()?;
+        Self::create_with_db(db, genesis_address, clock)
+    }
+
+    fn create_with_db(db: Db, genesis_address: &str, clock: Arc<dyn Clock>) -> Result<BlockChain> {
+        // a new genesis block must not be written next to the blocks of an old chain
+        Self::check_legacy_layout(&db)?;
+        let headers_tree = db.open_tree(HEADERS_TREE_NAME)?;
+        if let Some(data) = headers_tree.get(TIP_BLOCK_HASH_KEY)? {
+            let tip_hash = Self::decode_tip_hash(&data)?;
+            let mut blockchain = BlockChain {
+                tip_hash: Arc::new(RwLock::new(tip_hash)),
+                db,
+                clock,
+                update_lock: Mutex::new(()),
+                utxo_set_rebuilt: false,
+            };
+            blockchain.utxo_set_rebuilt = UtxoSet::new(&blockchain).migrate()?;
+            return Ok(blockchain);
+        }
+
+        let coinbase_tx = Transaction::new_coinbase_tx(genesis_address, 0, 0)?;
+        let genesis_block = Block::generate_genesis_block(coinbase_tx, clock.now());
+        Self::update_blocks_tree(&db, &genesis_block, genesis_block.get_header().get_work())?;
+        let blockchain = BlockChain {
+            tip_hash: Arc::new(RwLock::new(genesis_block.get_hash())),
+            db,
+            clock,
+            update_lock: Mutex::new(()),
+            utxo_set_rebuilt: false,
+        };
+        // the genesis block only becomes the tip in the db with its UTXO set
+        let utxo_set = UtxoSet::new(&blockchain);
+        utxo_set.update(&genesis_block)?;
+        utxo_set.migrate()?;
+        Ok(blockchain)
+    }
+
+    /// Reject a db which still has blocks in the tree of the old layout,
+    /// otherwise it would look like a db without a blockchain
+    fn check_legacy_layout(db: &Db) -> Result<()> {
+        let has_legacy_tree = db
+            .tree_names()
+            .iter()
+            .any(|name| name.as_ref() == LEGACY_BLOCKS_TREE_NAME.as_bytes());
+        if has_legacy_tree && !db.open_tree(LEGACY_BLOCKS_TREE_NAME)?.is_empty() {
+            return Err(Error::IncompatibleDatabase(format!(
+                "the blocks are in the old \"{}\" tree, remove the db and sync the chain again",
+                LEGACY_BLOCKS_TREE_NAME
+            )));
         }
+        Ok(())
+    }
+
+    fn decode_tip_hash(data: &[u8]) -> Result<BlockHash> {
+        BlockHash::from_slice(data)
+            .map_err(|err| Error::Corruption(format!("invalid tip block hash: {}", err)))
     }
 
     pub fn iterator(&self) -> BlockchainIterator {
         BlockchainIterator::new(self.get_tip_hash(), self.db.clone())
     }
 
-    fn update_blocks_tree(blocks_tree: &Tree, block: &Block) {
+    /// Walk the headers from the tip, without reading the block bodies
+    pub fn header_iterator(&self) -> HeaderIterator {
+        HeaderIterator::new(self.get_tip_hash(), self.db.clone())
+    }
+
+    /// Write the header, the body and the chain work of the block in one transaction.
+    /// The tip is not moved, it is moved together with the UTXO set, see `UtxoSet::update`.
+    fn update_blocks_tree(db: &Db, block: &Block, chain_work: u128) -> Result<()> {
+        let headers_tree = db.open_tree(HEADERS_TREE_NAME)?;
+        let bodies_tree = db.open_tree(BODIES_TREE_NAME)?;
+        let work_tree = db.open_tree(CHAIN_WORK_TREE_NAME)?;
         let block_hash = block.get_hash();
-        let _: TransactionResult<(), ()> = blocks_tree.transaction(|tx| {
-            let _ = tx.insert(block_hash, block.clone());
-            let _ = tx.insert(TIP_BLOCK_HASH_KEY, block_hash);
-            Ok(())
-        });
+        let header_data = block.get_header().serialize()?;
+        let body_data = bincode::serialize(block.get_transactions())?;
+        (&headers_tree, &bodies_tree, &work_tree).transaction(|(headers, bodies, works)| {
+            headers.insert(block_hash.as_ref(), header_data.as_slice())?;
+            bodies.insert(block_hash.as_ref(), body_data.as_slice())?;
+            works.insert(block_hash.as_ref(), &chain_work.to_be_bytes())?;
+            Ok::<(), ConflictableTransactionError<Error>>(())
+        })?;
+        Ok(())
     }
 
-    pub fn get_tip_hash(&self) -> String {
-        self.tip_hash.read().unwrap().clone()
+    pub fn get_tip_hash(&self) -> BlockHash {
+        *self.tip_hash.read().unwrap()
     }
 
-    pub fn set_tip_hash(&self, new_tip_hash: &str) {
+    pub fn set_tip_hash(&self, new_tip_hash: &BlockHash) {
         let mut tip_hash = self.tip_hash.write().unwrap();
-        *tip_hash = String::from(new_tip_hash);
+        *tip_hash = *new_tip_hash;
+    }
+
+    /// Make a stored block the tip, in the db and in memory
+    fn write_tip_hash(&self, block_hash: &BlockHash) -> Result<()> {
+        let headers_tree = self.db.open_tree(HEADERS_TREE_NAME)?;
+        headers_tree.insert(TIP_BLOCK_HASH_KEY, block_hash.as_ref())?;
+        self.set_tip_hash(block_hash);
+        Ok(())
     }
 
     pub fn get_db(&self) -> &Db {
         &self.db
     }
 
-    pub fn get_best_height(&self) -> usize {
-        // Read the tip block from the db
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE_NAME).unwrap();
-        let tip_block_data = blocks_tree.get(self.get_tip_hash()).unwrap();
-        let tip_block: Block = Block::deserialize(&tip_block_data.unwrap());
-        tip_block.get_height()
+    /// True if the UTXO set was rebuilt from the chain when the db was opened,
+    /// e.g. it had an older schema, so opening took longer
+    pub fn is_utxo_set_rebuilt(&self) -> bool {
+        self.utxo_set_rebuilt
     }
 
-    pub fn mine_block(&self, transactions: &[Transaction]) -> Block {
-        let best_height = self.get_best_height();
-        let block: Block = Block::new(self.get_tip_hash(), transactions, best_height + 1);
-        let block_hash = block.get_hash();
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE_NAME).unwrap();
-        Self::update_blocks_tree(&blocks_tree, &block);
-        self.set_tip_hash(block_hash);
-        block
+    pub fn get_clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
     }
 
-    /// Return a hashmap
-    /// The key is the txid_hex
-    /// The value is a vector of TXOutput
-    pub fn find_utxo(&self) -> HashMap<String, Vec<TXOutput>> {
-        let mut utxo: HashMap<String, Vec<TXOutput>> = HashMap::new();
-        let mut spent_txos: HashMap<String, Vec<usize>> = HashMap::new();
+    /// Read a block header from the db by its hash
+    pub fn get_header(&self, block_hash: &BlockHash) -> Result<Option<BlockHeader>> {
+        read_header(&self.db, block_hash)
+    }
+
+    /// Read a block from the db by its hash
+    pub fn get_block(&self, block_hash: &BlockHash) -> Result<Option<Block>> {
+        read_block(&self.db, block_hash)
+    }
+
+    fn get_tip_header(&self) -> Result<BlockHeader> {
+        let tip_hash = self.get_tip_hash();
+        self.get_header(&tip_hash)?
+            .ok_or_else(|| Error::Corruption(format!("tip block {} is missing", tip_hash)))
+    }
+
+    pub fn get_best_height(&self) -> Result<usize> {
+        Ok(self.get_tip_header()?.get_height())
+    }
+
+    /// Hash of the first block of the chain
+    pub fn get_genesis_hash(&self) -> Result<BlockHash> {
+        match self.header_iterator().last() {
+            Some(header) => Ok(header?.hash()),
+            None => Err(Error::Corruption(String::from("genesis block is missing"))),
+        }
+    }
+
+    /// Hashes of the best chain from the tip back to the genesis block, which a peer uses
+    /// to find the last block we have in common.
+    /// The first `LOCATOR_DENSE_COUNT` hashes are consecutive, then the gap doubles each time,
+    /// so even a long chain needs few hashes. The genesis block is always the last one.
+    pub fn get_block_locator(&self) -> Result<Vec<BlockHash>> {
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut next_height = self.get_best_height()?;
+        let mut genesis_hash = None;
+        for header in self.header_iterator() {
+            let header = header?;
+            if header.get_height() == next_height {
+                locator.push(header.hash());
+                if locator.len() >= LOCATOR_DENSE_COUNT {
+                    step *= 2;
+                }
+                next_height = next_height.saturating_sub(step);
+            }
+            if header.get_height() == 0 {
+                genesis_hash = Some(header.hash());
+            }
+        }
+        if let Some(genesis_hash) = genesis_hash {
+            if locator.last() != Some(&genesis_hash) {
+                locator.push(genesis_hash);
+            }
+        }
+        Ok(locator)
+    }
+
+    /// Hashes of the best chain after the fork with a peer, oldest first:
+    ///   1. the fork is the first hash of `locator` which is on our best chain,
+    ///      or the genesis block if there is none
+    ///   2. the hashes after the fork are returned up to `stop` (included),
+    ///      and at most `max_count` of them
+    pub fn get_block_hashes_after(
+        &self,
+        locator: &[BlockHash],
+        stop: &BlockHash,
+        max_count: usize,
+    ) -> Result<Vec<BlockHash>> {
+        let headers = self.get_headers_after(locator, stop, max_count)?;
+        Ok(headers.iter().map(|header| header.hash()).collect())
+    }
+
+    /// Same as `get_block_hashes_after`, but the headers instead of their hashes
+    pub fn get_headers_after(
+        &self,
+        locator: &[BlockHash],
+        stop: &BlockHash,
+        max_count: usize,
+    ) -> Result<Vec<BlockHeader>> {
+        let locator: HashSet<&BlockHash> = locator.iter().collect();
+        let mut headers = vec![];
+        for header in self.header_iterator() {
+            let header = header?;
+            if header.get_height() == 0 || locator.contains(&header.hash()) {
+                break;
+            }
+            headers.push(header);
+        }
+        headers.reverse();
+        if let Some(pos) = headers.iter().position(|header| header.hash() == *stop) {
+            headers.truncate(pos + 1);
+        }
+        headers.truncate(max_count);
+        Ok(headers)
+    }
 
-        let mut iterator = self.iterator();
+    /// Cumulative work of the chain from the genesis block up to the stored block.
+    /// Blocks stored before the work was recorded get it computed and stored here.
+    pub fn get_chain_work(&self, block_hash: &BlockHash) -> Result<u128> {
+        let work_tree = self.db.open_tree(CHAIN_WORK_TREE_NAME)?;
+        let mut missing = vec![];
+        let mut current_hash = *block_hash;
+        let mut chain_work = 0u128;
         loop {
-            let option = iterator.next();
-            if option.is_none() {
+            if let Some(data) = work_tree.get(current_hash)? {
+                let bytes: [u8; 16] = data.as_ref().try_into().map_err(|_| {
+                    Error::Corruption(format!("invalid chain work of block {}", current_hash))
+                })?;
+                chain_work = u128::from_be_bytes(bytes);
                 break;
             }
-            let block = option.unwrap();
-            'outer: for tx in block.get_transactions() {
-                let txid_hex = HEXLOWER.encode(tx.get_id());
+            let header = self
+                .get_header(&current_hash)?
+                .ok_or_else(|| Error::Corruption(format!("block {} is missing", current_hash)))?;
+            missing.push((current_hash, header.get_work()));
+            if header.get_height() == 0 {
+                break;
+            }
+            current_hash = header.get_pre_block_hash();
+        }
+        for (hash, work) in missing.into_iter().rev() {
+            chain_work += work;
+            work_tree.insert(hash, &chain_work.to_be_bytes())?;
+        }
+        Ok(chain_work)
+    }
+
+    /// True if the block failed validation when a reorg tried to connect it
+    pub fn is_invalid_block(&self, block_hash: &BlockHash) -> Result<bool> {
+        let invalid_tree = self.db.open_tree(INVALID_BLOCKS_TREE_NAME)?;
+        Ok(invalid_tree.contains_key(block_hash)?)
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks.
+    /// A single miner can not move it much with a wrong clock.
+    pub fn get_median_time_past(&self) -> Result<u64> {
+        self.get_median_time_past_at(&self.get_tip_hash())
+    }
+
+    /// Median timestamp of the `MEDIAN_TIME_SPAN` blocks up to `block_hash`, which can be
+    /// in a side chain
+    fn get_median_time_past_at(&self, block_hash: &BlockHash) -> Result<u64> {
+        let prev_headers = self.get_ancestor_headers(block_hash, MEDIAN_TIME_SPAN)?;
+        median_time_past(&prev_headers)
+            .ok_or_else(|| Error::Corruption(format!("block {} is missing", block_hash)))
+    }
+
+    /// Difficulty of the next block.
+    /// Every `RETARGET_INTERVAL` blocks, compare the time spent on the last interval with
+    /// the expected time, and add one bit (double the work) if blocks came more than twice
+    /// too fast, or remove one bit if they came more than twice too slow.
+    pub fn get_next_bits(&self) -> Result<u32> {
+        self.get_next_bits_after(&self.get_tip_header()?)
+    }
+
+    /// Difficulty of the block after `prev_header`, which can be in a side chain
+    fn get_next_bits_after(&self, prev_header: &BlockHeader) -> Result<u32> {
+        next_bits(&self.get_ancestor_headers(&prev_header.hash(), RETARGET_INTERVAL)?)
+    }
+
+    /// Up to `count` stored headers which end with `block_hash`, oldest first.
+    /// There are fewer only near the genesis block.
+    pub fn get_ancestor_headers(
+        &self,
+        block_hash: &BlockHash,
+        count: usize,
+    ) -> Result<Vec<BlockHeader>> {
+        let mut headers = HeaderIterator::new(*block_hash, self.db.clone())
+            .take(count)
+            .collect::<Result<Vec<BlockHeader>>>()?;
+        headers.reverse();
+        Ok(headers)
+    }
+
+    /// Check a header against the headers before it, e.g. a header received before its body.
+    /// `prev_headers` are oldest first and end with the parent, they need the last
+    /// `HEADER_CONTEXT_LENGTH` headers, or all of them down to the genesis block.
+    ///   1. it links to the parent, and its height is the parent height plus one
+    ///   2. its timestamp is after the median time past, and not too far in the future
+    ///   3. its difficulty follows the retarget rule, and its hash is valid proof of work
+    pub fn check_header(&self, header: &BlockHeader, prev_headers: &[BlockHeader]) -> Result<()> {
+        let prev_header = prev_headers
+            .last()
+            .ok_or_else(|| Error::Corruption(String::from("the parent header is missing")))?;
+        let prev_hash = prev_header.hash();
+        if header.get_pre_block_hash() != prev_hash {
+            return Err(BlockValidationError::PrevHashMismatch {
+                expected: prev_hash,
+                found: header.get_pre_block_hash(),
+            }
+            .into());
+        }
+        let expected_height = prev_header.get_height() + 1;
+        if header.get_height() != expected_height {
+            return Err(BlockValidationError::InvalidHeight {
+                expected: expected_height,
+                found: header.get_height(),
+            }
+            .into());
+        }
+        let median_time_past = median_time_past(prev_headers).unwrap_or(0);
+        if header.get_timestamp() <= median_time_past {
+            return Err(BlockValidationError::TimestampTooOld {
+                median_time_past,
+                found: header.get_timestamp(),
+            }
+            .into());
+        }
+        let max_timestamp = self.clock.now() + MAX_FUTURE_BLOCK_TIME;
+        if header.get_timestamp() > max_timestamp {
+            return Err(BlockValidationError::TimestampTooNew {
+                max: max_timestamp,
+                found: header.get_timestamp(),
+            }
+            .into());
+        }
+        let expected_bits = next_bits(prev_headers)?;
+        if header.get_bits() != expected_bits {
+            return Err(BlockValidationError::InvalidBits {
+                expected: expected_bits,
+                found: header.get_bits(),
+            }
+            .into());
+        }
+        if !header.validate_pow() {
+            return Err(BlockValidationError::InvalidProofOfWork.into());
+        }
+        Ok(())
+    }
+
+    /// Mine a block with `transactions`, and a coinbase in front of them which pays
+    /// the subsidy and the fees to `miner_address`.
+    /// A transaction can spend the outputs of the transactions before it in `transactions`.
+    pub fn mine_block(&self, miner_address: &str, transactions: &[Transaction]) -> Result<Block> {
+        let template = self.create_block_template(miner_address, transactions)?;
+        let block = MiningJob::start(template, MinerConfig::default())
+            .wait()?
+            .ok_or(Error::MiningCancelled)?;
+        self.add_block(&block)?;
+        Ok(block)
+    }
+
+    /// Check `transactions` and sum their fees, for a block on the tip, see `MiningJob`.
+    /// A transaction can spend the outputs of the transactions before it in `transactions`.
+    pub fn create_block_template(
+        &self,
+        miner_address: &str,
+        transactions: &[Transaction],
+    ) -> Result<BlockTemplate> {
+        let mut earlier_txs = HashMap::new();
+        let mut fees: i64 = 0;
+        for tx in transactions {
+            if tx.is_coinbase() {
+                return Err(Error::InvalidTransaction(format!(
+                    "{} is a coinbase, the coinbase is added by the miner",
+                    tx.get_id()
+                )));
+            }
+            if tx.get_vin().is_empty() || tx.get_vout().is_empty() {
+                return Err(Error::InvalidTransaction(format!(
+                    "{} has no inputs or no outputs",
+                    tx.get_id()
+                )));
+            }
+            let prev_outputs = self.find_prev_outputs(tx, &earlier_txs)?;
+            if !tx.verify(&prev_outputs) {
+                return Err(Error::InvalidTransaction(tx.get_id().to_string()));
+            }
+            // verify has checked every previous output is there
+            let fee = tx.get_fee(&prev_outputs).unwrap_or(0);
+            if tx.has_negative_output() || fee < 0 {
+                return Err(Error::InvalidTransaction(format!(
+                    "{} spends more than its inputs",
+                    tx.get_id()
+                )));
+            }
+            fees += fee;
+            earlier_txs.insert(tx.get_id(), tx.clone());
+        }
+        let fees = i32::try_from(fees)
+            .map_err(|_| Error::InvalidTransaction(format!("total fees {} overflow", fees)))?;
+
+        let height = self.get_best_height()? + 1;
+        let bits = self.get_next_bits()?;
+        // the local clock may be behind the last blocks, the timestamp must still be valid
+        let timestamp = self.clock.now().max(self.get_median_time_past()? + 1);
+        Ok(BlockTemplate::new(
+            self.get_tip_hash(),
+            height,
+            bits,
+            timestamp,
+            miner_address,
+            fees,
+            transactions.to_vec(),
+        ))
+    }
+
+    /// Add a block from anywhere, e.g. from peers or files, or mined by ourselves.
+    ///   1. a block on the tip is fully validated, and becomes the new tip
+    ///   2. a block on another stored block only has its header and coinbase checked
+    ///      against its parent, and is stored in a side chain
+    ///   3. when a side chain gets more work than the best chain, the node switches to it,
+    ///      see `reorganize`
+    ///
+    /// A rejected block is reported as `Error::InvalidBlock`.
+    pub fn add_block(&self, block: &Block) -> Result<BlockStatus> {
+        let _guard = self.update_lock.lock().unwrap();
+        let block_hash = block.get_hash();
+        if self.is_invalid_block(&block_hash)? {
+            return Err(BlockValidationError::InvalidParent { hash: block_hash }.into());
+        }
+        let tip_hash = self.get_tip_hash();
+        if self.get_header(&block_hash)?.is_some() {
+            // e.g. a block disconnected by `disconnect_tip` is added again
+            if self.get_chain_work(&block_hash)? > self.get_chain_work(&tip_hash)? {
+                return self.reorganize(&block_hash);
+            }
+            return Ok(BlockStatus::AlreadyKnown);
+        }
+
+        if block.get_pre_block_hash() == tip_hash {
+            self.validate_block(block)?;
+            let chain_work = self.get_chain_work(&tip_hash)? + block.get_header().get_work();
+            Self::update_blocks_tree(&self.db, block, chain_work)?;
+            UtxoSet::new(self).update(block)?;
+            self.set_tip_hash(&block_hash);
+            return Ok(BlockStatus::Connected);
+        }
+
+        if self.get_header(&block.get_pre_block_hash())?.is_none() {
+            return Err(BlockValidationError::PrevHashMismatch {
+                expected: tip_hash,
+                found: block.get_pre_block_hash(),
+            }
+            .into());
+        }
+        if self.is_invalid_block(&block.get_pre_block_hash())? {
+            return Err(BlockValidationError::InvalidParent {
+                hash: block.get_pre_block_hash(),
+            }
+            .into());
+        }
+        self.check_block_header(block)?;
+        let chain_work =
+            self.get_chain_work(&block.get_pre_block_hash())? + block.get_header().get_work();
+        Self::update_blocks_tree(&self.db, block, chain_work)?;
+        // on equal work, the chain seen first stays the best
+        if chain_work <= self.get_chain_work(&tip_hash)? {
+            return Ok(BlockStatus::SideChain);
+        }
+        self.reorganize(&block_hash)
+    }
+
+    /// Switch the best chain to the branch ending at `new_tip_hash`:
+    ///   1. find the fork, the last block the two chains have in common
+    ///   2. disconnect the blocks of the old chain down to the fork
+    ///   3. validate and connect the blocks of the new branch, from the fork up
+    ///
+    /// If a block of the new branch is invalid, it is recorded in `invalid_blocks`,
+    /// the blocks of the new branch are disconnected, the old chain is connected again,
+    /// and the error is returned.
+    fn reorganize(&self, new_tip_hash: &BlockHash) -> Result<BlockStatus> {
+        let old_tip_hash = self.get_tip_hash();
+        let (disconnect, connect) = self.find_fork(&old_tip_hash, new_tip_hash)?;
+        for block in &connect {
+            if self.is_invalid_block(&block.get_hash())? {
+                return Err(BlockValidationError::InvalidParent {
+                    hash: block.get_hash(),
+                }
+                .into());
+            }
+        }
+
+        for block in &disconnect {
+            self.disconnect_block(block)?;
+        }
+        for (connected, block) in connect.iter().enumerate() {
+            let result = self
+                .validate_block(block)
+                .and_then(|_| self.connect_block(block));
+            if let Err(err) = result {
+                if matches!(err, Error::InvalidBlock(_)) {
+                    let invalid_tree = self.db.open_tree(INVALID_BLOCKS_TREE_NAME)?;
+                    invalid_tree.insert(block.get_hash(), &[])?;
+                }
+                // go back to the old chain, its blocks were valid there
+                for block in connect[..connected].iter().rev() {
+                    self.disconnect_block(block)?;
+                }
+                for block in disconnect.iter().rev() {
+                    self.connect_block(block)?;
+                }
+                return Err(err);
+            }
+        }
+        Ok(BlockStatus::Reorganized {
+            disconnected: disconnect,
+            connected: connect,
+        })
+    }
+
+    /// Walk back from both tips to the block they have in common.
+    /// Return the blocks of the old chain from its tip down to the fork,
+    /// and the blocks of the new branch from the fork up, both without the fork.
+    fn find_fork(
+        &self,
+        old_tip_hash: &BlockHash,
+        new_tip_hash: &BlockHash,
+    ) -> Result<(Vec<Block>, Vec<Block>)> {
+        let read = |hash: &BlockHash| {
+            self.get_block(hash)?
+                .ok_or_else(|| Error::Corruption(format!("block {} is missing", hash)))
+        };
+        let mut old_block = read(old_tip_hash)?;
+        let mut new_block = read(new_tip_hash)?;
+        let mut disconnect = vec![];
+        let mut connect = vec![];
+        while old_block.get_hash() != new_block.get_hash() {
+            if old_block.get_height() >= new_block.get_height() {
+                let prev_hash = old_block.get_pre_block_hash();
+                disconnect.push(old_block);
+                old_block = read(&prev_hash)?;
+            } else {
+                let prev_hash = new_block.get_pre_block_hash();
+                connect.push(new_block);
+                new_block = read(&prev_hash)?;
+            }
+        }
+        connect.reverse();
+        Ok((disconnect, connect))
+    }
+
+    /// Undo the tip block: its parent becomes the tip, and the UTXO set goes back to the
+    /// state before the block. The block stays stored, it can be connected again by a reorg.
+    /// Return the disconnected block, or `None` if the tip is the genesis block.
+    pub fn disconnect_tip(&self) -> Result<Option<Block>> {
+        let _guard = self.update_lock.lock().unwrap();
+        let tip_hash = self.get_tip_hash();
+        let block = self
+            .get_block(&tip_hash)?
+            .ok_or_else(|| Error::Corruption(format!("tip block {} is missing", tip_hash)))?;
+        if block.get_height() == 0 {
+            return Ok(None);
+        }
+        self.disconnect_block(&block)?;
+        Ok(Some(block))
+    }
+
+    /// Make the parent of the tip block the tip, and undo the block in the UTXO set.
+    /// A block connected before undo records were kept has none, then the UTXO set
+    /// is rebuilt from the chain instead.
+    fn disconnect_block(&self, block: &Block) -> Result<()> {
+        let utxo_set = UtxoSet::new(self);
+        if utxo_set.has_undo(&block.get_hash())? {
+            utxo_set.disconnect(block)?;
+            self.set_tip_hash(&block.get_pre_block_hash());
+            Ok(())
+        } else {
+            // if the node stops before the reindex is done, it is done again on `open`
+            self.write_tip_hash(&block.get_pre_block_hash())?;
+            utxo_set.reindex()
+        }
+    }
+
+    /// Make a stored block on the tip the new tip, and apply it to the UTXO set
+    fn connect_block(&self, block: &Block) -> Result<()> {
+        UtxoSet::new(self).update(block)?;
+        self.set_tip_hash(&block.get_hash());
+        Ok(())
+    }
+
+    /// Check that the block can extend the current tip:
+    ///   1. it links to the tip, and its height is the tip height plus one
+    ///   2. its timestamp is after the median time past, and not too far in the future
+    ///   3. its difficulty follows the retarget rule, and its hash is valid proof of work
+    ///   4. the id of each transaction is its hash, the merkle root matches the ids,
+    ///      and it has at most one coinbase,
+    ///      which is the first transaction, has the block height,
+    ///      and pays at most the subsidy plus the fees
+    ///   5. every input exists, is signed by the owner, and is still in the UTXO set
+    ///      or created by an earlier transaction of the block
+    ///   6. no output is negative, and no transaction spends more than its inputs
+    pub fn validate_block(&self, block: &Block) -> Result<()> {
+        let tip_hash = self.get_tip_hash();
+        if block.get_pre_block_hash() != tip_hash {
+            return Err(BlockValidationError::PrevHashMismatch {
+                expected: tip_hash,
+                found: block.get_pre_block_hash(),
+            }
+            .into());
+        }
+        self.check_block_header(block)?;
+        self.check_block_transactions(block)
+    }
+
+    /// Steps 1 to 4 of `validate_block` without the coinbase value, against the parent,
+    /// which can be in a side chain. They do not need the UTXO set.
+    fn check_block_header(&self, block: &Block) -> Result<()> {
+        let prev_headers =
+            self.get_ancestor_headers(&block.get_pre_block_hash(), HEADER_CONTEXT_LENGTH)?;
+        self.check_header(block.get_header(), &prev_headers)?;
+        // the hash stored with the block must be the hash of its header
+        if !block.validate_pow() {
+            return Err(BlockValidationError::InvalidProofOfWork.into());
+        }
+        // the merkle root and the UTXO set trust the ids, so they must be the real hashes
+        if let Some(tx) = block
+            .get_transactions()
+            .iter()
+            .find(|tx| !tx.has_valid_id())
+        {
+            return Err(BlockValidationError::InvalidTxid { txid: tx.get_id() }.into());
+        }
+        if block.hash_transactions() != block.get_header().get_merkle_root() {
+            return Err(BlockValidationError::InvalidMerkleRoot.into());
+        }
+        let coinbase_count = block
+            .get_transactions()
+            .iter()
+            .filter(|tx| tx.is_coinbase())
+            .count();
+        if coinbase_count > 1 {
+            return Err(BlockValidationError::MultipleCoinbase.into());
+        }
+        let coinbase = block
+            .get_transactions()
+            .first()
+            .filter(|tx| tx.is_coinbase());
+        if coinbase_count == 1 && coinbase.is_none() {
+            return Err(BlockValidationError::CoinbaseNotFirst.into());
+        }
+        if let Some(coinbase) = coinbase {
+            if coinbase.get_coinbase_height() != Some(block.get_height()) {
+                return Err(BlockValidationError::InvalidCoinbaseHeight {
+                    expected: block.get_height(),
+                    found: coinbase.get_coinbase_height(),
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Steps 5 and 6 of `validate_block`, and the coinbase value, against the UTXO set of the tip
+    fn check_block_transactions(&self, block: &Block) -> Result<()> {
+        let coinbase = block
+            .get_transactions()
+            .first()
+            .filter(|tx| tx.is_coinbase());
+        let mut spent_in_block: HashSet<(Txid, usize)> = HashSet::new();
+        let mut earlier_txs: HashMap<Txid, Transaction> = HashMap::new();
+        let mut fees: i64 = 0;
+        for tx in block.get_transactions() {
+            if tx.has_negative_output() {
+                return Err(BlockValidationError::NegativeOutput { txid: tx.get_id() }.into());
+            }
+            if tx.is_coinbase() {
+                earlier_txs.insert(tx.get_id(), tx.clone());
+                continue;
+            }
+            if tx.get_vin().is_empty() || tx.get_vout().is_empty() {
+                return Err(
+                    BlockValidationError::EmptyInputsOrOutputs { txid: tx.get_id() }.into(),
+                );
+            }
+            let prev_outputs = self.find_prev_outputs(tx, &earlier_txs)?;
+            for vin in tx.get_vin() {
+                let outpoint = (vin.get_txid(), vin.get_vout());
+                if !prev_outputs.contains_key(&outpoint) {
+                    return Err(BlockValidationError::MissingInput {
+                        txid: outpoint.0,
+                        vout: outpoint.1,
+                    }
+                    .into());
+                }
+                if !spent_in_block.insert(outpoint) {
+                    return Err(BlockValidationError::DoubleSpend {
+                        txid: outpoint.0,
+                        vout: outpoint.1,
+                    }
+                    .into());
+                }
+            }
+            if !tx.verify(&prev_outputs) {
+                return Err(BlockValidationError::InvalidSignature { txid: tx.get_id() }.into());
+            }
+            // fee = inputs - outputs, the coinbase can claim it
+            let fee = tx.get_fee(&prev_outputs).unwrap_or(0);
+            if fee < 0 {
+                return Err(BlockValidationError::OutputsExceedInputs { txid: tx.get_id() }.into());
+            }
+            fees += fee;
+            earlier_txs.insert(tx.get_id(), tx.clone());
+        }
+
+        if let Some(coinbase) = coinbase {
+            let max = get_block_subsidy(block.get_height()) as i64 + fees;
+            if coinbase.get_output_value() > max {
+                return Err(BlockValidationError::InvalidCoinbaseValue {
+                    max,
+                    found: coinbase.get_output_value(),
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Search the whole chain for the transaction with the given id.
+    /// It reads every block, validation finds spent outputs in the UTXO set instead.
+    pub fn find_transaction(&self, txid: &Txid) -> Result<Option<Transaction>> {
+        for block in self.iterator() {
+            for tx in block?.get_transactions() {
+                if tx.get_id() == *txid {
+                    return Ok(Some(tx.clone()));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Collect the outputs spent by the inputs of `tx`.
+    /// They are looked up in `earlier_txs` first, then in the UTXO set,
+    /// an output which is in neither is left out.
+    fn find_prev_outputs(
+        &self,
+        tx: &Transaction,
+        earlier_txs: &HashMap<Txid, Transaction>,
+    ) -> Result<PrevOutputs> {
+        let utxo_set = UtxoSet::new(self);
+        let mut prev_outputs = HashMap::new();
+        for vin in tx.get_vin() {
+            let (txid, vout) = (vin.get_txid(), vin.get_vout());
+            let prev_out = match earlier_txs.get(&txid) {
+                Some(prev_tx) => prev_tx.get_vout().get(vout).cloned(),
+                None => utxo_set.get_output(&txid, vout)?,
+            };
+            if let Some(prev_out) = prev_out {
+                prev_outputs.insert((txid, vout), prev_out);
+            }
+        }
+        Ok(prev_outputs)
+    }
+
+    /// Sign the inputs of `tx`, which spend outputs of the UTXO set
+    pub fn sign_transaction(&self, tx: &mut Transaction, wallet: &Wallet) -> Result<()> {
+        let prev_outputs = self.find_prev_outputs(tx, &HashMap::new())?;
+        tx.sign(wallet, &prev_outputs)
+    }
+
+    /// True if every input spends an output of the UTXO set, and is signed by its owner
+    pub fn verify_transaction(&self, tx: &Transaction) -> Result<bool> {
+        if tx.is_coinbase() {
+            return Ok(true);
+        }
+        let prev_outputs = self.find_prev_outputs(tx, &HashMap::new())?;
+        Ok(tx.verify(&prev_outputs))
+    }
+
+    /// Return a hashmap
+    /// The key is the txid
+    /// The value is a vector of unspent (vout, TXOutput), vout is the index in the transaction
+    pub fn find_utxo(&self) -> Result<HashMap<Txid, Vec<(usize, TXOutput)>>> {
+        Ok(self
+            .find_utxo_with_height()?
+            .into_iter()
+            .map(|(txid, (_, outs))| (txid, outs))
+            .collect())
+    }
+
+    /// Same as `find_utxo`, and the value also has the height of the block with the transaction
+    pub fn find_utxo_with_height(&self) -> Result<HashMap<Txid, HeightAndOutputs>> {
+        let mut utxo: HashMap<Txid, HeightAndOutputs> = HashMap::new();
+        let mut spent_txos: HashMap<Txid, Vec<usize>> = HashMap::new();
+
+        // Walk from the tip, and each block from its last transaction,
+        // so an output is always seen after the inputs that spend it,
+        // also when it is spent in the same block
+        for block in self.iterator() {
+            let block = block?;
+            for tx in block.get_transactions().iter().rev() {
+                let txid = tx.get_id();
                 for (idx, out) in tx.get_vout().iter().enumerate() {
-                    if let Some(outs) = spent_txos.get(txid_hex.as_str()) {
+                    if let Some(outs) = spent_txos.get(&txid) {
                         if outs.contains(&idx) {
-                            continue 'outer;
+                            continue;
                         }
                     }
-                    if utxo.contains_key(txid_hex.as_str()) {
-                        utxo.get_mut(txid_hex.as_str()).unwrap().push(out.clone());
-                    } else {
-                        utxo.insert(txid_hex.clone(), vec![out.clone()]);
-                    }
+                    utxo.entry(txid)
+                        .or_insert_with(|| (block.get_height(), vec![]))
+                        .1
+                        .push((idx, out.clone()));
                 }
                 if tx.is_coinbase() {
                     continue;
                 }
                 for txin in tx.get_vin() {
-                    let txid_hex = HEXLOWER.encode(txin.get_txid());
-                    if spent_txos.contains_key(txid_hex.as_str()) {
-                        spent_txos
-                            .get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(txin.get_vout());
-                    } else {
-                        spent_txos.insert(txid_hex.clone(), vec![txin.get_vout()]);
-                    }
+                    spent_txos
+                        .entry(txin.get_txid())
+                        .or_default()
+                        .push(txin.get_vout());
                 }
             }
         }
-        utxo
+        Ok(utxo)
+    }
+}
+
+/// Median timestamp of the last `MEDIAN_TIME_SPAN` of `prev_headers`, which are oldest first
+fn median_time_past(prev_headers: &[BlockHeader]) -> Option<u64> {
+    let start = prev_headers.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut timestamps: Vec<u64> = prev_headers[start..]
+        .iter()
+        .map(|header| header.get_timestamp())
+        .collect();
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied()
+}
+
+/// Difficulty of the block after the last of `prev_headers`, which are oldest first,
+/// see `BlockChain::get_next_bits`
+fn next_bits(prev_headers: &[BlockHeader]) -> Result<u32> {
+    let tip_block = prev_headers
+        .last()
+        .ok_or_else(|| Error::Corruption(String::from("the parent header is missing")))?;
+    let next_height = tip_block.get_height() + 1;
+    if !next_height.is_multiple_of(RETARGET_INTERVAL) {
+        return Ok(tip_block.get_bits());
+    }
+
+    // the first block of the interval is `RETARGET_INTERVAL - 1` blocks before the tip
+    let first_block = prev_headers
+        .len()
+        .checked_sub(RETARGET_INTERVAL)
+        .map(|pos| &prev_headers[pos])
+        .ok_or_else(|| Error::Corruption(String::from("retarget interval is incomplete")))?;
+    let actual_span = tip_block
+        .get_timestamp()
+        .saturating_sub(first_block.get_timestamp());
+    let expected_span = TARGET_BLOCK_INTERVAL * (RETARGET_INTERVAL as u64 - 1);
+    let bits = tip_block.get_bits();
+    let next_bits = if actual_span < expected_span / 2 {
+        bits + 1
+    } else if actual_span > expected_span * 2 {
+        bits.saturating_sub(1)
+    } else {
+        bits
+    };
+    Ok(next_bits.clamp(MIN_BITS, MAX_BITS))
+}
+
+fn read_header(db: &Db, block_hash: &BlockHash) -> Result<Option<BlockHeader>> {
+    let headers_tree = db.open_tree(HEADERS_TREE_NAME)?;
+    match headers_tree.get(block_hash)? {
+        Some(data) => Ok(Some(BlockHeader::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+/// A header without a body is reported as `Error::Corruption`, both are written together
+fn read_block(db: &Db, block_hash: &BlockHash) -> Result<Option<Block>> {
+    let header = match read_header(db, block_hash)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let bodies_tree = db.open_tree(BODIES_TREE_NAME)?;
+    let body_data = bodies_tree
+        .get(block_hash)?
+        .ok_or_else(|| Error::Corruption(format!("body of block {} is missing", block_hash)))?;
+    let transactions: Vec<Transaction> = bincode::deserialize(&body_data)
+        .map_err(|err| Error::Corruption(format!("failed to deserialize block body: {}", err)))?;
+    Ok(Some(Block::from_parts(header, transactions)))
+}
+
+// HeaderIterator
+pub struct HeaderIterator {
+    db: Db,
+    current_hash: BlockHash,
+}
+
+impl HeaderIterator {
+    pub fn new(tip_hash: BlockHash, db: Db) -> HeaderIterator {
+        HeaderIterator {
+            db,
+            current_hash: tip_hash,
+        }
+    }
+}
+
+/// Walk the headers from the tip back to the genesis block.
+/// An error is returned as the last item, then the iteration stops.
+impl Iterator for HeaderIterator {
+    type Item = Result<BlockHeader>;
+
+    fn next(&mut self) -> Option<Result<BlockHeader>> {
+        match read_header(&self.db, &self.current_hash) {
+            Ok(Some(header)) => {
+                self.current_hash = header.get_pre_block_hash();
+                Some(Ok(header))
+            }
+            Ok(None) => None,
+            Err(err) => {
+                self.current_hash = BlockHash::default();
+                Some(Err(err))
+            }
+        }
     }
 }
 
 // BlockChainIterator
 pub struct BlockchainIterator {
     db: Db,
-    current_hash: String,
+    current_hash: BlockHash,
 }
 
 impl BlockchainIterator {
-    pub fn new(tip_hash: String, db: Db) -> BlockchainIterator {
+    pub fn new(tip_hash: BlockHash, db: Db) -> BlockchainIterator {
         BlockchainIterator {
             db,
             current_hash: tip_hash,
         }
     }
+}
 
-    pub fn next(&mut self) -> Option<Block> {
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE_NAME).unwrap();
-        let current_block_data = blocks_tree.get(&self.current_hash).unwrap();
-        if current_block_data.is_none() {
-            return None;
+/// Walk from the tip back to the genesis block.
+/// An error is returned as the last item, then the iteration stops.
+impl Iterator for BlockchainIterator {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Result<Block>> {
+        match read_block(&self.db, &self.current_hash) {
+            Ok(Some(block)) => {
+                self.current_hash = block.get_pre_block_hash();
+                Some(Ok(block))
+            }
+            Ok(None) => None,
+            Err(err) => {
+                self.current_hash = BlockHash::default();
+                Some(Err(err))
+            }
         }
-        let current_block: Block = Block::deserialize(&current_block_data.unwrap());
-        self.current_hash = current_block.get_pre_block_hash();
-        return Some(current_block);
     }
 }
@@ -0,0 +1,375 @@
+/* # Command Line Interface
+ *
+ * Usage: toy_blockchain [--datadir <dir>] <command> [args...]
+ * The binary only calls `run`, the parsing is in the library so it can be tested.
+ */
+use crate::blockchain::{BlockChain, DB_NAME};
+use crate::error::{Error, Result};
+use crate::mempool::Mempool;
+use crate::miner::{MinerConfig, MiningJob};
+use crate::node::{Node, NodeConfig};
+use crate::rpc::{RpcServer, DEFAULT_RPC_ADDR};
+use crate::transaction::{Fee, Transaction};
+use crate::utxo_set::UtxoSet;
+use crate::wallet::decode_address;
+use crate::wallets::{Wallets, WALLET_FILE};
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+
+const USAGE: &str = "Usage: toy_blockchain [--datadir <dir>] <command>
+
+Commands:
+  createblockchain <address>   Create a blockchain and send the genesis reward to address
+  createwallet                 Generate a new key pair and save it into the wallet file
+  listaddresses                List all addresses in the wallet file
+  getbalance <address>         Get the balance of address
+  listunspent <address>        List the unspent outputs of address with their confirmations
+  send <from> <to> <amount> [fee]
+                               Send amount of coins from one address to another, and mine a block
+                               with the reward paid to the sender, fee is 0 by default
+  printchain                   Print all blocks of the blockchain
+  reindexutxo                  Rebuild the UTXO set from the blockchain
+  disconnecttip                Undo the last block, its parent becomes the tip
+  startnode <listen_addr> [peer_addr...] [--rpc <rpc_addr>]
+                               Run a node which listens on listen_addr, connects to the peers,
+                               and relays blocks and transactions until it is killed.
+                               It answers JSON-RPC over HTTP on rpc_addr, default is 127.0.0.1:8332
+
+Options:
+  --datadir <dir>              Directory of the blockchain data and wallet file, default is current dir";
+
+/// A command and its arguments, see `USAGE`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    CreateBlockchain {
+        address: String,
+    },
+    CreateWallet,
+    ListAddresses,
+    GetBalance {
+        address: String,
+    },
+    ListUnspent {
+        address: String,
+    },
+    Send {
+        from: String,
+        to: String,
+        amount: i32,
+        fee: i32,
+    },
+    PrintChain,
+    ReindexUtxo,
+    DisconnectTip,
+    StartNode {
+        listen_addr: String,
+        peers: Vec<String>,
+        rpc_addr: String,
+    },
+}
+
+/// Parse the arguments without the program name, return the data dir and the command
+pub fn parse_args(mut args: Vec<String>) -> std::result::Result<(Option<String>, Command), String> {
+    let mut datadir = None;
+    if let Some(pos) = args.iter().position(|arg| arg == "--datadir") {
+        if pos + 1 >= args.len() {
+            return Err(String::from("--datadir needs a directory"));
+        }
+        datadir = Some(args.remove(pos + 1));
+        args.remove(pos);
+    }
+
+    let (name, params) = match args.split_first() {
+        Some((name, params)) => (name.as_str(), params),
+        None => return Err(String::from("no command")),
+    };
+    let expect_params = |count: usize| {
+        if params.len() == count {
+            Ok(())
+        } else {
+            Err(format!("{} needs {} argument(s)", name, count))
+        }
+    };
+    let command = match name {
+        "createblockchain" => {
+            expect_params(1)?;
+            Command::CreateBlockchain {
+                address: params[0].clone(),
+            }
+        }
+        "createwallet" => {
+            expect_params(0)?;
+            Command::CreateWallet
+        }
+        "listaddresses" => {
+            expect_params(0)?;
+            Command::ListAddresses
+        }
+        "getbalance" => {
+            expect_params(1)?;
+            Command::GetBalance {
+                address: params[0].clone(),
+            }
+        }
+        "listunspent" => {
+            expect_params(1)?;
+            Command::ListUnspent {
+                address: params[0].clone(),
+            }
+        }
+        "send" => {
+            if params.len() != 4 {
+                expect_params(3)?;
+            }
+            let amount = params[2]
+                .parse::<i32>()
+                .map_err(|_| format!("invalid amount: {}", params[2]))?;
+            if amount <= 0 {
+                return Err(format!("invalid amount: {}", params[2]));
+            }
+            let fee = match params.get(3) {
+                Some(fee) => fee
+                    .parse::<i32>()
+                    .ok()
+                    .filter(|fee| *fee >= 0)
+                    .ok_or_else(|| format!("invalid fee: {}", fee))?,
+                None => 0,
+            };
+            Command::Send {
+                from: params[0].clone(),
+                to: params[1].clone(),
+                amount,
+                fee,
+            }
+        }
+        "printchain" => {
+            expect_params(0)?;
+            Command::PrintChain
+        }
+        "reindexutxo" => {
+            expect_params(0)?;
+            Command::ReindexUtxo
+        }
+        "disconnecttip" => {
+            expect_params(0)?;
+            Command::DisconnectTip
+        }
+        "startnode" => {
+            let mut params = params.to_vec();
+            let mut rpc_addr = String::from(DEFAULT_RPC_ADDR);
+            if let Some(pos) = params.iter().position(|param| param == "--rpc") {
+                if pos + 1 >= params.len() {
+                    return Err(String::from("--rpc needs an address"));
+                }
+                rpc_addr = params.remove(pos + 1);
+                params.remove(pos);
+            }
+            if params.is_empty() {
+                expect_params(1)?;
+            }
+            Command::StartNode {
+                listen_addr: params[0].clone(),
+                peers: params[1..].to_vec(),
+                rpc_addr,
+            }
+        }
+        _ => return Err(format!("unknown command: {}", name)),
+    };
+    Ok((datadir, command))
+}
+
+pub fn run() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (datadir, command) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("ERROR: {}\n\n{}", err, USAGE);
+            process::exit(1);
+        }
+    };
+
+    if let Err(err) = execute(datadir, command) {
+        eprintln!("ERROR: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Open the blockchain, and tell the user if the UTXO set had to be rebuilt
+fn open_blockchain(db_path: &Path) -> Result<BlockChain> {
+    let blockchain = BlockChain::open(db_path)?;
+    if blockchain.is_utxo_set_rebuilt() {
+        println!("The UTXO set was rebuilt from the chain");
+    }
+    Ok(blockchain)
+}
+
+/// A sender whose events, of the node or the RPC server, are printed by another thread
+fn print_events<E: Display + Send + 'static>() -> Sender<E> {
+    let (sender, receiver) = mpsc::channel::<E>();
+    thread::spawn(move || {
+        for event in receiver {
+            println!("{}", event);
+        }
+    });
+    sender
+}
+
+/// Run the command on the data in `datadir`, or in the current dir if it is None
+pub fn execute(datadir: Option<String>, command: Command) -> Result<()> {
+    // The blockchain db and the wallet file are both under datadir
+    let datadir = match datadir {
+        Some(datadir) => PathBuf::from(datadir),
+        None => env::current_dir()?,
+    };
+    fs::create_dir_all(&datadir)?;
+    let db_path = datadir.join(DB_NAME);
+    let wallet_path = datadir.join(WALLET_FILE);
+
+    match command {
+        Command::CreateBlockchain { address } => {
+            // opening the db creates its directory, so a bad address must not get that far
+            decode_address(&address)?;
+            match open_blockchain(&db_path) {
+                Ok(blockchain) => {
+                    println!(
+                        "A blockchain already exists, its tip is {}",
+                        blockchain.get_tip_hash()
+                    );
+                    return Ok(());
+                }
+                Err(Error::BlockchainNotFound) => {}
+                Err(err) => return Err(err),
+            }
+            println!("Creating a new blockchain");
+            println!("using address: {} as the genesis address", address);
+            BlockChain::create(&db_path, address.as_str())?;
+            println!("Done!");
+        }
+        Command::CreateWallet => {
+            let mut wallets = Wallets::open(&wallet_path)?;
+            let address = wallets.create_wallet()?;
+            println!("Your new address: {}", address);
+        }
+        Command::ListAddresses => {
+            let wallets = Wallets::open(&wallet_path)?;
+            for address in wallets.get_addresses() {
+                println!("{}", address);
+            }
+        }
+        Command::GetBalance { address } => {
+            let pub_key_hash = decode_address(address.as_str())?;
+            let blockchain = open_blockchain(&db_path)?;
+            let utxo_set = UtxoSet::new(&blockchain);
+            let balance = utxo_set.get_balance(&pub_key_hash)?;
+            println!("Balance of {}: {}", address, balance);
+        }
+        Command::ListUnspent { address } => {
+            let pub_key_hash = decode_address(address.as_str())?;
+            let blockchain = open_blockchain(&db_path)?;
+            let best_height = blockchain.get_best_height()?;
+            let utxo_set = UtxoSet::new(&blockchain);
+            for out in utxo_set.list_unspent(&pub_key_hash)? {
+                println!(
+                    "{}:{} value: {} height: {} confirmations: {}",
+                    out.get_txid(),
+                    out.get_vout(),
+                    out.get_value(),
+                    out.get_height(),
+                    out.get_confirmations(best_height)
+                );
+            }
+        }
+        Command::Send {
+            from,
+            to,
+            amount,
+            fee,
+        } => {
+            let wallets = Wallets::open(&wallet_path)?;
+            let wallet = wallets
+                .get_wallet(from.as_str())
+                .ok_or_else(|| Error::WalletNotFound(from.clone()))?;
+            let blockchain = open_blockchain(&db_path)?;
+            let utxo_set = UtxoSet::new(&blockchain);
+            let transaction = Transaction::new_utxo_transaction_with_fee(
+                wallet,
+                to.as_str(),
+                amount,
+                Fee::Fixed(fee),
+                &utxo_set,
+            )?;
+            // the sender mines the block, and gets the reward
+            let template = blockchain.create_block_template(from.as_str(), &[transaction])?;
+            let job = MiningJob::start(template, MinerConfig::default());
+            let block = job.wait()?.ok_or(Error::MiningCancelled)?;
+            blockchain.add_block(&block)?;
+            println!(
+                "Mined block {} at height {}, {:.0} hashes/s",
+                block.get_hash(),
+                block.get_height(),
+                job.get_stats().get_hashrate()
+            );
+            println!("Success!");
+        }
+        Command::PrintChain => {
+            let blockchain = open_blockchain(&db_path)?;
+            for block in blockchain.iterator() {
+                block?.print();
+                println!();
+            }
+        }
+        Command::ReindexUtxo => {
+            let blockchain = open_blockchain(&db_path)?;
+            let utxo_set = UtxoSet::new(&blockchain);
+            utxo_set.reindex()?;
+            let count = utxo_set.count_transactions()?;
+            println!("Done! There are {} transactions in the UTXO set.", count);
+        }
+        Command::DisconnectTip => {
+            let blockchain = open_blockchain(&db_path)?;
+            match blockchain.disconnect_tip()? {
+                Some(block) => println!(
+                    "Disconnected block {} at height {}",
+                    block.get_hash(),
+                    block.get_height()
+                ),
+                None => println!("The tip is the genesis block, nothing to disconnect"),
+            }
+        }
+        Command::StartNode {
+            listen_addr,
+            peers,
+            rpc_addr,
+        } => {
+            let blockchain = Arc::new(open_blockchain(&db_path)?);
+            let config = NodeConfig {
+                events: Some(print_events()),
+                ..NodeConfig::default()
+            };
+            let node = Arc::new(Node::start_with_config(
+                blockchain,
+                Arc::new(Mempool::new()),
+                &listen_addr,
+                config,
+            )?);
+            println!("Listening on {}", node.get_local_addr());
+            let rpc_server =
+                RpcServer::start_with_node(node.clone(), &rpc_addr, Some(print_events()))?;
+            println!("JSON-RPC on {}", rpc_server.get_local_addr());
+            for peer in peers {
+                // a peer which is down does not stop the node, it may connect to us later
+                if let Err(err) = node.connect(peer.as_str()) {
+                    eprintln!("ERROR: can not connect to {}: {}", peer, err);
+                }
+            }
+            node.join();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current time, in seconds since the unix epoch.
+/// The blockchain stamps new blocks and checks timestamps with it,
+/// tests replace it with a `MockClock` to stay deterministic.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+/// Wall-clock time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A clock which only moves when it is told to
+pub struct MockClock {
+    now: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(now: u64) -> MockClock {
+        MockClock {
+            now: AtomicU64::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.now.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
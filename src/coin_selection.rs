@@ -0,0 +1,247 @@
+/* # Coin Selection
+ *
+ * To send coins, a wallet picks some of its unspent outputs as inputs.
+ * Which outputs are picked matters:
+ *   - more inputs make a bigger transaction, so a higher fee per byte
+ *   - a change output creates a new small output, and many small outputs (fragmentation)
+ *     make later transactions bigger
+ *
+ * The strategies:
+ *   - LargestFirst: few inputs, but the small outputs are never used up
+ *   - SmallestFirst: uses up the small outputs, but needs more inputs
+ *   - BranchAndBound: searches for outputs which add up to exactly the amount, so there is
+ *     no change output at all. It finds nothing if there is no exact match.
+ *   - RandomDraw: takes outputs in a random order, which does not always link the same
+ *     outputs together
+ */
+use crate::error::Result;
+use crate::hash::Txid;
+use crate::utils::random_u64;
+use std::cell::Cell;
+use std::cmp::Reverse;
+
+/// An unspent output the wallet can spend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendableOutput {
+    txid: Txid,
+    vout: usize,
+    value: i32,
+}
+
+impl SpendableOutput {
+    pub fn new(txid: Txid, vout: usize, value: i32) -> SpendableOutput {
+        SpendableOutput { txid, vout, value }
+    }
+
+    pub fn get_txid(&self) -> Txid {
+        self.txid
+    }
+
+    pub fn get_vout(&self) -> usize {
+        self.vout
+    }
+
+    pub fn get_value(&self) -> i32 {
+        self.value
+    }
+}
+
+// fields:
+//   - outpoints: (txid, vout) of the selected outputs, in the order they are spent
+//   - total: sum of the selected outputs
+//   - change: total minus the target, sent back to the wallet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinSelection {
+    outpoints: Vec<(Txid, usize)>,
+    total: i32,
+    change: i32,
+}
+
+impl CoinSelection {
+    pub fn get_outpoints(&self) -> &[(Txid, usize)] {
+        self.outpoints.as_slice()
+    }
+
+    pub fn get_total(&self) -> i32 {
+        self.total
+    }
+
+    pub fn get_change(&self) -> i32 {
+        self.change
+    }
+}
+
+/// A strategy to pick the outputs for a payment of `target` coins (amount plus fee)
+pub trait CoinSelector {
+    /// Return `None` if the outputs can not cover the target the way the strategy wants
+    fn select(&self, outputs: &[SpendableOutput], target: i32) -> Option<CoinSelection>;
+}
+
+/// Take outputs in the given order until the target is reached.
+/// At least one output is taken even for a target of 0, a transaction needs an input.
+fn take_in_order<'a, I>(outputs: I, target: i32) -> Option<CoinSelection>
+where
+    I: IntoIterator<Item = &'a SpendableOutput>,
+{
+    let mut outpoints = vec![];
+    let mut total: i64 = 0;
+    let target = target as i64;
+    for out in outputs {
+        if total >= target && !outpoints.is_empty() {
+            break;
+        }
+        outpoints.push((out.txid, out.vout));
+        total += out.value as i64;
+    }
+    if total < target || outpoints.is_empty() {
+        return None;
+    }
+    let total = i32::try_from(total).ok()?;
+    Some(CoinSelection {
+        outpoints,
+        total,
+        change: total - target as i32,
+    })
+}
+
+/// Outputs that can not pay anything are never selected
+fn positive_outputs(outputs: &[SpendableOutput]) -> Vec<&SpendableOutput> {
+    outputs.iter().filter(|out| out.value > 0).collect()
+}
+
+/// The biggest outputs first
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, outputs: &[SpendableOutput], target: i32) -> Option<CoinSelection> {
+        let mut outputs = positive_outputs(outputs);
+        outputs.sort_by_key(|out| Reverse(out.value));
+        take_in_order(outputs, target)
+    }
+}
+
+/// The smallest outputs first
+pub struct SmallestFirst;
+
+impl CoinSelector for SmallestFirst {
+    fn select(&self, outputs: &[SpendableOutput], target: i32) -> Option<CoinSelection> {
+        let mut outputs = positive_outputs(outputs);
+        outputs.sort_by_key(|out| out.value);
+        take_in_order(outputs, target)
+    }
+}
+
+/// Depth-first search for outputs whose sum is exactly the target.
+/// The outputs are tried from the biggest, and a branch is cut when it is already over the
+/// target, or when all the remaining outputs can not reach it.
+/// The search gives up after `max_tries` steps.
+pub struct BranchAndBound {
+    max_tries: usize,
+}
+
+/// Steps of the search before `BranchAndBound` gives up
+pub const BNB_MAX_TRIES: usize = 100_000;
+
+impl BranchAndBound {
+    pub fn new(max_tries: usize) -> BranchAndBound {
+        BranchAndBound { max_tries }
+    }
+}
+
+impl Default for BranchAndBound {
+    fn default() -> Self {
+        BranchAndBound::new(BNB_MAX_TRIES)
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, outputs: &[SpendableOutput], target: i32) -> Option<CoinSelection> {
+        let mut outputs = positive_outputs(outputs);
+        outputs.sort_by_key(|out| Reverse(out.value));
+        let target = target as i64;
+        if target <= 0 {
+            return take_in_order(outputs, target as i32);
+        }
+
+        // remaining[i] is the sum of outputs[i..]
+        let mut remaining = vec![0i64; outputs.len() + 1];
+        for i in (0..outputs.len()).rev() {
+            remaining[i] = remaining[i + 1] + outputs[i].value as i64;
+        }
+
+        // the indices of the selected outputs, and the index to try next
+        let mut selected: Vec<usize> = vec![];
+        let mut total: i64 = 0;
+        let mut next = 0;
+        for _ in 0..self.max_tries {
+            if total == target {
+                let outpoints = selected
+                    .iter()
+                    .map(|&i| (outputs[i].txid, outputs[i].vout))
+                    .collect();
+                return Some(CoinSelection {
+                    outpoints,
+                    total: total as i32,
+                    change: 0,
+                });
+            }
+            if total < target && next < outputs.len() && total + remaining[next] >= target {
+                // include outputs[next], and go deeper
+                selected.push(next);
+                total += outputs[next].value as i64;
+                next += 1;
+                continue;
+            }
+            // backtrack: drop the last selected output, and try without it
+            let last = selected.pop()?;
+            total -= outputs[last].value as i64;
+            next = last + 1;
+        }
+        None
+    }
+}
+
+/// Outputs in a random order.
+/// The generator is a xorshift seeded from the system, or from a fixed seed in tests.
+pub struct RandomDraw {
+    state: Cell<u64>,
+}
+
+impl RandomDraw {
+    pub fn new() -> Result<RandomDraw> {
+        Ok(RandomDraw::with_seed(random_u64()?))
+    }
+
+    pub fn with_seed(seed: u64) -> RandomDraw {
+        // xorshift gets stuck at zero
+        let seed = if seed == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            seed
+        };
+        RandomDraw {
+            state: Cell::new(seed),
+        }
+    }
+
+    fn next_u64(&self) -> u64 {
+        let mut x = self.state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.set(x);
+        x
+    }
+}
+
+impl CoinSelector for RandomDraw {
+    fn select(&self, outputs: &[SpendableOutput], target: i32) -> Option<CoinSelection> {
+        let mut outputs = positive_outputs(outputs);
+        // Fisher-Yates shuffle
+        for i in (1..outputs.len()).rev() {
+            let j = (self.next_u64() % (i as u64 + 1)) as usize;
+            outputs.swap(i, j);
+        }
+        take_in_order(outputs, target)
+    }
+}
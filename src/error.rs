@@ -0,0 +1,138 @@
+use crate::address::AddressError;
+use crate::blockchain::BlockValidationError;
+use crate::mempool::MempoolError;
+use crate::network::NetworkError;
+use sled::transaction::TransactionError;
+use std::fmt;
+
+/// Error type of the whole crate
+#[derive(Debug)]
+pub enum Error {
+    /// The sender does not own enough coins for the transaction
+    InsufficientFunds { needed: i32, available: i32 },
+    /// The address can not be decoded into a public key hash
+    InvalidAddress(AddressError),
+    /// There is no wallet for the address in the wallet file
+    WalletNotFound(String),
+    /// The text or bytes are not a 32 byte hash
+    InvalidHash(String),
+    /// There is no blockchain in the db, it should be created first
+    BlockchainNotFound,
+    /// The db was written by an older version in a layout which can not be read any more
+    IncompatibleDatabase(String),
+    /// A transaction referenced by an input is not in the chain, or a signature is wrong
+    InvalidTransaction(String),
+    /// The block is rejected by the validation rules
+    InvalidBlock(BlockValidationError),
+    /// The transaction is not accepted into the mempool
+    RejectedTransaction(MempoolError),
+    /// A peer broke the network protocol, or can not be connected to
+    Network(NetworkError),
+    /// The miner was cancelled before it found a block, e.g. the tip changed
+    MiningCancelled,
+    /// The key pair can not be loaded, or the data can not be signed
+    Crypto(String),
+    /// Data in the db is missing or not what we wrote
+    Corruption(String),
+    /// Error from sled
+    Storage(sled::Error),
+    /// Error from bincode
+    Serialization(bincode::Error),
+    /// Error when reading or writing files, e.g. the wallet file
+    Io(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InsufficientFunds { needed, available } => write!(
+                f,
+                "not enough funds, needed {}, available {}",
+                needed, available
+            ),
+            Error::InvalidAddress(err) => write!(f, "invalid address: {}", err),
+            Error::WalletNotFound(address) => {
+                write!(f, "{} is not in the wallet file", address)
+            }
+            Error::InvalidHash(msg) => write!(f, "invalid hash: {}", msg),
+            Error::BlockchainNotFound => {
+                write!(f, "no existing blockchain found, create one first")
+            }
+            Error::IncompatibleDatabase(msg) => write!(f, "incompatible database: {}", msg),
+            Error::InvalidTransaction(msg) => write!(f, "invalid transaction: {}", msg),
+            Error::InvalidBlock(err) => write!(f, "invalid block: {}", err),
+            Error::RejectedTransaction(err) => write!(f, "rejected transaction: {}", err),
+            Error::Network(err) => write!(f, "network error: {}", err),
+            Error::MiningCancelled => write!(f, "mining was cancelled"),
+            Error::Crypto(msg) => write!(f, "crypto error: {}", msg),
+            Error::Corruption(msg) => write!(f, "corrupted data: {}", msg),
+            Error::Storage(err) => write!(f, "storage error: {}", err),
+            Error::Serialization(err) => write!(f, "serialization error: {}", err),
+            Error::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Storage(err) => Some(err),
+            Error::Serialization(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(err: sled::Error) -> Self {
+        Error::Storage(err)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Self {
+        Error::Serialization(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<AddressError> for Error {
+    fn from(err: AddressError) -> Self {
+        Error::InvalidAddress(err)
+    }
+}
+
+impl From<BlockValidationError> for Error {
+    fn from(err: BlockValidationError) -> Self {
+        Error::InvalidBlock(err)
+    }
+}
+
+impl From<MempoolError> for Error {
+    fn from(err: MempoolError) -> Self {
+        Error::RejectedTransaction(err)
+    }
+}
+
+impl From<NetworkError> for Error {
+    fn from(err: NetworkError) -> Self {
+        Error::Network(err)
+    }
+}
+
+impl From<TransactionError<Error>> for Error {
+    fn from(err: TransactionError<Error>) -> Self {
+        match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => Error::Storage(err),
+        }
+    }
+}
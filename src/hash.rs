@@ -0,0 +1,119 @@
+/* # Hash Types
+ *
+ * Block hashes, transaction ids and merkle roots are all sha256 digests, 32 bytes.
+ * They are kept as fixed-size arrays in memory and in the db, and only turned into text
+ * when they are shown to a user: lowercase hex, without a prefix.
+ *
+ * The types can not be mixed up by accident, e.g. a txid can not be used to look up a block.
+ */
+use crate::error::{Error, Result};
+use crate::utils::sha256_digest;
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+pub const HASH_LENGTH: usize = 32;
+
+macro_rules! hash_newtype {
+    ($name:ident, $what:expr) => {
+        #[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name([u8; HASH_LENGTH]);
+
+        impl $name {
+            pub fn new(bytes: [u8; HASH_LENGTH]) -> $name {
+                $name(bytes)
+            }
+
+            /// sha256 of `data`
+            pub fn digest(data: &[u8]) -> $name {
+                let mut bytes = [0u8; HASH_LENGTH];
+                bytes.copy_from_slice(&sha256_digest(data));
+                $name(bytes)
+            }
+
+            /// Fails if `data` is not 32 bytes
+            pub fn from_slice(data: &[u8]) -> Result<$name> {
+                let bytes: [u8; HASH_LENGTH] = data.try_into().map_err(|_| {
+                    Error::InvalidHash(format!(
+                        "{} must be {} bytes, found {}",
+                        $what,
+                        HASH_LENGTH,
+                        data.len()
+                    ))
+                })?;
+                Ok($name(bytes))
+            }
+
+            pub fn as_bytes(&self) -> &[u8; HASH_LENGTH] {
+                &self.0
+            }
+
+            pub fn is_zero(&self) -> bool {
+                self.0 == [0u8; HASH_LENGTH]
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", HEXLOWER.encode(&self.0))
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self)
+            }
+        }
+
+        /// Parse 64 hex characters, upper or lower case
+        impl FromStr for $name {
+            type Err = Error;
+
+            fn from_str(s: &str) -> Result<$name> {
+                let bytes = HEXLOWER_PERMISSIVE
+                    .decode(s.as_bytes())
+                    .map_err(|_| Error::InvalidHash(format!("{} is not hex: {}", $what, s)))?;
+                $name::from_slice(&bytes)
+            }
+        }
+
+        /// Hex text in human readable formats like JSON, 32 raw bytes in bincode
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                if serializer.is_human_readable() {
+                    serializer.serialize_str(&self.to_string())
+                } else {
+                    self.0.serialize(serializer)
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> std::result::Result<$name, D::Error> {
+                if deserializer.is_human_readable() {
+                    let s = String::deserialize(deserializer)?;
+                    s.parse().map_err(D::Error::custom)
+                } else {
+                    Ok($name(<[u8; HASH_LENGTH]>::deserialize(deserializer)?))
+                }
+            }
+        }
+    };
+}
+
+hash_newtype!(BlockHash, "block hash");
+hash_newtype!(Txid, "txid");
+hash_newtype!(MerkleRoot, "merkle root");
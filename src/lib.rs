@@ -0,0 +1,25 @@
+// Toy Block Chain
+
+pub mod address;
+pub mod block;
+pub mod blockchain;
+pub mod cli;
+pub mod clock;
+pub mod coin_selection;
+pub mod error;
+pub mod hash;
+pub mod mempool;
+pub mod merkle;
+pub mod miner;
+pub mod network;
+pub mod node;
+pub mod rpc;
+pub mod sync;
+pub mod transaction;
+pub mod utils;
+pub mod utxo_set;
+pub mod wallet;
+pub mod wallets;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,423 @@
+/* # Memory Pool
+ *
+ * Transactions which are valid but not in a block yet wait in the memory pool.
+ * The miner takes a block template from the pool, and when a block is connected,
+ * the transactions in it are removed from the pool.
+ *
+ * An input of a pending transaction can spend either an output in the UTXO set,
+ * or an output of another pending transaction. In the second case the child depends on
+ * the parent: it can only be mined in the same block as the parent or later, and it is
+ * removed together with the parent if the parent is dropped.
+ *
+ * Each output can only be spent by one pending transaction, a second transaction
+ * spending it is rejected as a conflict.
+ *
+ * After a reorg, the transactions of the disconnected blocks go back to the pool, and the
+ * pending transactions which spend an output that only existed on the old chain are removed.
+ *
+ * The block template prefers the transactions with the highest fee rate (fee per byte),
+ * a child is only taken after all its parents are taken.
+ */
+use crate::block::Block;
+use crate::blockchain::BlockChain;
+use crate::error::Result;
+use crate::hash::Txid;
+use crate::transaction::Transaction;
+use crate::utxo_set::UtxoSet;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::RwLock;
+
+/// The reason why a transaction is not accepted by `Mempool::add_transaction`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    /// A coinbase is only valid in a block, it is never relayed on its own
+    Coinbase,
+    /// The id of the transaction is not its hash
+    InvalidTxid { txid: Txid },
+    /// The transaction is already in the pool
+    AlreadyInPool { txid: Txid },
+    /// The output is neither in the UTXO set nor created by a pending transaction,
+    /// so it does not exist or it is already spent in the chain
+    MissingInput { txid: Txid, vout: usize },
+    /// The transaction spends the same output twice
+    DuplicateInput { txid: Txid, vout: usize },
+    /// The output is already spent by another pending transaction
+    Conflict {
+        txid: Txid,
+        vout: usize,
+        spent_by: Txid,
+    },
+    /// The signature of an input does not match the output it spends
+    InvalidSignature { txid: Txid },
+    /// The transaction has no inputs or no outputs
+    EmptyInputsOrOutputs { txid: Txid },
+    /// An output has a negative value
+    NegativeOutput { txid: Txid },
+    /// The outputs are worth more than the inputs
+    OutputsExceedInputs { txid: Txid },
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MempoolError::Coinbase => write!(f, "coinbase is not allowed in the mempool"),
+            MempoolError::InvalidTxid { txid } => {
+                write!(
+                    f,
+                    "transaction id {} is not the hash of the transaction",
+                    txid
+                )
+            }
+            MempoolError::AlreadyInPool { txid } => {
+                write!(f, "transaction {} is already in the mempool", txid)
+            }
+            MempoolError::MissingInput { txid, vout } => {
+                write!(f, "input {}:{} does not exist or is spent", txid, vout)
+            }
+            MempoolError::DuplicateInput { txid, vout } => {
+                write!(f, "input {}:{} is spent twice", txid, vout)
+            }
+            MempoolError::Conflict {
+                txid,
+                vout,
+                spent_by,
+            } => write!(
+                f,
+                "output {}:{} is already spent by pending transaction {}",
+                txid, vout, spent_by
+            ),
+            MempoolError::InvalidSignature { txid } => {
+                write!(f, "invalid signature in transaction {}", txid)
+            }
+            MempoolError::EmptyInputsOrOutputs { txid } => {
+                write!(f, "transaction {} has no inputs or no outputs", txid)
+            }
+            MempoolError::NegativeOutput { txid } => {
+                write!(f, "transaction {} has a negative output", txid)
+            }
+            MempoolError::OutputsExceedInputs { txid } => {
+                write!(f, "outputs of transaction {} exceed its inputs", txid)
+            }
+        }
+    }
+}
+
+// fields:
+//   - transaction: the pending transaction
+//   - depends: the pending transactions whose outputs it spends
+//   - sequence: the order in which it was accepted, breaks ties between equal fee rates.
+//     A parent put back by a reorg comes after its children, the order of parents is kept by depends
+//   - fee: inputs minus outputs
+//   - size: size of the serialized transaction in bytes
+struct MempoolEntry {
+    transaction: Transaction,
+    depends: HashSet<Txid>,
+    sequence: u64,
+    fee: i64,
+    size: usize,
+}
+
+impl MempoolEntry {
+    /// Higher fee rate first, then the earlier one.
+    /// The fee rates are compared as fee_a * size_b against fee_b * size_a, without floats.
+    fn priority_cmp(&self, other: &MempoolEntry) -> Ordering {
+        let lhs = self.fee as i128 * other.size as i128;
+        let rhs = other.fee as i128 * self.size as i128;
+        rhs.cmp(&lhs).then(self.sequence.cmp(&other.sequence))
+    }
+}
+
+#[derive(Default)]
+struct MempoolState {
+    entries: HashMap<Txid, MempoolEntry>,
+    // outpoint -> the pending transaction which spends it
+    spent: HashMap<(Txid, usize), Txid>,
+    next_sequence: u64,
+}
+
+impl MempoolState {
+    /// Remove the transaction and everything which depends on it, return the removed txids
+    fn remove_with_descendants(&mut self, txid: &Txid) -> Vec<Txid> {
+        let mut removed = vec![];
+        let mut queue = vec![*txid];
+        while let Some(txid) = queue.pop() {
+            let entry = match self.entries.remove(&txid) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            for vin in entry.transaction.get_vin() {
+                self.spent.remove(&(vin.get_txid(), vin.get_vout()));
+            }
+            for (child, child_entry) in &self.entries {
+                if child_entry.depends.contains(&txid) {
+                    queue.push(*child);
+                }
+            }
+            removed.push(txid);
+        }
+        removed
+    }
+}
+
+/// The pool can be shared between threads, e.g. the node and the miner
+#[derive(Default)]
+pub struct Mempool {
+    state: RwLock<MempoolState>,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool::default()
+    }
+
+    /// Validate the transaction against the UTXO set and the pending transactions, then add it.
+    /// A rejected transaction is reported as `Error::RejectedTransaction`.
+    pub fn add_transaction(&self, blockchain: &BlockChain, tx: Transaction) -> Result<Txid> {
+        let txid = tx.get_id();
+        // the pool and the relay are keyed by the id, so it must be the real hash
+        if !tx.has_valid_id() {
+            return Err(MempoolError::InvalidTxid { txid }.into());
+        }
+        if tx.is_coinbase() {
+            return Err(MempoolError::Coinbase.into());
+        }
+        if tx.get_vin().is_empty() || tx.get_vout().is_empty() {
+            return Err(MempoolError::EmptyInputsOrOutputs { txid }.into());
+        }
+
+        let utxo_set = UtxoSet::new(blockchain);
+        let mut state = self.state.write().unwrap();
+        if state.entries.contains_key(&txid) {
+            return Err(MempoolError::AlreadyInPool { txid }.into());
+        }
+
+        let mut depends = HashSet::new();
+        let mut prev_outputs = HashMap::new();
+        let mut outpoints = HashSet::new();
+        for vin in tx.get_vin() {
+            let outpoint = (vin.get_txid(), vin.get_vout());
+            if let Some(spent_by) = state.spent.get(&outpoint) {
+                return Err(MempoolError::Conflict {
+                    txid: outpoint.0,
+                    vout: outpoint.1,
+                    spent_by: *spent_by,
+                }
+                .into());
+            }
+            if !outpoints.insert(outpoint) {
+                return Err(MempoolError::DuplicateInput {
+                    txid: outpoint.0,
+                    vout: outpoint.1,
+                }
+                .into());
+            }
+
+            let prev_out = match state.entries.get(&vin.get_txid()) {
+                Some(parent) => {
+                    depends.insert(vin.get_txid());
+                    parent.transaction.get_vout().get(vin.get_vout()).cloned()
+                }
+                None => utxo_set.get_output(&vin.get_txid(), vin.get_vout())?,
+            };
+            let prev_out = prev_out.ok_or(MempoolError::MissingInput {
+                txid: outpoint.0,
+                vout: outpoint.1,
+            })?;
+            prev_outputs.insert(outpoint, prev_out);
+        }
+        if !tx.verify(&prev_outputs) {
+            return Err(MempoolError::InvalidSignature { txid }.into());
+        }
+        if tx.has_negative_output() {
+            return Err(MempoolError::NegativeOutput { txid }.into());
+        }
+        // verify has checked every previous output is there
+        let fee = tx.get_fee(&prev_outputs).unwrap_or(0);
+        if fee < 0 {
+            return Err(MempoolError::OutputsExceedInputs { txid }.into());
+        }
+
+        for outpoint in outpoints {
+            state.spent.insert(outpoint, txid);
+        }
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        let size = tx.get_size();
+        state.entries.insert(
+            txid,
+            MempoolEntry {
+                transaction: tx,
+                depends,
+                sequence,
+                fee,
+                size,
+            },
+        );
+        Ok(txid)
+    }
+
+    /// Remove the transactions of a connected block.
+    /// Pending transactions which spend the same outputs as the block can never be mined,
+    /// they are removed with their descendants.
+    pub fn remove_confirmed(&self, block: &Block) {
+        let mut state = self.state.write().unwrap();
+        for tx in block.get_transactions() {
+            let txid = tx.get_id();
+            if let Some(entry) = state.entries.remove(&txid) {
+                for vin in entry.transaction.get_vin() {
+                    state.spent.remove(&(vin.get_txid(), vin.get_vout()));
+                }
+                for child in state.entries.values_mut() {
+                    child.depends.remove(&txid);
+                }
+                continue;
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in tx.get_vin() {
+                if let Some(spent_by) = state.spent.get(&(vin.get_txid(), vin.get_vout())) {
+                    let spent_by = *spent_by;
+                    state.remove_with_descendants(&spent_by);
+                }
+            }
+        }
+    }
+
+    /// Update the pool after the best chain switched from the `disconnected` blocks
+    /// (tip first) to the `connected` blocks (fork first), see `BlockStatus::Reorganized`.
+    /// The transactions of the old chain go back to the pool unless the new chain spends
+    /// the same outputs. Then every pending transaction which spends an output that is
+    /// neither in the UTXO set of the new tip nor in the pool is removed with its descendants.
+    /// Return the removed txids.
+    pub fn reorganize(
+        &self,
+        blockchain: &BlockChain,
+        disconnected: &[Block],
+        connected: &[Block],
+    ) -> Result<Vec<Txid>> {
+        for block in connected {
+            self.remove_confirmed(block);
+        }
+        for block in disconnected.iter().rev() {
+            for tx in block.get_transactions() {
+                // a block may have no coinbase, so every transaction is tried; the coinbase
+                // and the transactions which are not valid on the new chain are just dropped
+                let _ = self.add_transaction(blockchain, tx.clone());
+            }
+        }
+
+        let utxo_set = UtxoSet::new(blockchain);
+        let mut state = self.state.write().unwrap();
+        let pending: HashSet<Txid> = state.entries.keys().copied().collect();
+        let mut unspendable = vec![];
+        for (txid, entry) in state.entries.iter_mut() {
+            // a parent from the old chain may be pending again
+            entry.depends = entry
+                .transaction
+                .get_vin()
+                .iter()
+                .map(|vin| vin.get_txid())
+                .filter(|parent| pending.contains(parent))
+                .collect();
+            for vin in entry.transaction.get_vin() {
+                if pending.contains(&vin.get_txid()) {
+                    continue;
+                }
+                if utxo_set
+                    .get_output(&vin.get_txid(), vin.get_vout())?
+                    .is_none()
+                {
+                    unspendable.push(*txid);
+                    break;
+                }
+            }
+        }
+        let mut removed = vec![];
+        for txid in unspendable {
+            removed.extend(state.remove_with_descendants(&txid));
+        }
+        Ok(removed)
+    }
+
+    /// Remove the transaction and the pending transactions which depend on it
+    pub fn remove_transaction(&self, txid: &Txid) -> Vec<Txid> {
+        self.state.write().unwrap().remove_with_descendants(txid)
+    }
+
+    /// Up to `max_count` transactions for the next block.
+    /// Each time, the transaction with the highest fee rate whose parents are all taken
+    /// is taken next, so each parent comes before its children in the template.
+    pub fn get_block_template(&self, max_count: usize) -> Vec<Transaction> {
+        let state = self.state.read().unwrap();
+        let mut remaining: Vec<&MempoolEntry> = state.entries.values().collect();
+        remaining.sort_by(|a, b| a.priority_cmp(b));
+
+        let mut taken = HashSet::new();
+        let mut template = vec![];
+        while template.len() < max_count {
+            let next = remaining
+                .iter()
+                .position(|entry| entry.depends.iter().all(|txid| taken.contains(txid)));
+            let entry = match next {
+                Some(pos) => remaining.remove(pos),
+                None => break,
+            };
+            taken.insert(entry.transaction.get_id());
+            template.push(entry.transaction.clone());
+        }
+        template
+    }
+
+    /// Fee per byte of a pending transaction
+    pub fn get_fee_rate(&self, txid: &Txid) -> Option<f64> {
+        let state = self.state.read().unwrap();
+        state
+            .entries
+            .get(txid)
+            .map(|entry| entry.fee as f64 / entry.size as f64)
+    }
+
+    /// Sum of the fees of all pending transactions
+    pub fn get_total_fee(&self) -> i64 {
+        let state = self.state.read().unwrap();
+        state.entries.values().map(|entry| entry.fee).sum()
+    }
+
+    /// Sum of the sizes of all pending transactions in bytes
+    pub fn get_total_size(&self) -> usize {
+        let state = self.state.read().unwrap();
+        state.entries.values().map(|entry| entry.size).sum()
+    }
+
+    pub fn get_transaction(&self, txid: &Txid) -> Option<Transaction> {
+        let state = self.state.read().unwrap();
+        state
+            .entries
+            .get(txid)
+            .map(|entry| entry.transaction.clone())
+    }
+
+    /// The pending transactions which `txid` spends from
+    pub fn get_depends(&self, txid: &Txid) -> Option<Vec<Txid>> {
+        let state = self.state.read().unwrap();
+        state
+            .entries
+            .get(txid)
+            .map(|entry| entry.depends.iter().copied().collect())
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.state.read().unwrap().entries.contains_key(txid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.read().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
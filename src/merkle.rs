@@ -0,0 +1,126 @@
+/* # Merkle Tree
+ *
+ * The leaves are the transaction ids of a block, each pair of nodes is hashed into its parent,
+ * until only the root is left. The root goes into the block hash.
+ *
+ * To prove a transaction is in a block, we only need the sibling of each node on the path
+ * from the leaf to the root, that is log2(n) hashes instead of the whole block.
+ *
+ * Leaves and inner nodes are hashed with different prefixes, so an inner node can not be
+ * passed off as a leaf. When a level has an odd number of nodes, the last one is moved up
+ * unchanged instead of being paired with a copy of itself, so two different transaction
+ * lists can not have the same root.
+ */
+use crate::hash::{MerkleRoot, Txid, HASH_LENGTH};
+use crate::utils::sha256_digest;
+use serde::{Deserialize, Serialize};
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+type NodeHash = [u8; HASH_LENGTH];
+
+fn digest(data: &[u8]) -> NodeHash {
+    let mut hash = [0u8; HASH_LENGTH];
+    hash.copy_from_slice(&sha256_digest(data));
+    hash
+}
+
+fn hash_leaf(txid: &Txid) -> NodeHash {
+    let mut data = vec![LEAF_PREFIX];
+    data.extend(txid.as_bytes());
+    digest(&data)
+}
+
+fn hash_node(left: &NodeHash, right: &NodeHash) -> NodeHash {
+    let mut data = vec![NODE_PREFIX];
+    data.extend(left);
+    data.extend(right);
+    digest(&data)
+}
+
+/// One step from a node to its parent: the sibling hash, and whether the sibling is on the left
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    hash: NodeHash,
+    is_left: bool,
+}
+
+/// Proof that `txid` is one of the leaves of a merkle tree
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    txid: Txid,
+    steps: Vec<ProofStep>,
+}
+
+impl MerkleProof {
+    pub fn get_txid(&self) -> Txid {
+        self.txid
+    }
+
+    /// Hash from the leaf up to the root, and compare with the expected root
+    pub fn verify(&self, root: &MerkleRoot) -> bool {
+        let mut hash = hash_leaf(&self.txid);
+        for step in &self.steps {
+            hash = if step.is_left {
+                hash_node(&step.hash, &hash)
+            } else {
+                hash_node(&hash, &step.hash)
+            };
+        }
+        MerkleRoot::new(hash) == *root
+    }
+}
+
+/// levels[0] are the leaf hashes, the last level only has the root
+pub struct MerkleTree {
+    txids: Vec<Txid>,
+    levels: Vec<Vec<NodeHash>>,
+}
+
+impl MerkleTree {
+    pub fn new(txids: &[Txid]) -> MerkleTree {
+        let mut levels = vec![txids.iter().map(hash_leaf).collect::<Vec<_>>()];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let mut parents = vec![];
+            for pair in level.chunks(2) {
+                if pair.len() == 2 {
+                    parents.push(hash_node(&pair[0], &pair[1]));
+                } else {
+                    parents.push(pair[0]);
+                }
+            }
+            levels.push(parents);
+        }
+        MerkleTree {
+            txids: txids.to_vec(),
+            levels,
+        }
+    }
+
+    /// The root of an empty tree is the hash of nothing
+    pub fn root(&self) -> MerkleRoot {
+        match self.levels.last().unwrap().first() {
+            Some(root) => MerkleRoot::new(*root),
+            None => MerkleRoot::digest(&[]),
+        }
+    }
+
+    /// Build the proof for `txid`, return None if it is not a leaf of the tree
+    pub fn proof(&self, txid: &Txid) -> Option<MerkleProof> {
+        let mut index = self.txids.iter().position(|id| id == txid)?;
+        let mut steps = vec![];
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = index ^ 1;
+            if sibling < level.len() {
+                steps.push(ProofStep {
+                    hash: level[sibling],
+                    is_left: sibling < index,
+                });
+            }
+            index /= 2;
+        }
+        Some(MerkleProof { txid: *txid, steps })
+    }
+}
@@ -0,0 +1,336 @@
+/* # Miner
+ *
+ * Mining is the search for a header whose hash is less than the target, see `ProofOfWork`.
+ * A `MiningJob` searches with several threads:
+ *   - the nonces 0..=max_nonce of a header are split into one range per thread
+ *   - when every nonce is tried, the extra nonce in the coinbase goes up by one, which gives
+ *     a new merkle root, and the threads search the new header. The timestamp stays,
+ *     rolling it forward could take it past `MAX_FUTURE_BLOCK_TIME`.
+ *   - the job ends when a thread finds a block, or when it is cancelled with its `CancelHandle`,
+ *     e.g. because a block from a peer made the template stale
+ *
+ * The job counts the hashes it tried, see `MiningStats` for the hashrate.
+ */
+use crate::block::{Block, BlockHeader, ProofOfWork};
+use crate::error::Result;
+use crate::hash::BlockHash;
+use crate::transaction::Transaction;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A worker adds its hashes to the count, and looks for the end of the search,
+/// after this many hashes
+const CHECK_INTERVAL: u64 = 1024;
+
+/// Everything of a block but the coinbase and the nonce, see `BlockChain::create_block_template`
+// fields:
+//   - fees: the fees of `transactions`, paid to `miner_address` with the subsidy
+//   - transactions: the transactions after the coinbase
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pre_block_hash: BlockHash,
+    height: usize,
+    bits: u32,
+    timestamp: u64,
+    miner_address: String,
+    fees: i32,
+    transactions: Vec<Transaction>,
+}
+
+impl BlockTemplate {
+    pub fn new(
+        pre_block_hash: BlockHash,
+        height: usize,
+        bits: u32,
+        timestamp: u64,
+        miner_address: &str,
+        fees: i32,
+        transactions: Vec<Transaction>,
+    ) -> BlockTemplate {
+        BlockTemplate {
+            pre_block_hash,
+            height,
+            bits,
+            timestamp,
+            miner_address: String::from(miner_address),
+            fees,
+            transactions,
+        }
+    }
+
+    pub fn get_pre_block_hash(&self) -> BlockHash {
+        self.pre_block_hash
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// The transactions of the block, a coinbase with `extra_nonce` in front,
+    /// and their header with nonce 0
+    fn build(&self, extra_nonce: u64) -> Result<(BlockHeader, Vec<Transaction>)> {
+        let coinbase = Transaction::new_coinbase_tx_with_extra_nonce(
+            &self.miner_address,
+            self.height,
+            self.fees,
+            extra_nonce,
+        )?;
+        let mut transactions = vec![coinbase];
+        transactions.extend_from_slice(&self.transactions);
+        let header = BlockHeader::new(
+            self.pre_block_hash,
+            &transactions,
+            self.height,
+            self.bits,
+            self.timestamp,
+        );
+        Ok((header, transactions))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MinerConfig {
+    /// Number of worker threads, at least one is used
+    pub threads: usize,
+    /// Highest nonce tried before the extra nonce is rolled, tests use small ones
+    pub max_nonce: i64,
+}
+
+/// One thread for each CPU, and the whole nonce space
+impl Default for MinerConfig {
+    fn default() -> Self {
+        MinerConfig {
+            threads: thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1),
+            max_nonce: i64::MAX,
+        }
+    }
+}
+
+/// Stops a `MiningJob` from another thread, it can be cloned
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// The workers stop within `CHECK_INTERVAL` hashes, and the job ends without a block
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Handles of the same job are equal
+impl PartialEq for CancelHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+    }
+}
+
+impl Eq for CancelHandle {}
+
+/// Progress of a `MiningJob`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MiningStats {
+    hashes: u64,
+    elapsed: Duration,
+    extra_nonce: u64,
+}
+
+impl MiningStats {
+    /// Hashes tried so far, counted in batches while the workers run
+    pub fn get_hashes(&self) -> u64 {
+        self.hashes
+    }
+
+    /// Time since the job started, or its duration once it ended
+    pub fn get_elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The extra nonce of the header being searched, or of the block found
+    pub fn get_extra_nonce(&self) -> u64 {
+        self.extra_nonce
+    }
+
+    /// Hashes per second
+    pub fn get_hashrate(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.hashes as f64 / seconds
+    }
+}
+
+// fields:
+//   - hashes, extra_nonce: see `MiningStats`
+//   - finished: when the job ended, it stops the clock of the stats
+struct JobShared {
+    cancel: CancelHandle,
+    hashes: AtomicU64,
+    extra_nonce: AtomicU64,
+    started: Instant,
+    finished: Mutex<Option<Instant>>,
+}
+
+type JobResult = Result<Option<Block>>;
+
+/// A block being mined in the background, the job is cancelled when dropped
+pub struct MiningJob {
+    shared: Arc<JobShared>,
+    runner: Mutex<Option<JoinHandle<JobResult>>>,
+}
+
+impl MiningJob {
+    /// Start mining the template, the block is returned by `wait`
+    pub fn start(template: BlockTemplate, config: MinerConfig) -> MiningJob {
+        let shared = Arc::new(JobShared {
+            cancel: CancelHandle::default(),
+            hashes: AtomicU64::new(0),
+            extra_nonce: AtomicU64::new(0),
+            started: Instant::now(),
+            finished: Mutex::new(None),
+        });
+        let runner = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let result = run_job(&shared, &template, &config);
+                *shared.finished.lock().unwrap() = Some(Instant::now());
+                result
+            })
+        };
+        MiningJob {
+            shared,
+            runner: Mutex::new(Some(runner)),
+        }
+    }
+
+    pub fn get_cancel_handle(&self) -> CancelHandle {
+        self.shared.cancel.clone()
+    }
+
+    pub fn get_stats(&self) -> MiningStats {
+        let finished = *self.shared.finished.lock().unwrap();
+        let end = finished.unwrap_or_else(Instant::now);
+        MiningStats {
+            hashes: self.shared.hashes.load(Ordering::SeqCst),
+            elapsed: end.duration_since(self.shared.started),
+            extra_nonce: self.shared.extra_nonce.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Wait until the job ends, return the block, or None if the job was cancelled.
+    /// The block is only returned by the first call.
+    pub fn wait(&self) -> Result<Option<Block>> {
+        let runner = self.runner.lock().unwrap().take();
+        match runner {
+            Some(runner) => runner.join().expect("the miner thread panicked"),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Drop for MiningJob {
+    fn drop(&mut self) {
+        self.shared.cancel.cancel();
+        let _ = self.wait();
+    }
+}
+
+/// The nonces 0..=max_nonce in at most `threads` ranges of almost the same size,
+/// without gaps or overlaps
+pub fn split_nonces(max_nonce: i64, threads: usize) -> Vec<RangeInclusive<i64>> {
+    // at most 2^63 nonces, so the count and the bounds fit into u64
+    let count = max_nonce.max(0) as u64 + 1;
+    let threads = (threads.max(1) as u64).min(count);
+    let (size, rest) = (count / threads, count % threads);
+    let mut ranges = vec![];
+    let mut start = 0u64;
+    for index in 0..threads {
+        let len = size + u64::from(index < rest);
+        ranges.push(start as i64..=(start + len - 1) as i64);
+        start += len;
+    }
+    ranges
+}
+
+/// Search header after header, until a block is found or the job is cancelled
+fn run_job(shared: &JobShared, template: &BlockTemplate, config: &MinerConfig) -> JobResult {
+    let ranges = split_nonces(config.max_nonce, config.threads);
+    let mut extra_nonce = 0u64;
+    while !shared.cancel.is_cancelled() {
+        shared.extra_nonce.store(extra_nonce, Ordering::SeqCst);
+        let (header, transactions) = template.build(extra_nonce)?;
+        let pow = ProofOfWork::new(header);
+        if let Some(nonce) = search(shared, &pow, &ranges) {
+            return Ok(Some(Block::from_parts(
+                pow.into_header(nonce),
+                transactions,
+            )));
+        }
+        extra_nonce += 1;
+    }
+    Ok(None)
+}
+
+/// Search the ranges in parallel, return the lowest nonce found
+fn search(shared: &JobShared, pow: &ProofOfWork, ranges: &[RangeInclusive<i64>]) -> Option<i64> {
+    // set by the first worker which finds a nonce, the others stop
+    let found = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let workers: Vec<_> = ranges
+            .iter()
+            .map(|range| {
+                let found = &found;
+                scope.spawn(move || search_range(shared, pow, range.clone(), found))
+            })
+            .collect();
+        workers
+            .into_iter()
+            .filter_map(|worker| worker.join().expect("a miner worker panicked"))
+            .min()
+    })
+}
+
+fn search_range(
+    shared: &JobShared,
+    pow: &ProofOfWork,
+    nonces: RangeInclusive<i64>,
+    found: &AtomicBool,
+) -> Option<i64> {
+    let mut hashes = 0;
+    for nonce in nonces {
+        hashes += 1;
+        if pow.try_nonce(nonce).is_some() {
+            found.store(true, Ordering::SeqCst);
+            shared.hashes.fetch_add(hashes, Ordering::SeqCst);
+            return Some(nonce);
+        }
+        if hashes == CHECK_INTERVAL {
+            shared.hashes.fetch_add(hashes, Ordering::SeqCst);
+            hashes = 0;
+            if found.load(Ordering::SeqCst) || shared.cancel.is_cancelled() {
+                return None;
+            }
+        }
+    }
+    shared.hashes.fetch_add(hashes, Ordering::SeqCst);
+    None
+}
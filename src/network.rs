@@ -0,0 +1,269 @@
+/* # Network Protocol
+ *
+ * Nodes talk to each other over TCP. Each message is sent as a frame:
+ *   magic (4 bytes) + payload length (4 bytes, big endian) + checksum (4 bytes) + payload
+ * The payload is the bincode encoding of a `Message`, and the checksum is the first 4 bytes of
+ * sha256(sha256(payload)). The magic tells a node of another network or program from ours.
+ *
+ * A connection starts with the handshake: both sides send `Version`, and each answers the
+ * other side's `Version` with `Verack`. Other messages are only sent after the handshake.
+ *
+ * Blocks and transactions are announced with `Inv`, and fetched with `GetData`:
+ *   A: inv [block X]  ->  B: getdata [block X]  ->  A: block X
+ *
+ * A node which is missing blocks sends `GetBlocks` with a block locator, the hashes of its
+ * best chain from the tip back to the genesis block, see `BlockChain::get_block_locator`.
+ * The peer finds the fork on its own best chain, and announces the blocks after it.
+ * `GetHeaders` works the same way, but the peer answers with the headers themselves,
+ * which is how a new node catches up, see `sync`.
+ */
+use crate::address::checksum;
+use crate::block::{Block, BlockHeader};
+use crate::error::{Error, Result};
+use crate::hash::{BlockHash, Txid};
+use crate::transaction::Transaction;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+
+/// First bytes of every frame
+pub const NETWORK_MAGIC: [u8; 4] = *b"TOYB";
+/// Version of the message protocol, sent in the handshake.
+/// Version 2 added `GetHeaders` and `Headers`, version 3 the chain work in `Version`.
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Peers with an older protocol version are refused
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+/// Size of the frame before the payload: magic, length and checksum
+pub const FRAME_HEADER_LENGTH: usize = 12;
+/// Bigger payloads are refused before they are read, so a peer can not make us allocate
+/// any amount of memory
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+/// Most items in one `Inv` or `GetData`, an answer to `GetBlocks` has at most this many hashes
+pub const MAX_INV_SIZE: usize = 500;
+/// Most addresses in one `Addr`
+pub const MAX_ADDR_SIZE: usize = 1000;
+/// Most headers in one `Headers`, a full answer means the peer may have more
+pub const MAX_HEADERS_SIZE: usize = 2000;
+
+/// The reason why a peer or one of its messages is refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkError {
+    /// The frame does not start with `NETWORK_MAGIC`
+    InvalidMagic,
+    /// The payload is bigger than `MAX_MESSAGE_SIZE`
+    MessageTooLarge { size: usize },
+    /// The checksum does not match the payload
+    InvalidChecksum,
+    /// The peer's protocol version is older than `MIN_PROTOCOL_VERSION`
+    UnsupportedVersion { version: u32 },
+    /// The peer's chain starts with another genesis block
+    GenesisMismatch {
+        expected: BlockHash,
+        found: BlockHash,
+    },
+    /// The node has connected to itself
+    SelfConnection,
+    /// The message is not allowed at this point, e.g. a second `Version`,
+    /// or a list over its limit
+    UnexpectedMessage(String),
+    /// The peer did not finish the handshake in time, or closed the connection
+    HandshakeFailed,
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::InvalidMagic => write!(f, "invalid network magic"),
+            NetworkError::MessageTooLarge { size } => write!(
+                f,
+                "message of {} bytes is larger than {} bytes",
+                size, MAX_MESSAGE_SIZE
+            ),
+            NetworkError::InvalidChecksum => write!(f, "message checksum does not match"),
+            NetworkError::UnsupportedVersion { version } => {
+                write!(f, "protocol version {} is not supported", version)
+            }
+            NetworkError::GenesisMismatch { expected, found } => write!(
+                f,
+                "genesis block mismatch, expected {}, found {}",
+                expected, found
+            ),
+            NetworkError::SelfConnection => write!(f, "connected to ourselves"),
+            NetworkError::UnexpectedMessage(msg) => write!(f, "unexpected message: {}", msg),
+            NetworkError::HandshakeFailed => write!(f, "handshake failed"),
+        }
+    }
+}
+
+// fields:
+//   - version: the sender's `PROTOCOL_VERSION`
+//   - genesis_hash: the first block of the sender's chain, both sides must have the same one
+//   - best_height: height of the sender's tip, the side with the lower height asks for blocks
+//   - chain_work: work of the sender's best chain, headers are asked from the peer with the most
+//   - listen_addr: the address the sender accepts connections on, shared with other peers
+//   - nonce: random for each node, a node which receives its own nonce connected to itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionMessage {
+    version: u32,
+    genesis_hash: BlockHash,
+    best_height: usize,
+    chain_work: u128,
+    listen_addr: Option<SocketAddr>,
+    nonce: u64,
+}
+
+impl VersionMessage {
+    pub fn new(
+        genesis_hash: BlockHash,
+        best_height: usize,
+        chain_work: u128,
+        listen_addr: Option<SocketAddr>,
+        nonce: u64,
+    ) -> VersionMessage {
+        VersionMessage {
+            version: PROTOCOL_VERSION,
+            genesis_hash,
+            best_height,
+            chain_work,
+            listen_addr,
+            nonce,
+        }
+    }
+
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn get_genesis_hash(&self) -> BlockHash {
+        self.genesis_hash
+    }
+
+    pub fn get_best_height(&self) -> usize {
+        self.best_height
+    }
+
+    pub fn get_chain_work(&self) -> u128 {
+        self.chain_work
+    }
+
+    pub fn get_listen_addr(&self) -> Option<SocketAddr> {
+        self.listen_addr
+    }
+
+    pub fn get_nonce(&self) -> u64 {
+        self.nonce
+    }
+}
+
+/// A block or a transaction, announced by its hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Inventory {
+    Block(BlockHash),
+    Tx(Txid),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Message {
+    /// First message of the handshake
+    Version(VersionMessage),
+    /// The `Version` of the peer is accepted
+    Verack,
+    /// Announce blocks and transactions, the receiver asks for the new ones with `GetData`
+    Inv(Vec<Inventory>),
+    /// Ask for the hashes of the blocks after the fork, answered with `Inv`.
+    /// `locator` is a block locator, and the answer ends at `stop` if it is on the chain.
+    GetBlocks {
+        locator: Vec<BlockHash>,
+        stop: BlockHash,
+    },
+    /// Same as `GetBlocks`, answered with `Headers`
+    GetHeaders {
+        locator: Vec<BlockHash>,
+        stop: BlockHash,
+    },
+    /// The headers after the fork, oldest first
+    Headers(Vec<BlockHeader>),
+    /// Ask for blocks and transactions, answered with a `Block` or `Tx` for each one
+    /// the peer has
+    GetData(Vec<Inventory>),
+    Block(Block),
+    Tx(Transaction),
+    /// Addresses of nodes which accept connections
+    Addr(Vec<SocketAddr>),
+    /// Check the peer is still there, it answers with `Pong` and the same nonce
+    Ping(u64),
+    Pong(u64),
+}
+
+impl Message {
+    /// Short name for logs
+    pub fn get_command(&self) -> &'static str {
+        match self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Inv(_) => "inv",
+            Message::GetBlocks { .. } => "getblocks",
+            Message::GetHeaders { .. } => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::GetData(_) => "getdata",
+            Message::Block(_) => "block",
+            Message::Tx(_) => "tx",
+            Message::Addr(_) => "addr",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+        }
+    }
+
+    /// The whole frame: magic, length, checksum and payload
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let payload = bincode::serialize(self)?;
+        if payload.len() > MAX_MESSAGE_SIZE {
+            return Err(NetworkError::MessageTooLarge {
+                size: payload.len(),
+            }
+            .into());
+        }
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LENGTH + payload.len());
+        frame.extend(NETWORK_MAGIC);
+        frame.extend((payload.len() as u32).to_be_bytes());
+        frame.extend(checksum(&payload));
+        frame.extend(payload);
+        Ok(frame)
+    }
+}
+
+/// Write one message to the stream
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<()> {
+    writer.write_all(&message.encode()?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read one message from the stream.
+/// The frame header is checked before the payload is read, and the checksum before it is decoded.
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Message> {
+    let mut header = [0u8; FRAME_HEADER_LENGTH];
+    reader.read_exact(&mut header)?;
+    if header[0..4] != NETWORK_MAGIC {
+        return Err(NetworkError::InvalidMagic.into());
+    }
+    let mut length = [0u8; 4];
+    length.copy_from_slice(&header[4..8]);
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(NetworkError::MessageTooLarge { size: length }.into());
+    }
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload)?;
+    if checksum(&payload) != header[8..12] {
+        return Err(NetworkError::InvalidChecksum.into());
+    }
+    let message: Message = bincode::deserialize(&payload).map_err(|err| {
+        Error::from(NetworkError::UnexpectedMessage(format!(
+            "can not decode payload: {}",
+            err
+        )))
+    })?;
+    Ok(message)
+}
@@ -0,0 +1,1075 @@
+/* # Node
+ *
+ * The node shares the blockchain and the mempool with its peers:
+ *   - the main loop accepts inbound connections, and pings the peers
+ *   - each peer has a reader thread, which handles its messages one by one,
+ *     so the answers to a peer go out in the order of its requests
+ *   - each peer has a writer thread with a queue, so a slow peer never blocks the node
+ *
+ * A new block or transaction, mined here or received from a peer, is announced with `Inv`
+ * to every peer which does not know it yet. A node which is behind its peers, e.g. a new one,
+ * downloads the headers first and then the blocks, see `sync`. A block whose parent is missing
+ * starts the same download.
+ *
+ * All nodes of a network must start from the same genesis block, the handshake refuses
+ * peers with another one.
+ *
+ * The node does not print, what happens to the peers and the download is sent as `NodeEvent`s
+ * to `NodeConfig::events`, if set.
+ */
+use crate::block::Block;
+use crate::blockchain::{BlockChain, BlockStatus, BlockValidationError};
+use crate::error::{Error, Result};
+use crate::hash::{BlockHash, Txid};
+use crate::mempool::Mempool;
+use crate::miner::{CancelHandle, MinerConfig, MiningJob, MiningStats};
+use crate::network::{
+    read_message, write_message, Inventory, Message, NetworkError, VersionMessage, MAX_ADDR_SIZE,
+    MAX_HEADERS_SIZE, MAX_INV_SIZE, MIN_PROTOCOL_VERSION,
+};
+use crate::sync::{BlockReceipt, BlockSync, SyncProgress, MAX_QUEUED_HEADERS};
+use crate::transaction::Transaction;
+use crate::utils::random_u64;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, BufReader, ErrorKind};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Id of a connection, unique for the lifetime of the node
+pub type PeerId = u64;
+
+/// Most transactions of the mempool the node puts in a block it mines
+pub const MAX_BLOCK_TRANSACTIONS: usize = 1000;
+/// Most addresses of other nodes the node remembers
+pub const MAX_KNOWN_ADDRS: usize = 1000;
+/// Most inbound connections, with the ones still in the handshake, each one has its own threads
+pub const MAX_INBOUND_PEERS: usize = 64;
+/// Most blocks and transactions remembered as known to a peer, the set is cleared when full
+const MAX_KNOWN_INVENTORY: usize = 50_000;
+/// How often the main loop looks for new connections and peers to ping
+const MAIN_LOOP_TICK: Duration = Duration::from_millis(20);
+
+/// Timeouts and limits of the node, the defaults suit a real network, tests use shorter ones
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    /// Time to open an outbound TCP connection
+    pub connect_timeout: Duration,
+    /// Time for the whole handshake, for both inbound and outbound peers
+    pub handshake_timeout: Duration,
+    /// Time between two pings to the same peer
+    pub ping_interval: Duration,
+    /// A peer which does not answer a ping in this time is disconnected
+    pub ping_timeout: Duration,
+    /// A peer which does not answer `GetHeaders` in this time is disconnected
+    pub headers_timeout: Duration,
+    /// A peer which does not send a requested block in this time is disconnected,
+    /// and the block is requested from another peer
+    pub block_timeout: Duration,
+    /// Most checked headers which wait for their blocks in the block download
+    pub max_queued_headers: usize,
+    /// Threads of `Node::mine_block`
+    pub miner: MinerConfig,
+    /// While this many inbound peers are connected, new connections wait in the listen backlog
+    pub max_inbound_peers: usize,
+    /// Where the node sends its `NodeEvent`s, None to drop them
+    pub events: Option<Sender<NodeEvent>>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            connect_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(10),
+            ping_interval: Duration::from_secs(60),
+            ping_timeout: Duration::from_secs(20),
+            headers_timeout: Duration::from_secs(30),
+            block_timeout: Duration::from_secs(30),
+            max_queued_headers: MAX_QUEUED_HEADERS,
+            miner: MinerConfig::default(),
+            max_inbound_peers: MAX_INBOUND_PEERS,
+            events: None,
+        }
+    }
+}
+
+/// What happens to the peers and the block download, e.g. for the CLI to print
+#[derive(Debug)]
+pub enum NodeEvent {
+    PeerConnected {
+        addr: SocketAddr,
+        inbound: bool,
+    },
+    /// The connection was closed or failed, or the peer misbehaved
+    PeerDisconnected {
+        addr: SocketAddr,
+        err: Error,
+    },
+    PingTimeout {
+        addr: SocketAddr,
+    },
+    /// The handshake of an inbound connection failed
+    InboundFailed {
+        addr: SocketAddr,
+        err: Error,
+    },
+    AcceptFailed(io::Error),
+    /// The peer did not send the headers or blocks in time
+    DownloadStalled {
+        peer: PeerId,
+    },
+    /// A downloaded block is invalid, its headers and the peer which sent them are dropped
+    InvalidBlock {
+        hash: BlockHash,
+        err: BlockValidationError,
+    },
+    /// The download is not done yet
+    SyncProgress {
+        height: usize,
+        header_height: usize,
+        blocks_in_flight: usize,
+    },
+    /// The headers and blocks could not be requested, e.g. the db can not be read
+    SyncFailed(Error),
+}
+
+impl fmt::Display for NodeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeEvent::PeerConnected { addr, inbound } => write!(
+                f,
+                "connected to peer {} ({})",
+                addr,
+                if *inbound { "inbound" } else { "outbound" }
+            ),
+            NodeEvent::PeerDisconnected { addr, err } => {
+                write!(f, "disconnected peer {}: {}", addr, err)
+            }
+            NodeEvent::PingTimeout { addr } => write!(f, "peer {} did not answer the ping", addr),
+            NodeEvent::InboundFailed { addr, err } => {
+                write!(f, "inbound connection from {} failed: {}", addr, err)
+            }
+            NodeEvent::AcceptFailed(err) => write!(f, "failed to accept a connection: {}", err),
+            NodeEvent::DownloadStalled { peer } => {
+                write!(f, "peer {} stalled the block download", peer)
+            }
+            NodeEvent::InvalidBlock { hash, err } => {
+                write!(f, "downloaded block {} is invalid: {}", hash, err)
+            }
+            NodeEvent::SyncProgress {
+                height,
+                header_height,
+                blocks_in_flight,
+            } => write!(
+                f,
+                "synced to height {} of {}, {} blocks in flight",
+                height, header_height, blocks_in_flight
+            ),
+            NodeEvent::SyncFailed(err) => {
+                write!(f, "failed to update the block download: {}", err)
+            }
+        }
+    }
+}
+
+/// A connected peer, as returned by `Node::get_peers`
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    id: PeerId,
+    addr: SocketAddr,
+    inbound: bool,
+    version: VersionMessage,
+    ping_time: Option<Duration>,
+}
+
+impl PeerInfo {
+    pub fn get_id(&self) -> PeerId {
+        self.id
+    }
+
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// True if the peer connected to us
+    pub fn is_inbound(&self) -> bool {
+        self.inbound
+    }
+
+    /// The `Version` the peer sent in the handshake
+    pub fn get_version(&self) -> &VersionMessage {
+        &self.version
+    }
+
+    /// Round trip time of the last answered ping
+    pub fn get_ping_time(&self) -> Option<Duration> {
+        self.ping_time
+    }
+}
+
+// fields:
+//   - known_inventory: blocks and transactions the peer has, or was told about
+//   - ping: nonce and send time of the ping which waits for its pong
+//   - last_ping: when the last ping was sent
+//   - ping_time: round trip time of the last answered ping
+struct PeerState {
+    known_inventory: HashSet<Inventory>,
+    ping: Option<(u64, Instant)>,
+    last_ping: Instant,
+    ping_time: Option<Duration>,
+}
+
+// fields:
+//   - stream: only used to shut the connection down, the reader and writer threads use clones
+//   - sender: the queue of the writer thread
+struct Peer {
+    id: PeerId,
+    addr: SocketAddr,
+    inbound: bool,
+    version: VersionMessage,
+    stream: TcpStream,
+    sender: Sender<Message>,
+    state: Mutex<PeerState>,
+}
+
+impl Peer {
+    /// Queue a message for the writer thread.
+    /// When the connection is closed, the writer thread is gone and the message is dropped.
+    fn send(&self, message: Message) {
+        let _ = self.sender.send(message);
+    }
+
+    /// Remember the peer knows the item, return false if it was known already
+    fn add_known(&self, item: Inventory) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.known_inventory.len() >= MAX_KNOWN_INVENTORY {
+            state.known_inventory.clear();
+        }
+        state.known_inventory.insert(item)
+    }
+
+    /// Close the connection, the reader thread then removes the peer
+    fn disconnect(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    fn get_info(&self) -> PeerInfo {
+        PeerInfo {
+            id: self.id,
+            addr: self.addr,
+            inbound: self.inbound,
+            version: self.version.clone(),
+            ping_time: self.state.lock().unwrap().ping_time,
+        }
+    }
+}
+
+// fields:
+//   - local_addr: the address the node listens on
+//   - genesis_hash: peers must have the same genesis block
+//   - nonce: random, sent in `Version` to detect connections to ourselves
+//   - known_addrs: listen addresses of other nodes, learned from handshakes and `Addr`
+//   - sync: the download of headers and blocks, never locked while `peers` is
+//   - connect_lock: only one thread connects the downloaded blocks, in height order
+//   - mining_jobs: the running jobs of `mine_block`, they are cancelled by a new tip
+//   - inbound_peers: the inbound connections, at most `NodeConfig::max_inbound_peers`
+struct NodeShared {
+    blockchain: Arc<BlockChain>,
+    mempool: Arc<Mempool>,
+    config: NodeConfig,
+    local_addr: SocketAddr,
+    genesis_hash: BlockHash,
+    nonce: u64,
+    peers: RwLock<HashMap<PeerId, Arc<Peer>>>,
+    next_peer_id: AtomicU64,
+    known_addrs: RwLock<HashSet<SocketAddr>>,
+    sync: Mutex<BlockSync>,
+    connect_lock: Mutex<()>,
+    mining_jobs: Mutex<Vec<CancelHandle>>,
+    inbound_peers: AtomicUsize,
+    shutdown: AtomicBool,
+}
+
+/// A running node, it is shut down when dropped
+pub struct Node {
+    shared: Arc<NodeShared>,
+    main_loop: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Node {
+    /// Listen on `listen_addr`, e.g. "127.0.0.1:0" for any free port, see `get_local_addr`
+    pub fn start(
+        blockchain: Arc<BlockChain>,
+        mempool: Arc<Mempool>,
+        listen_addr: &str,
+    ) -> Result<Node> {
+        Self::start_with_config(blockchain, mempool, listen_addr, NodeConfig::default())
+    }
+
+    /// Same as `start`, with other timeouts
+    pub fn start_with_config(
+        blockchain: Arc<BlockChain>,
+        mempool: Arc<Mempool>,
+        listen_addr: &str,
+        config: NodeConfig,
+    ) -> Result<Node> {
+        let listener = TcpListener::bind(listen_addr)?;
+        // the main loop polls the listener, so it can also ping peers and see the shutdown
+        listener.set_nonblocking(true)?;
+        let shared = Arc::new(NodeShared {
+            genesis_hash: blockchain.get_genesis_hash()?,
+            blockchain,
+            mempool,
+            config: config.clone(),
+            local_addr: listener.local_addr()?,
+            nonce: random_u64()?,
+            peers: RwLock::new(HashMap::new()),
+            next_peer_id: AtomicU64::new(0),
+            known_addrs: RwLock::new(HashSet::new()),
+            sync: Mutex::new(BlockSync::new(
+                config.headers_timeout,
+                config.block_timeout,
+                config.max_queued_headers,
+            )),
+            connect_lock: Mutex::new(()),
+            mining_jobs: Mutex::new(vec![]),
+            inbound_peers: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+        });
+        let main_loop = {
+            let shared = shared.clone();
+            thread::spawn(move || run_main_loop(shared, listener))
+        };
+        Ok(Node {
+            shared,
+            main_loop: Mutex::new(Some(main_loop)),
+        })
+    }
+
+    pub fn get_local_addr(&self) -> SocketAddr {
+        self.shared.local_addr
+    }
+
+    pub fn get_blockchain(&self) -> &Arc<BlockChain> {
+        &self.shared.blockchain
+    }
+
+    pub fn get_mempool(&self) -> &Arc<Mempool> {
+        &self.shared.mempool
+    }
+
+    /// Connect to a node and do the handshake, return the id of the new peer
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> Result<PeerId> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "the address resolves to nothing")
+        })?;
+        let stream = TcpStream::connect_timeout(&addr, self.shared.config.connect_timeout)?;
+        add_peer(&self.shared, stream, false)
+    }
+
+    /// Close the connection to a peer, return false if there is no such peer
+    pub fn disconnect_peer(&self, id: PeerId) -> bool {
+        match self.shared.peers.read().unwrap().get(&id) {
+            Some(peer) => {
+                peer.disconnect();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The peers which finished the handshake
+    pub fn get_peers(&self) -> Vec<PeerInfo> {
+        let peers = self.shared.peers.read().unwrap();
+        let mut infos: Vec<PeerInfo> = peers.values().map(|peer| peer.get_info()).collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    pub fn get_peer_count(&self) -> usize {
+        self.shared.peers.read().unwrap().len()
+    }
+
+    /// Listen addresses of other nodes, learned from the peers
+    pub fn get_known_addrs(&self) -> Vec<SocketAddr> {
+        self.shared.get_known_addrs()
+    }
+
+    /// How far the download of headers and blocks from the peers is
+    pub fn get_sync_progress(&self) -> Result<SyncProgress> {
+        self.shared
+            .sync
+            .lock()
+            .unwrap()
+            .get_progress(&self.shared.blockchain)
+    }
+
+    /// Add a block from outside the network, e.g. mined by another program,
+    /// and announce it if it changes the best chain
+    pub fn submit_block(&self, block: &Block) -> Result<BlockStatus> {
+        self.shared.process_block(block)
+    }
+
+    /// Add a transaction to the mempool, and announce it
+    pub fn submit_transaction(&self, tx: Transaction) -> Result<Txid> {
+        let txid = self
+            .shared
+            .mempool
+            .add_transaction(&self.shared.blockchain, tx)?;
+        self.shared.relay(Inventory::Tx(txid));
+        Ok(txid)
+    }
+
+    /// Mine a block on the tip with the transactions of the mempool, and announce it.
+    /// Return the block and the stats of the miner, e.g. for the hashrate.
+    /// A new tip from a peer makes the block stale, the miner then stops with
+    /// `Error::MiningCancelled`.
+    pub fn mine_block(&self, miner_address: &str) -> Result<(Block, MiningStats)> {
+        let transactions = self
+            .shared
+            .mempool
+            .get_block_template(MAX_BLOCK_TRANSACTIONS);
+        let template = self
+            .shared
+            .blockchain
+            .create_block_template(miner_address, &transactions)?;
+        let job = MiningJob::start(template, self.shared.config.miner.clone());
+        let cancel = job.get_cancel_handle();
+        self.shared.mining_jobs.lock().unwrap().push(cancel.clone());
+        let result = job.wait();
+        self.shared
+            .mining_jobs
+            .lock()
+            .unwrap()
+            .retain(|handle| *handle != cancel);
+        let block = result?.ok_or(Error::MiningCancelled)?;
+        self.shared.process_block(&block)?;
+        Ok((block, job.get_stats()))
+    }
+
+    /// Wait until the node is shut down, e.g. by another thread
+    pub fn join(&self) {
+        let main_loop = self.main_loop.lock().unwrap().take();
+        if let Some(main_loop) = main_loop {
+            let _ = main_loop.join();
+        }
+    }
+
+    /// Stop the main loop and close every connection
+    pub fn shutdown(&self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        for peer in self.shared.peers.read().unwrap().values() {
+            peer.disconnect();
+        }
+        self.join();
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl NodeShared {
+    /// Hand an event to the caller, if it asked for them
+    fn report(&self, event: NodeEvent) {
+        if let Some(events) = &self.config.events {
+            let _ = events.send(event);
+        }
+    }
+
+    fn get_version_message(&self) -> Result<VersionMessage> {
+        Ok(VersionMessage::new(
+            self.genesis_hash,
+            self.blockchain.get_best_height()?,
+            self.blockchain
+                .get_chain_work(&self.blockchain.get_tip_hash())?,
+            Some(self.local_addr),
+            self.nonce,
+        ))
+    }
+
+    /// Refuse peers of an older protocol, of another chain, and ourselves
+    fn check_version(&self, version: &VersionMessage) -> Result<()> {
+        if version.get_version() < MIN_PROTOCOL_VERSION {
+            return Err(NetworkError::UnsupportedVersion {
+                version: version.get_version(),
+            }
+            .into());
+        }
+        if version.get_genesis_hash() != self.genesis_hash {
+            return Err(NetworkError::GenesisMismatch {
+                expected: self.genesis_hash,
+                found: version.get_genesis_hash(),
+            }
+            .into());
+        }
+        if version.get_nonce() == self.nonce {
+            return Err(NetworkError::SelfConnection.into());
+        }
+        Ok(())
+    }
+
+    fn get_known_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs: Vec<SocketAddr> = self.known_addrs.read().unwrap().iter().copied().collect();
+        addrs.sort();
+        addrs
+    }
+
+    fn add_known_addrs(&self, addrs: &[SocketAddr]) {
+        let mut known_addrs = self.known_addrs.write().unwrap();
+        for addr in addrs {
+            if known_addrs.len() >= MAX_KNOWN_ADDRS {
+                break;
+            }
+            if *addr != self.local_addr {
+                known_addrs.insert(*addr);
+            }
+        }
+    }
+
+    /// Announce a block or transaction to every peer which does not know it yet
+    fn relay(&self, item: Inventory) {
+        for peer in self.peers.read().unwrap().values() {
+            if peer.add_known(item) {
+                peer.send(Message::Inv(vec![item]));
+            }
+        }
+    }
+
+    /// Send a message to a peer, if it is still connected
+    fn send_to(&self, id: PeerId, message: Message) {
+        if let Some(peer) = self.peers.read().unwrap().get(&id) {
+            peer.send(message);
+        }
+    }
+
+    /// Disconnect a peer, if it is still connected
+    fn disconnect_peer(&self, id: PeerId) {
+        if let Some(peer) = self.peers.read().unwrap().get(&id) {
+            peer.disconnect();
+        }
+    }
+
+    /// Ask the peers for the headers and blocks the sync is missing
+    fn update_sync(&self) -> Result<()> {
+        let requests = {
+            let mut sync = self.sync.lock().unwrap();
+            let mut requests: Vec<(PeerId, Message)> = sync
+                .request_headers(&self.blockchain, Instant::now())?
+                .into_iter()
+                .collect();
+            requests.extend(sync.request_blocks(Instant::now()));
+            requests
+        };
+        for (id, message) in requests {
+            self.send_to(id, message);
+        }
+        Ok(())
+    }
+
+    /// Disconnect the peers which did not answer the sync in time,
+    /// and ask other peers instead
+    fn check_stalls(&self) -> Result<()> {
+        let stalled = self.sync.lock().unwrap().check_stalls(Instant::now());
+        if stalled.is_empty() {
+            return Ok(());
+        }
+        for id in stalled {
+            self.report(NodeEvent::DownloadStalled { peer: id });
+            self.disconnect_peer(id);
+        }
+        self.update_sync()
+    }
+
+    /// Connect the downloaded blocks which follow the tip.
+    /// An invalid block drops the headers after it, and the peer which sent them.
+    fn connect_synced_blocks(&self) -> Result<()> {
+        let _connect_guard = self.connect_lock.lock().unwrap();
+        loop {
+            let next_block = self.sync.lock().unwrap().take_next_block();
+            let (block, header_source) = match next_block {
+                Some(next_block) => next_block,
+                None => break,
+            };
+            match self.process_block(&block) {
+                Ok(_) => {}
+                Err(Error::InvalidBlock(err)) => {
+                    self.report(NodeEvent::InvalidBlock {
+                        hash: block.get_hash(),
+                        err,
+                    });
+                    self.sync.lock().unwrap().on_invalid_block(header_source);
+                    self.disconnect_peer(header_source);
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let progress = self.sync.lock().unwrap().get_progress(&self.blockchain)?;
+        if !progress.is_syncing() {
+            return Ok(());
+        }
+        self.report(NodeEvent::SyncProgress {
+            height: self.blockchain.get_best_height()?,
+            header_height: progress.get_header_height(),
+            blocks_in_flight: progress.get_blocks_in_flight(),
+        });
+        Ok(())
+    }
+
+    fn process_block(&self, block: &Block) -> Result<BlockStatus> {
+        let status = self.blockchain.add_block(block)?;
+        self.on_block_accepted(block, &status);
+        Ok(status)
+    }
+
+    /// Update the mempool for the new best chain, announce the new tip,
+    /// and stop the miners which work on the old one
+    fn on_block_accepted(&self, block: &Block, status: &BlockStatus) {
+        if matches!(
+            status,
+            BlockStatus::Connected | BlockStatus::Reorganized { .. }
+        ) {
+            for handle in self.mining_jobs.lock().unwrap().iter() {
+                handle.cancel();
+            }
+        }
+        match status {
+            BlockStatus::Connected => {
+                self.mempool.remove_confirmed(block);
+                self.relay(Inventory::Block(block.get_hash()));
+            }
+            BlockStatus::Reorganized {
+                disconnected,
+                connected,
+            } => {
+                // the pool is only a cache, a storage error here does not undo the reorg
+                let _ = self
+                    .mempool
+                    .reorganize(&self.blockchain, disconnected, connected);
+                if let Some(tip) = connected.last() {
+                    self.relay(Inventory::Block(tip.get_hash()));
+                }
+            }
+            BlockStatus::SideChain | BlockStatus::AlreadyKnown => {}
+        }
+    }
+
+    /// Handle one message of a peer after the handshake.
+    /// An error closes the connection.
+    fn handle_message(&self, peer: &Peer, message: Message) -> Result<()> {
+        match message {
+            Message::Version(_) | Message::Verack => Err(NetworkError::UnexpectedMessage(format!(
+                "{} after the handshake",
+                message.get_command()
+            ))
+            .into()),
+            Message::Ping(nonce) => {
+                peer.send(Message::Pong(nonce));
+                Ok(())
+            }
+            Message::Pong(nonce) => {
+                let mut state = peer.state.lock().unwrap();
+                if let Some((expected, sent)) = state.ping {
+                    if expected == nonce {
+                        state.ping = None;
+                        state.ping_time = Some(sent.elapsed());
+                    }
+                }
+                Ok(())
+            }
+            Message::Addr(addrs) => {
+                check_size("addr", addrs.len(), MAX_ADDR_SIZE)?;
+                self.add_known_addrs(&addrs);
+                Ok(())
+            }
+            Message::Inv(items) => {
+                check_size("inv", items.len(), MAX_INV_SIZE)?;
+                self.handle_inv(peer, items)
+            }
+            Message::GetBlocks { locator, stop } => {
+                check_size("getblocks", locator.len(), MAX_INV_SIZE)?;
+                let hashes =
+                    self.blockchain
+                        .get_block_hashes_after(&locator, &stop, MAX_INV_SIZE)?;
+                if !hashes.is_empty() {
+                    let items: Vec<Inventory> = hashes.into_iter().map(Inventory::Block).collect();
+                    for item in &items {
+                        peer.add_known(*item);
+                    }
+                    peer.send(Message::Inv(items));
+                }
+                Ok(())
+            }
+            Message::GetHeaders { locator, stop } => {
+                check_size("getheaders", locator.len(), MAX_INV_SIZE)?;
+                let headers =
+                    self.blockchain
+                        .get_headers_after(&locator, &stop, MAX_HEADERS_SIZE)?;
+                // also when empty, so the peer knows it has all our headers
+                peer.send(Message::Headers(headers));
+                Ok(())
+            }
+            Message::Headers(headers) => {
+                check_size("headers", headers.len(), MAX_HEADERS_SIZE)?;
+                let request = self.sync.lock().unwrap().on_headers(
+                    &self.blockchain,
+                    peer.id,
+                    headers,
+                    Instant::now(),
+                )?;
+                if let Some((id, message)) = request {
+                    self.send_to(id, message);
+                }
+                self.update_sync()
+            }
+            Message::GetData(items) => {
+                check_size("getdata", items.len(), MAX_INV_SIZE)?;
+                for item in items {
+                    match item {
+                        Inventory::Block(hash) => {
+                            if let Some(block) = self.blockchain.get_block(&hash)? {
+                                peer.send(Message::Block(block));
+                            }
+                        }
+                        Inventory::Tx(txid) => {
+                            if let Some(tx) = self.mempool.get_transaction(&txid) {
+                                peer.send(Message::Tx(tx));
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Message::Block(block) => {
+                peer.add_known(Inventory::Block(block.get_hash()));
+                let receipt = self.sync.lock().unwrap().on_block(peer.id, &block);
+                match receipt {
+                    BlockReceipt::Accepted => {
+                        self.connect_synced_blocks()?;
+                        return self.update_sync();
+                    }
+                    BlockReceipt::Rejected => {
+                        return Err(NetworkError::UnexpectedMessage(format!(
+                            "block {} does not match its header",
+                            block.get_hash()
+                        ))
+                        .into())
+                    }
+                    BlockReceipt::NotRequested => {}
+                }
+                match self.process_block(&block) {
+                    // only a valid block tells what chain the peer has
+                    Ok(_) => {
+                        let chain_work = self.blockchain.get_chain_work(&block.get_hash())?;
+                        self.sync.lock().unwrap().update_peer(
+                            peer.id,
+                            block.get_height(),
+                            chain_work,
+                        );
+                        Ok(())
+                    }
+                    // the parent is missing, download the headers and blocks after the fork
+                    Err(Error::InvalidBlock(BlockValidationError::PrevHashMismatch { .. })) => {
+                        self.sync.lock().unwrap().on_orphan_block(
+                            &self.blockchain,
+                            peer.id,
+                            &block,
+                        )?;
+                        self.update_sync()
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            Message::Tx(tx) => {
+                // decoding already refuses a forged id, this keeps the pool from trusting it
+                if !tx.has_valid_id() {
+                    return Err(NetworkError::UnexpectedMessage(format!(
+                        "transaction id {} is not the hash of the transaction",
+                        tx.get_id()
+                    ))
+                    .into());
+                }
+                peer.add_known(Inventory::Tx(tx.get_id()));
+                match self.mempool.add_transaction(&self.blockchain, tx) {
+                    Ok(txid) => {
+                        self.relay(Inventory::Tx(txid));
+                        Ok(())
+                    }
+                    // e.g. already in the pool, or it spends a transaction we do not have yet
+                    Err(Error::RejectedTransaction(_)) => Ok(()),
+                    Err(err) => Err(err),
+                }
+            }
+        }
+    }
+
+    /// Ask for the announced items we do not have
+    fn handle_inv(&self, peer: &Peer, items: Vec<Inventory>) -> Result<()> {
+        let mut wanted = vec![];
+        let mut last_block = None;
+        let mut block_count = 0;
+        for item in items {
+            peer.add_known(item);
+            match item {
+                Inventory::Block(hash) => {
+                    block_count += 1;
+                    last_block = Some(hash);
+                    // blocks of the sync are requested by the sync
+                    if self.blockchain.get_header(&hash)?.is_none()
+                        && !self.sync.lock().unwrap().contains(&hash)
+                    {
+                        wanted.push(item);
+                    }
+                }
+                Inventory::Tx(txid) => {
+                    if !self.mempool.contains(&txid) {
+                        wanted.push(item);
+                    }
+                }
+            }
+        }
+        if !wanted.is_empty() {
+            peer.send(Message::GetData(wanted));
+        }
+        // a full answer to `GetBlocks`, the peer may have more blocks after the last one
+        if block_count == MAX_INV_SIZE {
+            if let Some(last_block) = last_block {
+                peer.send(Message::GetBlocks {
+                    locator: vec![last_block],
+                    stop: BlockHash::default(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Ping the peers whose last ping is older than `ping_interval`,
+    /// and disconnect the ones which did not answer in `ping_timeout`
+    fn ping_peers(&self) {
+        let now = Instant::now();
+        for peer in self.peers.read().unwrap().values() {
+            let mut state = peer.state.lock().unwrap();
+            match state.ping {
+                Some((_, sent)) => {
+                    if now.duration_since(sent) > self.config.ping_timeout {
+                        self.report(NodeEvent::PingTimeout { addr: peer.addr });
+                        peer.disconnect();
+                    }
+                }
+                None => {
+                    if now.duration_since(state.last_ping) < self.config.ping_interval {
+                        continue;
+                    }
+                    if let Ok(nonce) = random_u64() {
+                        state.ping = Some((nonce, now));
+                        state.last_ping = now;
+                        peer.send(Message::Ping(nonce));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A list in a message is longer than allowed
+fn check_size(command: &str, size: usize, max: usize) -> Result<()> {
+    if size > max {
+        return Err(NetworkError::UnexpectedMessage(format!(
+            "{} with {} items, at most {} are allowed",
+            command, size, max
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Accept inbound connections, ping the peers and look for stalled downloads,
+/// until the node is shut down.
+/// While `max_inbound_peers` are connected, new connections wait in the listen backlog.
+fn run_main_loop(shared: Arc<NodeShared>, listener: TcpListener) {
+    while !shared.shutdown.load(Ordering::SeqCst) {
+        // at the limit, nothing is accepted and the loop only pings and looks for stalls
+        let accepted =
+            if shared.inbound_peers.load(Ordering::SeqCst) >= shared.config.max_inbound_peers {
+                Err(io::Error::from(ErrorKind::WouldBlock))
+            } else {
+                listener.accept()
+            };
+        match accepted {
+            Ok((stream, addr)) => {
+                shared.inbound_peers.fetch_add(1, Ordering::SeqCst);
+                let shared = shared.clone();
+                // the handshake may take a while, the main loop does not wait for it
+                thread::spawn(move || {
+                    // once added, the reader thread of the peer releases its place
+                    if let Err(err) = add_peer(&shared, stream, true) {
+                        shared.inbound_peers.fetch_sub(1, Ordering::SeqCst);
+                        shared.report(NodeEvent::InboundFailed { addr, err });
+                    }
+                });
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                shared.ping_peers();
+                if let Err(err) = shared.check_stalls() {
+                    shared.report(NodeEvent::SyncFailed(err));
+                }
+                thread::sleep(MAIN_LOOP_TICK);
+            }
+            Err(err) => {
+                shared.report(NodeEvent::AcceptFailed(err));
+                thread::sleep(MAIN_LOOP_TICK);
+            }
+        }
+    }
+}
+
+/// Exchange `Version` and `Verack` with a new connection, return the peer's version
+fn handshake(
+    shared: &NodeShared,
+    reader: &mut BufReader<TcpStream>,
+    writer: &mut TcpStream,
+) -> Result<VersionMessage> {
+    write_message(writer, &Message::Version(shared.get_version_message()?))?;
+    let version = match read_message(reader)? {
+        Message::Version(version) => version,
+        message => {
+            return Err(NetworkError::UnexpectedMessage(format!(
+                "{} before version",
+                message.get_command()
+            ))
+            .into())
+        }
+    };
+    shared.check_version(&version)?;
+    write_message(writer, &Message::Verack)?;
+    match read_message(reader)? {
+        Message::Verack => Ok(version),
+        message => Err(NetworkError::UnexpectedMessage(format!(
+            "{} before verack",
+            message.get_command()
+        ))
+        .into()),
+    }
+}
+
+/// Do the handshake, then register the peer and start its reader and writer threads
+fn add_peer(shared: &Arc<NodeShared>, stream: TcpStream, inbound: bool) -> Result<PeerId> {
+    // accepted streams may inherit the non-blocking mode of the listener
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(shared.config.handshake_timeout))?;
+    stream.set_write_timeout(Some(shared.config.handshake_timeout))?;
+    let addr = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream.try_clone()?;
+    let version = handshake(shared, &mut reader, &mut writer).map_err(|err| match err {
+        // the peer closed the connection, e.g. it refused our version, or it timed out
+        Error::Io(_) => Error::from(NetworkError::HandshakeFailed),
+        err => err,
+    });
+    let version = match version {
+        Ok(version) => version,
+        Err(err) => {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(err);
+        }
+    };
+    // dead peers are found by the ping instead
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+
+    let id = shared.next_peer_id.fetch_add(1, Ordering::SeqCst);
+    let (sender, receiver) = mpsc::channel();
+    let now = Instant::now();
+    let peer = Arc::new(Peer {
+        id,
+        addr,
+        inbound,
+        version: version.clone(),
+        stream,
+        sender,
+        state: Mutex::new(PeerState {
+            known_inventory: HashSet::new(),
+            ping: None,
+            last_ping: now,
+            ping_time: None,
+        }),
+    });
+    thread::spawn(move || run_writer(writer, receiver));
+    shared.peers.write().unwrap().insert(id, peer.clone());
+    if shared.shutdown.load(Ordering::SeqCst) {
+        peer.disconnect();
+    }
+    shared.report(NodeEvent::PeerConnected { addr, inbound });
+
+    // share the nodes we know, and download the headers and blocks if the peer is ahead
+    let addrs: Vec<SocketAddr> = shared
+        .get_known_addrs()
+        .into_iter()
+        .filter(|known| Some(*known) != version.get_listen_addr())
+        .collect();
+    if !addrs.is_empty() {
+        peer.send(Message::Addr(addrs));
+    }
+    if let Some(listen_addr) = version.get_listen_addr() {
+        shared.add_known_addrs(&[listen_addr]);
+    }
+    shared
+        .sync
+        .lock()
+        .unwrap()
+        .add_peer(id, version.get_best_height(), version.get_chain_work());
+    if let Err(err) = shared.update_sync() {
+        shared.report(NodeEvent::SyncFailed(err));
+    }
+
+    let shared = shared.clone();
+    thread::spawn(move || run_reader(shared, peer, reader));
+    Ok(id)
+}
+
+/// Handle the messages of a peer until the connection is closed or the peer misbehaves
+fn run_reader(shared: Arc<NodeShared>, peer: Arc<Peer>, mut reader: BufReader<TcpStream>) {
+    let err = loop {
+        let message = match read_message(&mut reader) {
+            Ok(message) => message,
+            Err(err) => break err,
+        };
+        if let Err(err) = shared.handle_message(&peer, message) {
+            break err;
+        }
+    };
+    peer.disconnect();
+    shared.peers.write().unwrap().remove(&peer.id);
+    if peer.inbound {
+        shared.inbound_peers.fetch_sub(1, Ordering::SeqCst);
+    }
+    if shared.shutdown.load(Ordering::SeqCst) {
+        return;
+    }
+    shared.report(NodeEvent::PeerDisconnected {
+        addr: peer.addr,
+        err,
+    });
+    // the peer's part of the download goes to other peers
+    shared.sync.lock().unwrap().remove_peer(peer.id);
+    if let Err(err) = shared.update_sync() {
+        shared.report(NodeEvent::SyncFailed(err));
+    }
+}
+
+/// Send the queued messages, until the peer is removed or the connection fails
+fn run_writer(mut writer: TcpStream, receiver: Receiver<Message>) {
+    for message in receiver {
+        if write_message(&mut writer, &message).is_err() {
+            let _ = writer.shutdown(Shutdown::Both);
+            break;
+        }
+    }
+}
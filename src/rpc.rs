@@ -0,0 +1,703 @@
+/* # JSON-RPC Server
+ *
+ * Other programs query the node over HTTP with JSON-RPC 2.0, without linking this crate:
+ *   curl -H 'Content-Type: application/json' \
+ *     -d '{"jsonrpc":"2.0","id":1,"method":"getblockcount","params":[]}' http://127.0.0.1:8332/
+ *
+ * Each request is a POST of `application/json` with one JSON-RPC call, or a batch of calls in
+ * an array. Other content types are refused, so a web page can not send a call as a form.
+ * The params are positional, and the connection is closed after the answer.
+ * A call without an `id` is a notification, it is handled but not answered.
+ * A request must be sent and answered within `REQUEST_TIMEOUT` in total.
+ * At most `MAX_RPC_CONNECTIONS` requests are handled at a time, more connections wait
+ * until one is answered.
+ *
+ * Methods:
+ *   - getblockcount: height of the tip
+ *   - getblockhash <height>: hash of the block at height on the best chain
+ *   - getblock <hash>: header fields and txids of the block
+ *   - getrawtransaction <txid> [verbose]: hex of the transaction from the mempool or the chain,
+ *     or its fields if verbose is true. Transactions in the chain are found by a scan of all blocks.
+ *   - sendrawtransaction <hex>: add the transaction to the mempool and relay it, return the txid
+ *   - getbalance <address>
+ *   - listunspent <address>: the unspent outputs with their confirmations
+ *   - getmempoolinfo: count, size and fees of the pending transactions
+ *
+ * The server has no authentication, anyone who can connect can send transactions.
+ * So it listens on localhost by default, see `DEFAULT_RPC_ADDR`.
+ *
+ * The server does not print, the failed requests and accepts are sent as `RpcEvent`s
+ * to the caller, if it asked for them.
+ */
+use crate::address::{Address, DEFAULT_NETWORK, PUB_KEY_HASH_LENGTH};
+use crate::blockchain::BlockChain;
+use crate::error::{Error, Result};
+use crate::hash::{BlockHash, Txid};
+use crate::mempool::Mempool;
+use crate::node::Node;
+use crate::transaction::Transaction;
+use crate::utxo_set::UtxoSet;
+use crate::wallet::decode_address;
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The server only accepts local connections unless told otherwise
+pub const DEFAULT_RPC_ADDR: &str = "127.0.0.1:8332";
+/// Bigger request bodies are refused before they are read
+pub const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+/// Most connections handled at a time, each one has its own thread
+pub const MAX_RPC_CONNECTIONS: usize = 16;
+/// Most bytes of the request line and headers
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+/// A client must send its request and read the answer in this time, in total
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the accept loop looks for new connections and the shutdown
+const ACCEPT_LOOP_TICK: Duration = Duration::from_millis(20);
+
+/// The JSON is not valid
+pub const PARSE_ERROR: i64 = -32700;
+/// The JSON is not a JSON-RPC call
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// A param is missing, or has the wrong type or value
+pub const INVALID_PARAMS: i64 = -32602;
+/// The node failed, e.g. the db can not be read
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The block or transaction does not exist
+pub const NOT_FOUND: i64 = -5;
+/// The mempool did not accept the transaction
+pub const TRANSACTION_REJECTED: i64 = -26;
+
+/// The `error` of a JSON-RPC answer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: String) -> RpcError {
+        RpcError { code, message }
+    }
+
+    pub fn get_code(&self) -> i64 {
+        self.code
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+/// Errors of the user's input are reported as such, the others are internal
+impl From<Error> for RpcError {
+    fn from(err: Error) -> Self {
+        let code = match err {
+            Error::InvalidAddress(_) | Error::InvalidHash(_) => INVALID_PARAMS,
+            Error::RejectedTransaction(_) => TRANSACTION_REJECTED,
+            _ => INTERNAL_ERROR,
+        };
+        RpcError::new(code, err.to_string())
+    }
+}
+
+type RpcResult = std::result::Result<Value, RpcError>;
+
+/// What the server reports to the caller instead of printing it
+#[derive(Debug)]
+pub enum RpcEvent {
+    /// The request of a client could not be read or answered, e.g. it timed out
+    RequestFailed { addr: SocketAddr, err: Error },
+    /// The listener failed to accept a connection
+    AcceptFailed(io::Error),
+}
+
+impl fmt::Display for RpcEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcEvent::RequestFailed { addr, err } => {
+                write!(f, "rpc request from {} failed: {}", addr, err)
+            }
+            RpcEvent::AcceptFailed(err) => {
+                write!(f, "failed to accept an rpc connection: {}", err)
+            }
+        }
+    }
+}
+
+/// An HTTP answer other than 200, without JSON
+struct HttpStatus {
+    code: u16,
+    reason: &'static str,
+}
+
+/// The answer to a request of notifications only
+const NO_CONTENT: HttpStatus = HttpStatus {
+    code: 204,
+    reason: "No Content",
+};
+const BAD_REQUEST: HttpStatus = HttpStatus {
+    code: 400,
+    reason: "Bad Request",
+};
+const METHOD_NOT_ALLOWED: HttpStatus = HttpStatus {
+    code: 405,
+    reason: "Method Not Allowed",
+};
+const PAYLOAD_TOO_LARGE: HttpStatus = HttpStatus {
+    code: 413,
+    reason: "Payload Too Large",
+};
+const UNSUPPORTED_MEDIA_TYPE: HttpStatus = HttpStatus {
+    code: 415,
+    reason: "Unsupported Media Type",
+};
+
+// fields:
+//   - node: set when the server runs next to a node, sent transactions are then relayed
+//   - connections: the connections being handled, at most `MAX_RPC_CONNECTIONS`
+//   - events: where the failures go, they are dropped if the caller did not ask for them
+struct RpcShared {
+    blockchain: Arc<BlockChain>,
+    mempool: Arc<Mempool>,
+    node: Option<Arc<Node>>,
+    events: Option<Sender<RpcEvent>>,
+    shutdown: AtomicBool,
+    connections: AtomicUsize,
+}
+
+/// A running server, it is shut down when dropped
+pub struct RpcServer {
+    shared: Arc<RpcShared>,
+    local_addr: SocketAddr,
+    accept_loop: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl RpcServer {
+    /// Listen on `listen_addr`, e.g. `DEFAULT_RPC_ADDR`, or "127.0.0.1:0" for any free port.
+    /// Sent transactions only go to the mempool. The failures are sent to `events`, if given.
+    pub fn start(
+        blockchain: Arc<BlockChain>,
+        mempool: Arc<Mempool>,
+        listen_addr: &str,
+        events: Option<Sender<RpcEvent>>,
+    ) -> Result<RpcServer> {
+        Self::start_with_shared(
+            RpcShared {
+                blockchain,
+                mempool,
+                node: None,
+                events,
+                shutdown: AtomicBool::new(false),
+                connections: AtomicUsize::new(0),
+            },
+            listen_addr,
+        )
+    }
+
+    /// Same as `start` with the blockchain and mempool of the node,
+    /// sent transactions are relayed to its peers
+    pub fn start_with_node(
+        node: Arc<Node>,
+        listen_addr: &str,
+        events: Option<Sender<RpcEvent>>,
+    ) -> Result<RpcServer> {
+        Self::start_with_shared(
+            RpcShared {
+                blockchain: node.get_blockchain().clone(),
+                mempool: node.get_mempool().clone(),
+                node: Some(node),
+                events,
+                shutdown: AtomicBool::new(false),
+                connections: AtomicUsize::new(0),
+            },
+            listen_addr,
+        )
+    }
+
+    fn start_with_shared(shared: RpcShared, listen_addr: &str) -> Result<RpcServer> {
+        let listener = TcpListener::bind(listen_addr)?;
+        // the accept loop polls the listener, so it sees the shutdown
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let shared = Arc::new(shared);
+        let accept_loop = {
+            let shared = shared.clone();
+            thread::spawn(move || run_accept_loop(shared, listener))
+        };
+        Ok(RpcServer {
+            shared,
+            local_addr,
+            accept_loop: Mutex::new(Some(accept_loop)),
+        })
+    }
+
+    pub fn get_local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Wait until the server is shut down, e.g. by another thread
+    pub fn join(&self) {
+        let accept_loop = self.accept_loop.lock().unwrap().take();
+        if let Some(accept_loop) = accept_loop {
+            let _ = accept_loop.join();
+        }
+    }
+
+    /// Stop accepting connections, the requests being handled are still answered
+    pub fn shutdown(&self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        self.join();
+    }
+}
+
+impl Drop for RpcServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl RpcShared {
+    /// Hand a failure to the caller, if it asked for them
+    fn report(&self, event: RpcEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    /// Answer the body of a request, a single call or a batch.
+    /// None if it only has notifications, they are not answered.
+    fn handle_body(&self, body: &[u8]) -> Option<Value> {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(err) => {
+                let err = RpcError::new(PARSE_ERROR, format!("invalid JSON: {}", err));
+                return Some(answer(Value::Null, Err(err)));
+            }
+        };
+        match request {
+            Value::Array(calls) if calls.is_empty() => Some(answer(
+                Value::Null,
+                Err(RpcError::new(INVALID_REQUEST, String::from("empty batch"))),
+            )),
+            Value::Array(calls) => {
+                let answers: Vec<Value> = calls
+                    .iter()
+                    .filter_map(|call| self.handle_call(call))
+                    .collect();
+                (!answers.is_empty()).then_some(Value::Array(answers))
+            }
+            call => self.handle_call(&call),
+        }
+    }
+
+    /// Answer a call, None if it is a notification, i.e. a call without an `id`.
+    /// A call without a method is not a notification, its error is answered.
+    fn handle_call(&self, call: &Value) -> Option<Value> {
+        let id = call.get("id").cloned();
+        let method = match call.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            None => {
+                let err = RpcError::new(INVALID_REQUEST, String::from("no method"));
+                return Some(answer(id.unwrap_or(Value::Null), Err(err)));
+            }
+        };
+        let result = match call.get("params") {
+            None | Some(Value::Null) => self.call(method, &[]),
+            Some(Value::Array(params)) => self.call(method, params),
+            Some(_) => Err(RpcError::new(
+                INVALID_PARAMS,
+                String::from("params must be an array"),
+            )),
+        };
+        id.map(|id| answer(id, result))
+    }
+
+    fn call(&self, method: &str, params: &[Value]) -> RpcResult {
+        match method {
+            "getblockcount" => Ok(json!(self.blockchain.get_best_height()?)),
+            "getblockhash" => self.get_block_hash(param(params, 0, "height")?),
+            "getblock" => {
+                let hash: String = param(params, 0, "hash")?;
+                self.get_block(&hash.parse()?)
+            }
+            "getrawtransaction" => {
+                let txid: String = param(params, 0, "txid")?;
+                let verbose: Option<bool> = optional_param(params, 1, "verbose")?;
+                self.get_raw_transaction(&txid.parse()?, verbose.unwrap_or(false))
+            }
+            "sendrawtransaction" => self.send_raw_transaction(&param::<String>(params, 0, "hex")?),
+            "getbalance" => {
+                let address: String = param(params, 0, "address")?;
+                let utxo_set = UtxoSet::new(&self.blockchain);
+                Ok(json!(utxo_set.get_balance(&decode_address(&address)?)?))
+            }
+            "listunspent" => self.list_unspent(&param::<String>(params, 0, "address")?),
+            "getmempoolinfo" => Ok(json!({
+                "size": self.mempool.len(),
+                "bytes": self.mempool.get_total_size(),
+                "total_fee": self.mempool.get_total_fee(),
+            })),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method: {}", method),
+            )),
+        }
+    }
+
+    /// Walk the best chain back from the tip to the height
+    fn get_block_hash(&self, height: usize) -> RpcResult {
+        let best_height = self.blockchain.get_best_height()?;
+        if height > best_height {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("height {} is above the tip at {}", height, best_height),
+            ));
+        }
+        for header in self.blockchain.header_iterator() {
+            let header = header?;
+            if header.get_height() == height {
+                return Ok(json!(header.hash()));
+            }
+        }
+        Err(Error::Corruption(format!("no block at height {} on the best chain", height)).into())
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> RpcResult {
+        let block = self
+            .blockchain
+            .get_block(hash)?
+            .ok_or_else(|| RpcError::new(NOT_FOUND, format!("block {} not found", hash)))?;
+        let header = block.get_header();
+        let txids: Vec<Txid> = block
+            .get_transactions()
+            .iter()
+            .map(|tx| tx.get_id())
+            .collect();
+        Ok(json!({
+            "hash": block.get_hash(),
+            "height": block.get_height(),
+            "previousblockhash": block.get_pre_block_hash(),
+            "merkleroot": header.get_merkle_root().to_string(),
+            "time": block.get_timestamp(),
+            "bits": block.get_bits(),
+            "nonce": header.get_nonce(),
+            "tx": txids,
+        }))
+    }
+
+    fn get_raw_transaction(&self, txid: &Txid, verbose: bool) -> RpcResult {
+        let tx = match self.mempool.get_transaction(txid) {
+            Some(tx) => tx,
+            None => self.blockchain.find_transaction(txid)?.ok_or_else(|| {
+                RpcError::new(NOT_FOUND, format!("transaction {} not found", txid))
+            })?,
+        };
+        if !verbose {
+            return Ok(json!(HEXLOWER.encode(&tx.serialize())));
+        }
+        let vin: Vec<Value> = if tx.is_coinbase() {
+            vec![json!({ "coinbase": HEXLOWER.encode(tx.get_vin()[0].get_signature()) })]
+        } else {
+            tx.get_vin()
+                .iter()
+                .map(|vin| json!({ "txid": vin.get_txid(), "vout": vin.get_vout() }))
+                .collect()
+        };
+        let vout: Vec<Value> = tx
+            .get_vout()
+            .iter()
+            .map(|out| {
+                json!({
+                    "value": out.get_value(),
+                    "address": address_of(out.get_pub_key_hash()),
+                })
+            })
+            .collect();
+        Ok(json!({
+            "txid": tx.get_id(),
+            "size": tx.get_size(),
+            "vin": vin,
+            "vout": vout,
+        }))
+    }
+
+    fn send_raw_transaction(&self, hex: &str) -> RpcResult {
+        let data = HEXLOWER_PERMISSIVE
+            .decode(hex.as_bytes())
+            .map_err(|_| RpcError::new(INVALID_PARAMS, String::from("transaction is not hex")))?;
+        let tx = Transaction::deserialize(&data).map_err(|err| {
+            RpcError::new(
+                INVALID_PARAMS,
+                format!("can not decode transaction: {}", err),
+            )
+        })?;
+        let txid = match &self.node {
+            Some(node) => node.submit_transaction(tx)?,
+            None => self.mempool.add_transaction(&self.blockchain, tx)?,
+        };
+        Ok(json!(txid))
+    }
+
+    fn list_unspent(&self, address: &str) -> RpcResult {
+        let pub_key_hash = decode_address(address)?;
+        let best_height = self.blockchain.get_best_height()?;
+        let utxo_set = UtxoSet::new(&self.blockchain);
+        let outputs: Vec<Value> = utxo_set
+            .list_unspent(&pub_key_hash)?
+            .iter()
+            .map(|out| {
+                json!({
+                    "txid": out.get_txid(),
+                    "vout": out.get_vout(),
+                    "value": out.get_value(),
+                    "height": out.get_height(),
+                    "confirmations": out.get_confirmations(best_height),
+                })
+            })
+            .collect();
+        Ok(json!(outputs))
+    }
+}
+
+/// The JSON-RPC answer to the call with `id`
+fn answer(id: Value, result: RpcResult) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(err) => json!({ "jsonrpc": "2.0", "id": id, "error": err }),
+    }
+}
+
+/// Address of an output, None if the hash is not of an address
+fn address_of(pub_key_hash: &[u8]) -> Option<String> {
+    let pub_key_hash: [u8; PUB_KEY_HASH_LENGTH] = pub_key_hash.try_into().ok()?;
+    Some(Address::new(DEFAULT_NETWORK, pub_key_hash).to_string())
+}
+
+/// The param at `index`, it must be there
+fn param<T: DeserializeOwned>(
+    params: &[Value],
+    index: usize,
+    name: &str,
+) -> std::result::Result<T, RpcError> {
+    optional_param(params, index, name)?
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing param {}", name)))
+}
+
+/// The param at `index`, None if it is left out or null
+fn optional_param<T: DeserializeOwned>(
+    params: &[Value],
+    index: usize,
+    name: &str,
+) -> std::result::Result<Option<T>, RpcError> {
+    match params.get(index) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => serde_json::from_value(value.clone())
+            .map(Some)
+            .map_err(|err| RpcError::new(INVALID_PARAMS, format!("invalid {}: {}", name, err))),
+    }
+}
+
+/// Accept connections until the server is shut down.
+/// While `MAX_RPC_CONNECTIONS` are handled, new connections wait in the listen backlog.
+fn run_accept_loop(shared: Arc<RpcShared>, listener: TcpListener) {
+    while !shared.shutdown.load(Ordering::SeqCst) {
+        if shared.connections.load(Ordering::SeqCst) >= MAX_RPC_CONNECTIONS {
+            thread::sleep(ACCEPT_LOOP_TICK);
+            continue;
+        }
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                shared.connections.fetch_add(1, Ordering::SeqCst);
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    if let Err(err) = handle_connection(&shared, stream) {
+                        shared.report(RpcEvent::RequestFailed { addr, err });
+                    }
+                    shared.connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_LOOP_TICK),
+            Err(err) => {
+                shared.report(RpcEvent::AcceptFailed(err));
+                thread::sleep(ACCEPT_LOOP_TICK);
+            }
+        }
+    }
+}
+
+/// A stream whose reads and writes all end by the same deadline,
+/// so a client which sends one byte at a time can not hold the connection
+pub struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl DeadlineStream {
+    pub fn new(stream: TcpStream, timeout: Duration) -> DeadlineStream {
+        DeadlineStream {
+            stream,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<DeadlineStream> {
+        Ok(DeadlineStream {
+            stream: self.stream.try_clone()?,
+            deadline: self.deadline,
+        })
+    }
+
+    /// The time left, an error once the deadline passed
+    fn get_time_left(&self) -> io::Result<Duration> {
+        let time_left = self.deadline.saturating_duration_since(Instant::now());
+        if time_left.is_zero() {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "the request was not done in time",
+            ));
+        }
+        Ok(time_left)
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.get_time_left()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.get_time_left()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Read one HTTP request and answer it
+fn handle_connection(shared: &RpcShared, stream: TcpStream) -> Result<()> {
+    // accepted streams may inherit the non-blocking mode of the listener
+    stream.set_nonblocking(false)?;
+    let stream = DeadlineStream::new(stream, REQUEST_TIMEOUT);
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    match read_http_request(&mut reader)? {
+        Ok(body) => match shared.handle_body(&body) {
+            Some(answer) => {
+                let answer = serde_json::to_vec(&answer).map_err(io::Error::from)?;
+                write_http_response(&mut writer, 200, "OK", "application/json", &answer)?;
+            }
+            None => write_http_response(&mut writer, NO_CONTENT.code, NO_CONTENT.reason, "", &[])?,
+        },
+        Err(status) => {
+            write_http_response(
+                &mut writer,
+                status.code,
+                status.reason,
+                "text/plain",
+                status.reason.as_bytes(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the request line and headers, then the body of a POST.
+/// A request which can not be answered with JSON gets an HTTP status.
+fn read_http_request<R: BufRead>(
+    reader: &mut R,
+) -> io::Result<std::result::Result<Vec<u8>, HttpStatus>> {
+    let mut head = reader.by_ref().take(MAX_HEADER_SIZE as u64);
+    let mut request_line = String::new();
+    head.read_line(&mut request_line)?;
+    let mut content_length = None;
+    let mut content_type = None;
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line)? == 0 {
+            // the headers do not end, or are too long
+            return Ok(Err(BAD_REQUEST));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            } else if name.trim().eq_ignore_ascii_case("content-type") {
+                content_type = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("POST"), Some(_), Some(version)) if version.starts_with("HTTP/1.") => {}
+        (Some(_), Some(_), Some(version)) if version.starts_with("HTTP/1.") => {
+            return Ok(Err(METHOD_NOT_ALLOWED))
+        }
+        _ => return Ok(Err(BAD_REQUEST)),
+    }
+    // parameters like "; charset=utf-8" are allowed
+    let is_json = content_type.as_deref().is_some_and(|content_type| {
+        let media_type = content_type.split(';').next().unwrap_or_default();
+        media_type.trim().eq_ignore_ascii_case("application/json")
+    });
+    if !is_json {
+        return Ok(Err(UNSUPPORTED_MEDIA_TYPE));
+    }
+    let content_length = match content_length {
+        Some(content_length) => content_length,
+        None => return Ok(Err(BAD_REQUEST)),
+    };
+    if content_length > MAX_REQUEST_SIZE {
+        return Ok(Err(PAYLOAD_TOO_LARGE));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Ok(body))
+}
+
+/// An empty body, e.g. of `NO_CONTENT`, is sent without the content headers
+fn write_http_response<W: Write>(
+    writer: &mut W,
+    code: u16,
+    reason: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(writer, "HTTP/1.1 {} {}\r\n", code, reason)?;
+    if !body.is_empty() {
+        write!(
+            writer,
+            "Content-Type: {}\r\nContent-Length: {}\r\n",
+            content_type,
+            body.len()
+        )?;
+    }
+    write!(writer, "Connection: close\r\n\r\n")?;
+    writer.write_all(body)?;
+    writer.flush()
+}
@@ -0,0 +1,529 @@
+/* # Initial Block Download
+ *
+ * A node which is behind its peers catches up in two steps:
+ *   1. headers first: it asks the peer with the most chain work for the headers after its best
+ *      chain with `GetHeaders`, `MAX_HEADERS_SIZE` at a time, and checks each one with
+ *      `BlockChain::check_header`: it links to the header before, and has a valid timestamp,
+ *      difficulty and proof of work. So a peer can not make the node download the bodies of
+ *      a chain nobody worked for. At most `max_queued_headers` headers wait for their blocks,
+ *      the rest is asked for when blocks are connected.
+ *   2. bodies: the blocks of the checked headers are requested from every peer which has them,
+ *      at most `MAX_BLOCKS_IN_FLIGHT` per peer, and at most `DOWNLOAD_WINDOW` heights ahead
+ *      of the next block to connect. They arrive in any order, and are connected in height order.
+ *
+ * Bad peers:
+ *   - a peer which does not answer a request in time is stalling, it is dropped,
+ *     and its requests go to other peers
+ *   - a peer which sends headers that do not check, or a block which does not match its header,
+ *     is dropped
+ *   - if a block fails validation when it is connected, the peer which sent its header is
+ *     dropped, and the headers after it are forgotten
+ *
+ * `BlockSync` only keeps the state and decides what to ask whom, the node sends the messages.
+ */
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::{BlockChain, HEADER_CONTEXT_LENGTH};
+use crate::error::Result;
+use crate::hash::BlockHash;
+use crate::network::{Inventory, Message, NetworkError, MAX_HEADERS_SIZE};
+use crate::node::PeerId;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Most blocks requested from one peer at a time
+pub const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+/// Blocks are only requested up to this many heights above the next block to connect,
+/// so the node never keeps many blocks which it can not connect yet
+pub const DOWNLOAD_WINDOW: usize = 1024;
+/// Default of the most checked headers which wait for their blocks, see `NodeConfig`
+pub const MAX_QUEUED_HEADERS: usize = 4 * MAX_HEADERS_SIZE;
+
+enum DownloadState {
+    Pending,
+    InFlight { peer: PeerId, since: Instant },
+    Received(Block),
+}
+
+// fields:
+//   - chain_work: work of the chain up to and including the header
+//   - source: the peer which sent the header, it is blamed if the block is invalid
+struct HeaderEntry {
+    header: BlockHeader,
+    hash: BlockHash,
+    chain_work: u128,
+    source: PeerId,
+    state: DownloadState,
+}
+
+// fields:
+//   - best_height: the height of the peer's tip, from its version and the blocks it sent
+//   - chain_work: the work of the peer's chain, from its version, then from the checked
+//     headers and valid blocks it sent
+//   - headers_done: the peer sent all its headers, it is not asked again until it sends
+//     a new block
+struct SyncPeer {
+    best_height: usize,
+    chain_work: u128,
+    headers_done: bool,
+}
+
+/// What `BlockSync::on_block` did with a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReceipt {
+    /// The sync does not wait for the block, it is handled like a relayed block
+    NotRequested,
+    /// The block is kept until it can be connected, see `take_next_block`
+    Accepted,
+    /// The block does not match its checked header
+    Rejected,
+}
+
+/// Progress of the sync, see `Node::get_sync_progress`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncProgress {
+    header_height: usize,
+    queued_blocks: usize,
+    blocks_in_flight: usize,
+    downloading_headers: bool,
+}
+
+impl SyncProgress {
+    /// Height of the last checked header, or of the tip if all blocks are connected
+    pub fn get_header_height(&self) -> usize {
+        self.header_height
+    }
+
+    /// Blocks with a checked header which are not connected yet
+    pub fn get_queued_blocks(&self) -> usize {
+        self.queued_blocks
+    }
+
+    pub fn get_blocks_in_flight(&self) -> usize {
+        self.blocks_in_flight
+    }
+
+    /// True while headers or blocks are still downloaded
+    pub fn is_syncing(&self) -> bool {
+        self.downloading_headers || self.queued_blocks > 0
+    }
+}
+
+// fields:
+//   - max_queued_headers: no more headers are requested while the queue has this many
+//   - header_peer: the peer headers are requested from, and when they were requested
+//   - queue: checked headers whose blocks are not connected yet, in height order,
+//     the parent of the first one is a stored block
+pub struct BlockSync {
+    headers_timeout: Duration,
+    block_timeout: Duration,
+    max_queued_headers: usize,
+    peers: HashMap<PeerId, SyncPeer>,
+    header_peer: Option<(PeerId, Instant)>,
+    queue: VecDeque<HeaderEntry>,
+}
+
+impl BlockSync {
+    /// A peer is stalling if it does not answer `GetHeaders` in `headers_timeout`,
+    /// or a requested block in `block_timeout`
+    pub fn new(
+        headers_timeout: Duration,
+        block_timeout: Duration,
+        max_queued_headers: usize,
+    ) -> BlockSync {
+        BlockSync {
+            headers_timeout,
+            block_timeout,
+            max_queued_headers,
+            peers: HashMap::new(),
+            header_peer: None,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// `best_height` and `chain_work` are what the peer says about its tip in its version
+    pub fn add_peer(&mut self, peer: PeerId, best_height: usize, chain_work: u128) {
+        self.peers.insert(
+            peer,
+            SyncPeer {
+                best_height,
+                chain_work,
+                headers_done: false,
+            },
+        );
+    }
+
+    /// Forget the peer, its requests go to other peers
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+        if matches!(self.header_peer, Some((header_peer, _)) if header_peer == peer) {
+            self.header_peer = None;
+        }
+        for entry in self.queue.iter_mut() {
+            if matches!(entry.state, DownloadState::InFlight { peer: p, .. } if p == peer) {
+                entry.state = DownloadState::Pending;
+            }
+        }
+    }
+
+    /// The peer sent a valid block at `height` with `chain_work`, it may have new headers.
+    /// Only call it after the block is accepted, a peer can send anything.
+    pub fn update_peer(&mut self, peer: PeerId, height: usize, chain_work: u128) {
+        self.raise_peer_tip(peer, height, chain_work);
+        if let Some(sync_peer) = self.peers.get_mut(&peer) {
+            sync_peer.headers_done = false;
+        }
+    }
+
+    /// The peer sent a block whose parent we do not have. If its proof of work is valid,
+    /// the peer's chain has at least one block of work more than the last checked header,
+    /// so its headers are asked for. The headers are checked, the block is not trusted.
+    pub fn on_orphan_block(
+        &mut self,
+        blockchain: &BlockChain,
+        peer: PeerId,
+        block: &Block,
+    ) -> Result<()> {
+        if !block.validate_pow() {
+            return Ok(());
+        }
+        let chain_work = self.get_header_work(blockchain)? + block.get_header().get_work();
+        self.update_peer(peer, block.get_height(), chain_work);
+        Ok(())
+    }
+
+    /// The peer has a checked header or a valid block at `height` with `chain_work`
+    fn raise_peer_tip(&mut self, peer: PeerId, height: usize, chain_work: u128) {
+        if let Some(sync_peer) = self.peers.get_mut(&peer) {
+            sync_peer.best_height = sync_peer.best_height.max(height);
+            sync_peer.chain_work = sync_peer.chain_work.max(chain_work);
+        }
+    }
+
+    /// True if the block has a checked header and is not connected yet
+    pub fn contains(&self, block_hash: &BlockHash) -> bool {
+        self.queue.iter().any(|entry| entry.hash == *block_hash)
+    }
+
+    /// Height of the last checked header, or of the tip
+    fn get_header_height(&self, blockchain: &BlockChain) -> Result<usize> {
+        match self.queue.back() {
+            Some(entry) => Ok(entry.header.get_height()),
+            None => blockchain.get_best_height(),
+        }
+    }
+
+    /// Chain work up to the last checked header, or of the tip
+    fn get_header_work(&self, blockchain: &BlockChain) -> Result<u128> {
+        match self.queue.back() {
+            Some(entry) => Ok(entry.chain_work),
+            None => blockchain.get_chain_work(&blockchain.get_tip_hash()),
+        }
+    }
+
+    pub fn get_progress(&self, blockchain: &BlockChain) -> Result<SyncProgress> {
+        let blocks_in_flight = self
+            .queue
+            .iter()
+            .filter(|entry| matches!(entry.state, DownloadState::InFlight { .. }))
+            .count();
+        Ok(SyncProgress {
+            header_height: self.get_header_height(blockchain)?,
+            queued_blocks: self.queue.len(),
+            blocks_in_flight,
+            downloading_headers: self.header_peer.is_some(),
+        })
+    }
+
+    /// Ask the peer with the most chain work for headers, unless a request is waiting already,
+    /// the queue is full, or no peer has more work than the last checked header.
+    /// The best chain is the one with the most work, a long chain of easy blocks is not.
+    pub fn request_headers(
+        &mut self,
+        blockchain: &BlockChain,
+        now: Instant,
+    ) -> Result<Option<(PeerId, Message)>> {
+        if self.header_peer.is_some() || self.queue.len() >= self.max_queued_headers {
+            return Ok(None);
+        }
+        let header_work = self.get_header_work(blockchain)?;
+        let best_peer = self
+            .peers
+            .iter()
+            .filter(|(_, sync_peer)| !sync_peer.headers_done && sync_peer.chain_work > header_work)
+            .max_by_key(|(peer, sync_peer)| (sync_peer.chain_work, std::cmp::Reverse(**peer)))
+            .map(|(peer, _)| *peer);
+        let peer = match best_peer {
+            Some(peer) => peer,
+            None => return Ok(None),
+        };
+        // the peer goes on after the last checked header, or finds the fork with our chain
+        let mut locator = blockchain.get_block_locator()?;
+        if let Some(entry) = self.queue.back() {
+            locator.insert(0, entry.hash);
+        }
+        self.header_peer = Some((peer, now));
+        Ok(Some((
+            peer,
+            Message::GetHeaders {
+                locator,
+                stop: BlockHash::default(),
+            },
+        )))
+    }
+
+    /// The headers before a new header whose parent is `parent_hash`, see `check_header`.
+    /// Return `None` if the parent is neither the last checked header nor a stored block.
+    fn get_prev_headers(
+        &self,
+        blockchain: &BlockChain,
+        parent_hash: &BlockHash,
+    ) -> Result<Option<Vec<BlockHeader>>> {
+        let last = match self.queue.back() {
+            Some(last) if last.hash == *parent_hash => last,
+            _ => {
+                if blockchain.get_header(parent_hash)?.is_none() {
+                    return Ok(None);
+                }
+                let prev_headers =
+                    blockchain.get_ancestor_headers(parent_hash, HEADER_CONTEXT_LENGTH)?;
+                return Ok(Some(prev_headers));
+            }
+        };
+        let start = self.queue.len().saturating_sub(HEADER_CONTEXT_LENGTH);
+        let mut prev_headers = vec![];
+        if start == 0 {
+            let first = &self.queue[0].header;
+            prev_headers = blockchain
+                .get_ancestor_headers(&first.get_pre_block_hash(), HEADER_CONTEXT_LENGTH)?;
+        }
+        prev_headers.extend(self.queue.range(start..).map(|entry| entry.header.clone()));
+        debug_assert_eq!(
+            prev_headers.last().map(|header| header.hash()),
+            Some(last.hash)
+        );
+        Ok(Some(prev_headers))
+    }
+
+    /// Check the headers of the header peer and queue the new ones, up to `max_queued_headers`.
+    /// A full answer is followed by the next request to the same peer, unless the queue is full.
+    /// An error means the peer sent bad headers.
+    pub fn on_headers(
+        &mut self,
+        blockchain: &BlockChain,
+        peer: PeerId,
+        headers: Vec<BlockHeader>,
+        now: Instant,
+    ) -> Result<Option<(PeerId, Message)>> {
+        if !matches!(self.header_peer, Some((header_peer, _)) if header_peer == peer) {
+            // not asked for, e.g. the answer came after the peer was taken for stalling
+            return Ok(None);
+        }
+        if headers.len() > MAX_HEADERS_SIZE {
+            return Err(NetworkError::UnexpectedMessage(format!(
+                "{} headers, at most {} are allowed",
+                headers.len(),
+                MAX_HEADERS_SIZE
+            ))
+            .into());
+        }
+        let first = match headers.first() {
+            Some(first) => first,
+            None => {
+                self.finish_headers(peer);
+                return Ok(None);
+            }
+        };
+
+        let is_continuation =
+            matches!(self.queue.back(), Some(last) if last.hash == first.get_pre_block_hash());
+        let mut prev_headers = self
+            .get_prev_headers(blockchain, &first.get_pre_block_hash())?
+            .ok_or_else(|| {
+                NetworkError::UnexpectedMessage(String::from(
+                    "headers do not connect to a known block",
+                ))
+            })?;
+        let mut chain_work = match self.queue.back() {
+            Some(last) if is_continuation => last.chain_work,
+            _ => blockchain.get_chain_work(&first.get_pre_block_hash())?,
+        };
+        let mut new_entries = vec![];
+        for header in &headers {
+            blockchain.check_header(header, &prev_headers)?;
+            prev_headers.push(header.clone());
+            if prev_headers.len() > HEADER_CONTEXT_LENGTH {
+                prev_headers.remove(0);
+            }
+            chain_work += header.get_work();
+            let hash = header.hash();
+            // e.g. blocks of a side chain we have already
+            if blockchain.get_header(&hash)?.is_some() {
+                continue;
+            }
+            new_entries.push(HeaderEntry {
+                header: header.clone(),
+                hash,
+                chain_work,
+                source: peer,
+                state: DownloadState::Pending,
+            });
+        }
+        let last = &headers[headers.len() - 1];
+        self.raise_peer_tip(peer, last.get_height(), chain_work);
+
+        // the headers start from a stored block, not from the queue: the peer is on another
+        // branch than the queued headers, which are dropped
+        if !is_continuation {
+            self.queue.clear();
+        }
+        // the headers over the limit are asked for again when blocks are connected
+        let room = self.max_queued_headers.saturating_sub(self.queue.len());
+        let is_full = new_entries.len() >= room;
+        new_entries.truncate(room);
+        self.queue.extend(new_entries);
+
+        if is_full {
+            self.header_peer = None;
+            return Ok(None);
+        }
+        if headers.len() < MAX_HEADERS_SIZE {
+            self.finish_headers(peer);
+            return Ok(None);
+        }
+        self.header_peer = Some((peer, now));
+        Ok(Some((
+            peer,
+            Message::GetHeaders {
+                locator: vec![last.hash()],
+                stop: BlockHash::default(),
+            },
+        )))
+    }
+
+    /// The peer has no more headers
+    fn finish_headers(&mut self, peer: PeerId) {
+        self.header_peer = None;
+        if let Some(sync_peer) = self.peers.get_mut(&peer) {
+            sync_peer.headers_done = true;
+        }
+    }
+
+    /// Request the queued blocks from the peers which have them, each from the peer
+    /// with the fewest blocks in flight. Return one `GetData` per peer.
+    pub fn request_blocks(&mut self, now: Instant) -> Vec<(PeerId, Message)> {
+        let mut in_flight: HashMap<PeerId, usize> =
+            self.peers.keys().map(|peer| (*peer, 0)).collect();
+        for entry in &self.queue {
+            if let DownloadState::InFlight { peer, .. } = entry.state {
+                *in_flight.entry(peer).or_default() += 1;
+            }
+        }
+
+        let mut requests: HashMap<PeerId, Vec<Inventory>> = HashMap::new();
+        for entry in self.queue.iter_mut().take(DOWNLOAD_WINDOW) {
+            if !matches!(entry.state, DownloadState::Pending) {
+                continue;
+            }
+            let height = entry.header.get_height();
+            let peer = self
+                .peers
+                .iter()
+                .filter(|(peer, sync_peer)| {
+                    sync_peer.best_height >= height && in_flight[*peer] < MAX_BLOCKS_IN_FLIGHT
+                })
+                .min_by_key(|(peer, _)| (in_flight[*peer], **peer))
+                .map(|(peer, _)| *peer);
+            let peer = match peer {
+                Some(peer) => peer,
+                None => continue,
+            };
+            *in_flight.get_mut(&peer).unwrap() += 1;
+            entry.state = DownloadState::InFlight { peer, since: now };
+            requests
+                .entry(peer)
+                .or_default()
+                .push(Inventory::Block(entry.hash));
+        }
+        let mut requests: Vec<(PeerId, Message)> = requests
+            .into_iter()
+            .map(|(peer, items)| (peer, Message::GetData(items)))
+            .collect();
+        requests.sort_by_key(|(peer, _)| *peer);
+        requests
+    }
+
+    /// Keep a block of the queue until it can be connected.
+    /// The block must match its checked header, also in its merkle root,
+    /// then the peer is known to have the chain up to it.
+    pub fn on_block(&mut self, peer: PeerId, block: &Block) -> BlockReceipt {
+        let entry = match self
+            .queue
+            .iter_mut()
+            .find(|entry| entry.hash == block.get_hash())
+        {
+            Some(entry) => entry,
+            None => return BlockReceipt::NotRequested,
+        };
+        if matches!(entry.state, DownloadState::Received(_)) {
+            return BlockReceipt::Accepted;
+        }
+        let matches_header = block.validate_pow()
+            && *block.get_header() == entry.header
+            && block.hash_transactions() == entry.header.get_merkle_root();
+        if !matches_header {
+            if matches!(entry.state, DownloadState::InFlight { peer: p, .. } if p == peer) {
+                entry.state = DownloadState::Pending;
+            }
+            return BlockReceipt::Rejected;
+        }
+        entry.state = DownloadState::Received(block.clone());
+        let (height, chain_work) = (entry.header.get_height(), entry.chain_work);
+        self.raise_peer_tip(peer, height, chain_work);
+        BlockReceipt::Accepted
+    }
+
+    /// Take the next block to connect, if it has arrived.
+    /// Return it with the peer which sent its header.
+    pub fn take_next_block(&mut self) -> Option<(Block, PeerId)> {
+        if !matches!(
+            self.queue.front().map(|entry| &entry.state),
+            Some(DownloadState::Received(_))
+        ) {
+            return None;
+        }
+        let entry = self.queue.pop_front()?;
+        match entry.state {
+            DownloadState::Received(block) => Some((block, entry.source)),
+            _ => None,
+        }
+    }
+
+    /// A block of the queue failed validation, so the headers after it are no good either.
+    /// They are dropped, and `header_source`, the peer which sent them, is forgotten.
+    pub fn on_invalid_block(&mut self, header_source: PeerId) {
+        self.queue.clear();
+        self.remove_peer(header_source);
+    }
+
+    /// Find the peers which did not answer in time, forget them and return them,
+    /// so the node can disconnect them. Their requests go to other peers.
+    pub fn check_stalls(&mut self, now: Instant) -> Vec<PeerId> {
+        let mut stalled = vec![];
+        if let Some((peer, since)) = self.header_peer {
+            if now.duration_since(since) > self.headers_timeout {
+                stalled.push(peer);
+            }
+        }
+        for entry in &self.queue {
+            if let DownloadState::InFlight { peer, since } = entry.state {
+                if now.duration_since(since) > self.block_timeout && !stalled.contains(&peer) {
+                    stalled.push(peer);
+                }
+            }
+        }
+        for peer in &stalled {
+            self.remove_peer(*peer);
+        }
+        stalled
+    }
+}
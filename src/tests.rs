@@ -1,213 +1,3137 @@
-#[cfg(test)]
-mod tests {
-    use crate::block::Block;
-    use crate::blockchain::{BlockChain, BLOCKS_TREE_NAME, TIP_BLOCK_HASH_KEY};
-    use crate::transaction::{TXOutput, Transaction};
-    use crate::utils::hex_encode;
-    use crate::utxo_set::UtxoSet;
-    use crate::wallet::hash_pub_key;
-    use crate::wallet::Wallet;
-    use std::collections::HashMap;
-    use std::fs;
-    use std::path::Path;
-    use std::sync::Mutex;
-
-    static TEST_MUTX: Mutex<()> = Mutex::new(());
-
-    // rm -rf blockchain_data
-    fn clean_db() {
-        let _guard = TEST_MUTX.lock().unwrap();
-        let db_path = Path::new("blockchain_data");
-        if db_path.exists() {
-            fs::remove_dir_all(db_path).unwrap();
-        }
+use crate::address::{
+    checksum, validate_address, Address, AddressError, Network, PUB_KEY_HASH_LENGTH,
+};
+use crate::block::{
+    Block, BlockHeader, INITIAL_BITS, MAX_BITS, MAX_FUTURE_BLOCK_TIME, RETARGET_INTERVAL,
+    TARGET_BLOCK_INTERVAL,
+};
+use crate::blockchain::{
+    BlockChain, BlockStatus, BlockValidationError, BODIES_TREE_NAME, CHAIN_WORK_TREE_NAME, DB_NAME,
+    HEADERS_TREE_NAME, LEGACY_BLOCKS_TREE_NAME, TIP_BLOCK_HASH_KEY,
+};
+use crate::cli::{execute, parse_args, Command};
+use crate::clock::{Clock, MockClock};
+use crate::coin_selection::{
+    BranchAndBound, CoinSelector, LargestFirst, RandomDraw, SmallestFirst, SpendableOutput,
+};
+use crate::error::Error;
+use crate::hash::{BlockHash, MerkleRoot, Txid};
+use crate::mempool::{Mempool, MempoolError};
+use crate::merkle::MerkleTree;
+use crate::miner::{split_nonces, BlockTemplate, MinerConfig, MiningJob};
+use crate::network::{
+    read_message, write_message, Inventory, Message, NetworkError, VersionMessage,
+    FRAME_HEADER_LENGTH, MAX_HEADERS_SIZE, MAX_INV_SIZE, MAX_MESSAGE_SIZE,
+};
+use crate::node::{Node, NodeConfig, NodeEvent, MAX_INBOUND_PEERS};
+use crate::rpc::{
+    DeadlineStream, RpcEvent, RpcServer, DEFAULT_RPC_ADDR, INVALID_PARAMS, INVALID_REQUEST,
+    MAX_RPC_CONNECTIONS, METHOD_NOT_FOUND, NOT_FOUND, PARSE_ERROR, TRANSACTION_REJECTED,
+};
+use crate::sync::{BlockReceipt, BlockSync, MAX_BLOCKS_IN_FLIGHT, MAX_QUEUED_HEADERS};
+use crate::transaction::{
+    get_block_subsidy, Fee, PrevOutputs, TXInput, TXOutput, Transaction, HALVING_INTERVAL,
+    INITIAL_SUBSIDY,
+};
+use crate::utils::hex_encode;
+use crate::utxo_set::{
+    utxo_key, UtxoSet, UTXO_ADDRESS_TREE, UTXO_BEST_BLOCK_KEY, UTXO_META_TREE,
+    UTXO_SCHEMA_VERSION_KEY, UTXO_TREE, UTXO_UNDO_TREE,
+};
+use crate::wallet::Wallet;
+use crate::wallet::{decode_address, hash_pub_key};
+use crate::wallets::Wallets;
+use data_encoding::HEXLOWER;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[test]
+fn print_transactions() {
+    let tx = Transaction::new_coinbase_tx(&Wallet::new().unwrap().get_address(), 0, 0).unwrap();
+    tx.print();
+}
+
+#[test]
+fn print_block1() {
+    let tx = Transaction::new_coinbase_tx(&Wallet::new().unwrap().get_address(), 0, 0).unwrap();
+    let tx = vec![tx];
+    let genesis_pre_hash = BlockHash::default();
+    let bk = Block::new(genesis_pre_hash, &tx, 0, INITIAL_BITS, 0);
+    bk.print();
+}
+
+#[test]
+fn print_block2() {
+    let tx = Transaction::new_coinbase_tx(&Wallet::new().unwrap().get_address(), 0, 0).unwrap();
+    let tx = vec![tx];
+    let genesis_pre_hash = BlockHash::new([0x12; 32]);
+    let bk = Block::new(genesis_pre_hash, &tx, 0, INITIAL_BITS, 0);
+    bk.print();
+}
+
+#[test]
+fn create_blockchain() {
+    let blockchain = BlockChain::create_temporary(&Wallet::new().unwrap().get_address()).unwrap();
+    println!("Tip block hash: {}", blockchain.get_tip_hash());
+    let db: &sled::Db = blockchain.get_db();
+    let headers_tree = db.open_tree(HEADERS_TREE_NAME).unwrap();
+    let tip_block_hash = headers_tree.get(TIP_BLOCK_HASH_KEY).unwrap();
+    let tip_block_hash = BlockHash::from_slice(&tip_block_hash.unwrap()).unwrap();
+    let tip_header_data = headers_tree.get(tip_block_hash).unwrap();
+    let tip_header = BlockHeader::deserialize(&tip_header_data.unwrap()).unwrap();
+    assert_eq!(tip_header.hash(), tip_block_hash);
+    assert_eq!(tip_header.get_height(), 0);
+    assert!(tip_header.get_pre_block_hash().is_zero());
+}
+
+#[test]
+fn mine_block() {
+    let address = Wallet::new().unwrap().get_address();
+    let blockchain = BlockChain::create_temporary(&address).unwrap();
+    let block = blockchain.mine_block(&address, &[]).unwrap();
+    // check block and tip block in db
+    println!("mined block: ");
+    block.print();
+    println!("\nTip block: ");
+    let db = blockchain.get_db();
+    let headers_tree = db.open_tree(HEADERS_TREE_NAME).unwrap();
+    let bodies_tree = db.open_tree(BODIES_TREE_NAME).unwrap();
+    let tip_blocks_hash = headers_tree.get(TIP_BLOCK_HASH_KEY).unwrap();
+    let tip_blocks_hash = BlockHash::from_slice(&tip_blocks_hash.unwrap()).unwrap();
+    let tip_header_data = headers_tree.get(tip_blocks_hash).unwrap().unwrap();
+    let tip_header = BlockHeader::deserialize(&tip_header_data).unwrap();
+    let tip_body_data = bodies_tree.get(tip_blocks_hash).unwrap().unwrap();
+    let tip_body: Vec<Transaction> = bincode::deserialize(&tip_body_data).unwrap();
+    let tip_block = Block::from_parts(tip_header, tip_body);
+    assert_eq!(tip_block.get_hash(), block.get_hash());
+    tip_block.print();
+}
+
+#[test]
+fn view_all_block() {
+    let address = Wallet::new().unwrap().get_address();
+    let blockchain = BlockChain::create_temporary(&address).unwrap();
+    let block = blockchain.mine_block(&address, &[]).unwrap();
+    // check block and tip block in db
+    println!("mined block: ");
+    block.print();
+    println!("\nVisit all blocks: ");
+    for block in blockchain.iterator() {
+        block.unwrap().print();
     }
+}
+
+#[test]
+fn test_find_spendable() {
+    let address = Wallet::new().unwrap().get_address();
+    let blockchain = BlockChain::create_temporary(&Wallet::new().unwrap().get_address()).unwrap();
+    let _ = blockchain.mine_block(&address, &[]).unwrap();
 
-    #[test]
-    fn print_transactions() {
-        let tx = Transaction::new_coinbase_tx("abxgtsunkodojahucd");
-        tx.print();
-    }
-
-    #[test]
-    fn print_block1() {
-        let tx = Transaction::new_coinbase_tx("abxgtsunkodojahucd");
-        let tx = vec![tx];
-        let genesis_pre_hash = String::from("0x0");
-        let bk = Block::new(genesis_pre_hash, &tx, 0);
-        bk.print();
-    }
-
-    #[test]
-    fn print_block2() {
-        let tx = Transaction::new_coinbase_tx("Heobockchain");
-        let tx = vec![tx];
-        let genesis_pre_hash = String::from("0x12324567");
-        let bk = Block::new(genesis_pre_hash, &tx, 0);
-        bk.print();
-    }
-
-    #[test]
-    fn create_blockchain() {
-        let _guard = TEST_MUTX.lock().unwrap();
-
-        let blockchain = BlockChain::create_blockchain("abxgtsunkodojahucd");
-        println!("Tip block hash: {}", blockchain.get_tip_hash());
-        let db: &sled::Db = blockchain.get_db();
-        let blocks_tree = db.open_tree(BLOCKS_TREE_NAME).unwrap();
-        let tip_block_hash = blocks_tree.get(TIP_BLOCK_HASH_KEY).unwrap();
-        let tip_block_hash = String::from_utf8(tip_block_hash.unwrap().to_vec()).unwrap();
-        let tip_block_data = blocks_tree.get(tip_block_hash).unwrap();
-        let tip_block = Block::deserialize(&tip_block_data.unwrap());
-        tip_block.print();
-
-        // unlock guard
-        drop(_guard);
-        clean_db();
-    }
-
-    #[test]
-    fn mine_block() {
-        let _guard = TEST_MUTX.lock().unwrap();
-
-        let blockchain = BlockChain::create_blockchain("bdsaowaappoqcvxhs");
-        let transaction = Transaction::new_coinbase_tx("bdsaowaappoqcvxhs");
-        let block = blockchain.mine_block(&[transaction]);
-        // check block and tip block in db
-        println!("mined block: ");
-        block.print();
-        println!("\nTip block: ");
-        let db = blockchain.get_db();
-        let blocks_tree = db.open_tree(BLOCKS_TREE_NAME).unwrap();
-        let tip_blocks_hash = blocks_tree.get(TIP_BLOCK_HASH_KEY).unwrap();
-        let tip_blocks_hash = String::from_utf8(tip_blocks_hash.unwrap().to_vec()).unwrap();
-        let tip_block: Block =
-            Block::deserialize(&blocks_tree.get(tip_blocks_hash).unwrap().unwrap());
-        tip_block.print();
-
-        // unlock guard
-        drop(_guard);
-        clean_db();
-    }
-
-    #[test]
-    fn view_all_block() {
-        let _guard = TEST_MUTX.lock().unwrap();
-        let blockchain = BlockChain::create_blockchain("abxgtsunkodojahucd");
-        let transaction = Transaction::new_coinbase_tx("abxgtsunkodojahucd");
-        let block = blockchain.mine_block(&[transaction]);
-        // check block and tip block in db
-        println!("mined block: ");
-        block.print();
-        println!("\nVisit all blocks: ");
-        let mut block_iterator = blockchain.iterator();
-        while let Some(block) = block_iterator.next() {
-            block.print();
+    let utxo: HashMap<Txid, Vec<(usize, TXOutput)>> = blockchain.find_utxo().unwrap();
+    for (k, v) in utxo.iter() {
+        println!("==============================");
+        println!("txid: {}", k);
+        for (vout, txo) in v {
+            println!("  {}: TXOutput: {:?}", vout, txo);
         }
+    }
+
+    println!("\n=====Find Spendable=========================\n");
+
+    let utxo_set = UtxoSet::new(&blockchain);
+    utxo_set.reindex().unwrap();
+    let pub_key_hash = decode_address(&address).unwrap();
+    let spendable_outputs = utxo_set.find_spendable_outputs(&pub_key_hash, 8).unwrap();
+    println!("pub_key_hash: {:?}", hex_encode(&pub_key_hash));
+    println!("spendable_outputs: {:?}", spendable_outputs);
+    assert_eq!(spendable_outputs.0, 10);
+}
+
+#[test]
+fn test_utxo_transaction() {
+    let from = Wallet::new().unwrap();
+    let to = Wallet::new().unwrap();
+
+    let blockchain = BlockChain::create_temporary(&to.get_address()).unwrap();
+    let _ = blockchain.mine_block(&from.get_address(), &[]).unwrap();
+
+    let utxo_set = UtxoSet::new(&blockchain);
+    utxo_set.reindex().unwrap();
+
+    println!("\n==========utxo transaction=================\n");
+    let transaction =
+        Transaction::new_utxo_transactions(&from, &to.get_address(), 8, &utxo_set).unwrap();
+    transaction.print();
+    assert!(blockchain.verify_transaction(&transaction).unwrap());
+    let _ = blockchain
+        .mine_block(&to.get_address(), &[transaction])
+        .unwrap();
+    println!("\n=====Find Spendable=========================\n");
+
+    utxo_set.reindex().unwrap();
+    let pub_key_hash = hash_pub_key(from.get_public_key());
+    let spendable_outputs = utxo_set.find_spendable_outputs(&pub_key_hash, 8).unwrap();
+    println!("pub_key_hash: {:?}", hex_encode(&pub_key_hash));
+    println!("spendable_outputs: {:?}", spendable_outputs);
+    assert_eq!(spendable_outputs.0, 2);
+}
+
+#[test]
+fn test_pub_key_hash() {
+    let w1 = Wallet::new().unwrap();
+    let w2 = Wallet::new().unwrap();
+
+    let addr1 = w1.get_address();
+    let addr2 = w2.get_address();
+
+    let pub_key1 = w1.get_public_key();
+    let pub_key2 = w2.get_public_key();
+
+    let pub_key_hash1 = hash_pub_key(pub_key1);
+    let pub_key_hash2 = hash_pub_key(pub_key2);
+
+    let pub_key_hash_from_addr1 = &decode_address(&addr1).unwrap();
+    let pub_key_hash_from_addr2 = &decode_address(&addr2).unwrap();
+
+    let pub_key_hash1_str = hex_encode(&pub_key_hash1);
+    let pub_key_hash2_str = hex_encode(&pub_key_hash2);
+
+    let pub_key_hash_from_addr1_str = hex_encode(pub_key_hash_from_addr1);
+    let pub_key_hash_from_addr2_str = hex_encode(pub_key_hash_from_addr2);
+
+    assert_eq!(pub_key_hash1_str, pub_key_hash_from_addr1_str);
+    assert_eq!(pub_key_hash2_str, pub_key_hash_from_addr2_str);
+}
+
+#[test]
+fn test_sign_and_verify() {
+    let owner = Wallet::new().unwrap();
+    let thief = Wallet::new().unwrap();
+
+    let blockchain = BlockChain::create_temporary(&owner.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    utxo_set.reindex().unwrap();
+
+    let tx =
+        Transaction::new_utxo_transactions(&owner, &thief.get_address(), 3, &utxo_set).unwrap();
+    assert!(blockchain.verify_transaction(&tx).unwrap());
+
+    // signing the same inputs with someone else's key must not verify
+    let mut forged = tx.clone();
+    blockchain.sign_transaction(&mut forged, &thief).unwrap();
+    assert!(!blockchain.verify_transaction(&forged).unwrap());
+}
+
+#[test]
+fn test_reject_invalid_blocks() {
+    let wallet = Wallet::new().unwrap();
+    let address = wallet.get_address();
+    let blockchain = BlockChain::create_temporary(&address).unwrap();
+    let tip_hash = blockchain.get_tip_hash();
+    let timestamp = blockchain.get_median_time_past().unwrap() + 1;
+
+    let coinbase = vec![Transaction::new_coinbase_tx(&address, 1, 0).unwrap()];
+    let block = Block::new(BlockHash::default(), &coinbase, 1, INITIAL_BITS, timestamp);
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(
+            BlockValidationError::PrevHashMismatch { .. }
+        ))
+    ));
+
+    let block = Block::new(tip_hash, &coinbase, 5, INITIAL_BITS, timestamp);
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(BlockValidationError::InvalidHeight {
+            expected: 1,
+            found: 5
+        }))
+    ));
+
+    let two_coinbase = vec![coinbase[0].clone(), coinbase[0].clone()];
+    let block = Block::new(tip_hash, &two_coinbase, 1, INITIAL_BITS, timestamp);
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(BlockValidationError::MultipleCoinbase))
+    ));
+
+    // flip one bit of the stored hash
+    let block = Block::new(tip_hash, &coinbase, 1, INITIAL_BITS, timestamp);
+    let mut data = block.serialize().unwrap();
+    let hash = *block.get_hash().as_bytes();
+    let pos = data
+        .windows(hash.len())
+        .position(|w| w == hash.as_slice())
+        .unwrap()
+        + 2;
+    data[pos] ^= 1;
+    let block = Block::deserialize(&data).unwrap();
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(
+            BlockValidationError::InvalidProofOfWork
+        ))
+    ));
+
+    assert_eq!(blockchain.get_tip_hash(), tip_hash);
+    assert_eq!(blockchain.get_best_height().unwrap(), 0);
+}
+
+#[test]
+fn test_reject_double_spend() {
+    let from = Wallet::new().unwrap();
+    let to = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&from.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    utxo_set.reindex().unwrap();
+
+    let tx1 = Transaction::new_utxo_transactions(&from, &to.get_address(), 4, &utxo_set).unwrap();
+    let tx2 = Transaction::new_utxo_transactions(&from, &to.get_address(), 5, &utxo_set).unwrap();
+
+    // both transactions spend the genesis coinbase
+    let block = Block::new(
+        blockchain.get_tip_hash(),
+        &[tx1.clone(), tx2.clone()],
+        1,
+        INITIAL_BITS,
+        blockchain.get_median_time_past().unwrap() + 1,
+    );
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(
+            BlockValidationError::DoubleSpend { .. }
+        ))
+    ));
 
-        // unlock guard
-        drop(_guard);
-        clean_db();
+    let timestamp = blockchain.get_median_time_past().unwrap() + 1;
+    let block = Block::new(
+        blockchain.get_tip_hash(),
+        &[tx1],
+        1,
+        INITIAL_BITS,
+        timestamp,
+    );
+    blockchain.add_block(&block).unwrap();
+
+    let timestamp = blockchain.get_median_time_past().unwrap() + 1;
+    let block = Block::new(
+        blockchain.get_tip_hash(),
+        &[tx2],
+        2,
+        INITIAL_BITS,
+        timestamp,
+    );
+    // the output spent in the chain is not in the UTXO set any more
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(
+            BlockValidationError::MissingInput { .. }
+        ))
+    ));
+    assert_eq!(blockchain.get_best_height().unwrap(), 1);
+}
+
+#[test]
+fn test_reject_forged_txid() {
+    let from = Wallet::new().unwrap();
+    let to = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&from.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    utxo_set.reindex().unwrap();
+    let genesis = blockchain
+        .get_block(&blockchain.get_tip_hash())
+        .unwrap()
+        .unwrap();
+    let victim = genesis.get_transactions()[0].get_id();
+
+    let tx = Transaction::new_utxo_transactions(&from, &to.get_address(), 4, &utxo_set).unwrap();
+    assert!(tx.has_valid_id());
+    assert_eq!(tx.compute_id(), tx.get_id());
+
+    // the signatures do not cover the id, so only the id check finds the swap
+    let txid = tx.get_id();
+    let forge = |data: &[u8]| {
+        let pos = data
+            .windows(32)
+            .position(|w| w == txid.as_bytes().as_slice())
+            .unwrap();
+        let mut data = data.to_vec();
+        data[pos..pos + 32].copy_from_slice(victim.as_bytes());
+        data
+    };
+    assert!(matches!(
+        Transaction::deserialize(&forge(&tx.serialize())),
+        Err(Error::Serialization(_))
+    ));
+
+    let block = Block::new(
+        blockchain.get_tip_hash(),
+        std::slice::from_ref(&tx),
+        1,
+        INITIAL_BITS,
+        blockchain.get_median_time_past().unwrap() + 1,
+    );
+    assert!(Block::deserialize(&forge(&block.serialize().unwrap())).is_err());
+
+    // a peer which relays a forged id fails to decode, and the connection is closed
+    let payload = forge(&bincode::serialize(&Message::Tx(tx.clone())).unwrap());
+    let mut frame = Message::Tx(tx.clone()).encode().unwrap()[..FRAME_HEADER_LENGTH].to_vec();
+    frame[8..12].copy_from_slice(&checksum(&payload));
+    frame.extend(payload);
+    assert!(matches!(
+        read_message(&mut frame.as_slice()),
+        Err(Error::Network(NetworkError::UnexpectedMessage(_)))
+    ));
+    let mempool = Mempool::new();
+    assert_eq!(mempool.add_transaction(&blockchain, tx).unwrap(), txid);
+
+    blockchain.add_block(&block).unwrap();
+}
+
+#[test]
+fn test_wallets_persist() {
+    let path = std::env::temp_dir().join(format!(
+        "wallet_{}.dat",
+        Wallet::new().unwrap().get_address()
+    ));
+
+    let mut wallets = Wallets::open(&path).unwrap();
+    assert!(wallets.get_addresses().is_empty());
+    let addr1 = wallets.create_wallet().unwrap();
+    let addr2 = wallets.create_wallet().unwrap();
+    let public_key = wallets
+        .get_wallet(&addr1)
+        .unwrap()
+        .get_public_key()
+        .to_vec();
+
+    let wallets = Wallets::open(&path).unwrap();
+    let mut expected = vec![addr1.clone(), addr2];
+    expected.sort();
+    assert_eq!(wallets.get_addresses(), expected);
+    let wallet = wallets.get_wallet(&addr1).unwrap();
+    assert_eq!(wallet.get_public_key(), public_key.as_slice());
+    assert_eq!(wallet.get_address(), addr1);
+    assert!(wallets.get_wallet("unknown").is_none());
+
+    // the private keys are only readable by the owner
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
     }
 
-    #[test]
-    fn test_find_spendable() {
-        let _guard = TEST_MUTX.lock().unwrap();
-        let blockchain = BlockChain::create_blockchain("abxgtsunkodojahucd");
-        let transaction = Transaction::new_coinbase_tx("hegtsodoucahjsubxg");
-        let _ = blockchain.mine_block(&[transaction]);
+    fs::remove_file(&path).unwrap();
+}
 
-        let utxo: HashMap<String, Vec<TXOutput>> = blockchain.find_utxo();
-        for (k, v) in utxo.iter() {
-            println!("==============================");
-            println!("txid: {}", k);
-            for txo in v {
-                println!("  TXOutput: {:?}", txo);
+/// sled's background threads can hold the file lock for a moment after the db is dropped,
+/// so reopening the same path is retried for a while
+fn open_when_unlocked<T, E: std::fmt::Debug>(open: impl Fn() -> std::result::Result<T, E>) -> T {
+    for _ in 0..100 {
+        match open() {
+            Err(err) if format!("{:?}", err).contains("could not acquire lock") => {
+                std::thread::sleep(std::time::Duration::from_millis(10));
             }
+            result => return result.unwrap(),
         }
+    }
+    open().unwrap()
+}
 
-        println!("\n=====Find Spendable=========================\n");
+#[test]
+fn test_create_and_open_on_disk() {
+    let address = Wallet::new().unwrap().get_address();
+    let path = std::env::temp_dir().join(format!("blockchain_{}", address));
 
-        let utxo_set = UtxoSet::new(&blockchain);
-        utxo_set.reindex();
-        let addr = "hegtsodoucahjsubxg".as_bytes();
-        let decode = bs58::decode(addr).into_vec().unwrap();
-        let pub_key_hash = &decode[1..decode.len() - 4];
-        let spendable_outputs = utxo_set.find_spendable_outputs(pub_key_hash, 8);
-        let pub_key_hash = pub_key_hash.to_vec();
-        println!("pub_key_hash: {:?}", hex_encode(&pub_key_hash));
-        println!("spendable_outputs: {:?}", spendable_outputs);
+    assert!(matches!(
+        BlockChain::open(&path),
+        Err(Error::BlockchainNotFound)
+    ));
+
+    let blockchain = BlockChain::create(&path, &address).unwrap();
+    let block = blockchain.mine_block(&address, &[]).unwrap();
+    drop(blockchain);
+
+    let blockchain = open_when_unlocked(|| BlockChain::open(&path));
+    assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+    assert_eq!(blockchain.get_best_height().unwrap(), 1);
+    drop(blockchain);
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_create_with_invalid_address() {
+    // neither the library nor the CLI leaves an empty db directory behind
+    let address = Wallet::new().unwrap().get_address();
+    let path = std::env::temp_dir().join(format!("blockchain_invalid_{}", address));
+    assert!(matches!(
+        BlockChain::create(&path, "not an address"),
+        Err(Error::InvalidAddress(_))
+    ));
+    assert!(!path.exists());
+
+    let datadir = path.to_str().unwrap().to_string();
+    let command = Command::CreateBlockchain {
+        address: String::from("not an address"),
+    };
+    assert!(matches!(
+        execute(Some(datadir), command),
+        Err(Error::InvalidAddress(_))
+    ));
+    assert!(!path.join(DB_NAME).exists());
+    fs::remove_dir_all(&path).unwrap();
+}
 
-        // unlock guard
-        drop(_guard);
-        clean_db();
+#[test]
+fn test_reject_legacy_layout() {
+    let address = Wallet::new().unwrap().get_address();
+    let path = std::env::temp_dir().join(format!("blockchain_legacy_{}", address));
+
+    // a db of the old layout keeps the tip and the whole blocks in one tree
+    let db = sled::open(&path).unwrap();
+    db.open_tree(LEGACY_BLOCKS_TREE_NAME)
+        .unwrap()
+        .insert(TIP_BLOCK_HASH_KEY, "0".repeat(64).as_bytes())
+        .unwrap();
+    db.flush().unwrap();
+    drop(db);
+
+    let result = open_when_unlocked(|| match BlockChain::open(&path) {
+        Err(Error::IncompatibleDatabase(msg)) => Ok(msg),
+        Err(err) => Err(err),
+        Ok(_) => panic!("a db of the old layout is opened"),
+    });
+    assert!(result.contains(LEGACY_BLOCKS_TREE_NAME));
+    assert!(matches!(
+        BlockChain::create(&path, &address),
+        Err(Error::IncompatibleDatabase(_))
+    ));
+    // no genesis block is written next to the old chain
+    let db = sled::open(&path).unwrap();
+    assert!(db.open_tree(HEADERS_TREE_NAME).unwrap().is_empty());
+    drop(db);
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_errors_instead_of_panics() {
+    let from = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&from.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    utxo_set.reindex().unwrap();
+
+    assert!(matches!(
+        Transaction::new_coinbase_tx("abxgtsunkodojahucd", 0, 0),
+        Err(Error::InvalidAddress(_))
+    ));
+    assert!(matches!(
+        Transaction::new_utxo_transactions(&from, "0OIl", 1, &utxo_set),
+        Err(Error::InvalidAddress(_))
+    ));
+    assert!(matches!(
+        Transaction::new_utxo_transactions(
+            &from,
+            &Wallet::new().unwrap().get_address(),
+            11,
+            &utxo_set
+        ),
+        Err(Error::InsufficientFunds {
+            needed: 11,
+            available: 10
+        })
+    ));
+    assert!(matches!(
+        Block::deserialize(&[1, 2, 3]),
+        Err(Error::Corruption(_))
+    ));
+}
+
+#[test]
+fn test_utxo_update_matches_reindex() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let utxo_tree = blockchain.get_db().open_tree(UTXO_TREE).unwrap();
+
+    // the genesis block is already in the UTXO set
+    assert_eq!(utxo_set.count_transactions().unwrap(), 1);
+
+    let tx = Transaction::new_utxo_transactions(&alice, &bob.get_address(), 4, &utxo_set).unwrap();
+    blockchain.mine_block(&bob.get_address(), &[tx]).unwrap();
+
+    let bob_hash = hash_pub_key(bob.get_public_key());
+    let alice_hash = hash_pub_key(alice.get_public_key());
+    assert_eq!(
+        utxo_set.find_spendable_outputs(&bob_hash, 100).unwrap().0,
+        14
+    );
+    assert_eq!(
+        utxo_set.find_spendable_outputs(&alice_hash, 100).unwrap().0,
+        6
+    );
+
+    let updated: Vec<_> = utxo_tree.iter().map(|item| item.unwrap()).collect();
+    utxo_set.reindex().unwrap();
+    let reindexed: Vec<_> = utxo_tree.iter().map(|item| item.unwrap()).collect();
+    assert_eq!(updated, reindexed);
+}
+
+#[test]
+fn test_partially_spent_transaction() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let alice_hash = hash_pub_key(alice.get_public_key());
+    let bob_hash = hash_pub_key(bob.get_public_key());
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let miner = Wallet::new().unwrap().get_address();
+
+    // tx1: [bob: 8, alice: 2]
+    let tx1 = Transaction::new_utxo_transactions(&alice, &bob.get_address(), 8, &utxo_set).unwrap();
+    blockchain
+        .mine_block(&miner, std::slice::from_ref(&tx1))
+        .unwrap();
+
+    // bob spends tx1:0, alice still owns tx1:1
+    let tx2 = Transaction::new_utxo_transactions(&bob, &alice.get_address(), 3, &utxo_set).unwrap();
+    blockchain.mine_block(&miner, &[tx2]).unwrap();
+    let (balance, outputs) = utxo_set.find_spendable_outputs(&alice_hash, 100).unwrap();
+    assert_eq!(balance, 5);
+    assert_eq!(outputs.get(&tx1.get_id()), Some(&vec![1]));
+
+    // spending tx1:1 must use the real index
+    let tx3 = Transaction::new_utxo_transactions(&alice, &bob.get_address(), 5, &utxo_set).unwrap();
+    blockchain.mine_block(&miner, &[tx3]).unwrap();
+    assert_eq!(
+        utxo_set.find_spendable_outputs(&alice_hash, 100).unwrap().0,
+        0
+    );
+    assert_eq!(
+        utxo_set.find_spendable_outputs(&bob_hash, 100).unwrap().0,
+        10
+    );
+
+    let utxo_tree = blockchain.get_db().open_tree(UTXO_TREE).unwrap();
+    let updated: Vec<_> = utxo_tree.iter().map(|item| item.unwrap()).collect();
+    utxo_set.reindex().unwrap();
+    let reindexed: Vec<_> = utxo_tree.iter().map(|item| item.unwrap()).collect();
+    assert_eq!(updated, reindexed);
+}
+
+#[test]
+fn test_migrate_utxo_schema() {
+    let address = Wallet::new().unwrap().get_address();
+    let path = std::env::temp_dir().join(format!("blockchain_{}", address));
+    let blockchain = BlockChain::create(&path, &address).unwrap();
+    let genesis = blockchain.iterator().next().unwrap().unwrap();
+    let coinbase = &genesis.get_transactions()[0];
+    drop(blockchain);
+
+    // rewrite the chainstate in the version 1 format: txid -> Vec<TXOutput>
+    {
+        let db = open_when_unlocked(|| sled::open(&path));
+        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        utxo_tree.clear().unwrap();
+        let outs = bincode::serialize(&coinbase.get_vout()).unwrap();
+        utxo_tree.insert(coinbase.get_id(), outs).unwrap();
+        db.open_tree(UTXO_META_TREE).unwrap().clear().unwrap();
     }
 
-    #[test]
-    fn test_utxo_transaction() {
-        let _guard = TEST_MUTX.lock().unwrap();
+    let blockchain = open_when_unlocked(|| BlockChain::open(&path));
+    let utxo_tree = blockchain.get_db().open_tree(UTXO_TREE).unwrap();
+    assert!(utxo_tree.get(coinbase.get_id()).unwrap().is_none());
+    assert!(utxo_tree
+        .get(utxo_key(&coinbase.get_id(), 0))
+        .unwrap()
+        .is_some());
+    drop(utxo_tree);
+    drop(blockchain);
 
-        let blockchain = BlockChain::create_blockchain("abxgtsunkodojahucd");
-        let transaction = Transaction::new_coinbase_tx("hegtsodoucahjsubxg");
-        let _ = blockchain.mine_block(&[transaction]);
+    fs::remove_dir_all(&path).unwrap();
+}
 
-        let utxo_set = UtxoSet::new(&blockchain);
-        utxo_set.reindex();
+#[test]
+fn test_repair_chainstate_on_open() {
+    let address = Wallet::new().unwrap().get_address();
+    let path = std::env::temp_dir().join(format!("blockchain_{}", address));
+    let blockchain = BlockChain::create(&path, &address).unwrap();
+    let block1 = blockchain.mine_block(&address, &[]).unwrap();
+    let at_block1 = chainstate(&blockchain);
+    let block2 = blockchain.mine_block(&address, &[]).unwrap();
+    // the tip is moved in the same transaction as the UTXO set
+    assert_eq!(
+        UtxoSet::new(&blockchain).get_best_block().unwrap(),
+        Some(block2.get_hash())
+    );
+    drop(blockchain);
+
+    // the node stopped in the middle of a reindex, after the tip went back to block 1
+    {
+        let db = open_when_unlocked(|| sled::open(&path));
+        let meta_tree = db.open_tree(UTXO_META_TREE).unwrap();
+        meta_tree.remove(UTXO_BEST_BLOCK_KEY).unwrap();
+        db.open_tree(UTXO_TREE).unwrap().clear().unwrap();
+        let headers_tree = db.open_tree(HEADERS_TREE_NAME).unwrap();
+        headers_tree
+            .insert(TIP_BLOCK_HASH_KEY, block1.get_hash().as_ref())
+            .unwrap();
+        db.flush().unwrap();
+    }
+
+    let blockchain = open_when_unlocked(|| BlockChain::open(&path));
+    assert_eq!(blockchain.get_tip_hash(), block1.get_hash());
+    assert_eq!(chainstate(&blockchain), at_block1);
+    assert_eq!(
+        UtxoSet::new(&blockchain).get_best_block().unwrap(),
+        Some(block1.get_hash())
+    );
+    drop(blockchain);
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_retarget_difficulty() {
+    let address = Wallet::new().unwrap().get_address();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let blockchain = BlockChain::create_temporary_with_clock(&address, clock.clone()).unwrap();
+
+    // one block per second is far too fast
+    for _ in 1..RETARGET_INTERVAL {
+        clock.advance(1);
+        let block = blockchain.mine_block(&address, &[]).unwrap();
+        assert_eq!(block.get_bits(), INITIAL_BITS);
+    }
+    assert_eq!(blockchain.get_next_bits().unwrap(), INITIAL_BITS + 1);
+
+    clock.advance(1);
+    let block = Block::new(
+        blockchain.get_tip_hash(),
+        &[],
+        RETARGET_INTERVAL,
+        INITIAL_BITS,
+        clock.now(),
+    );
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(BlockValidationError::InvalidBits {
+            expected: 3,
+            found: 2
+        }))
+    ));
+    let block = blockchain.mine_block(&address, &[]).unwrap();
+    assert_eq!(block.get_bits(), INITIAL_BITS + 1);
+    assert!(block.validate_pow());
+
+    // ten times slower than expected, the difficulty goes back
+    for _ in 1..RETARGET_INTERVAL {
+        clock.advance(TARGET_BLOCK_INTERVAL * 10);
+        blockchain.mine_block(&address, &[]).unwrap();
+    }
+    assert_eq!(blockchain.get_next_bits().unwrap(), INITIAL_BITS);
+}
+
+#[test]
+fn test_block_timestamps() {
+    let address = Wallet::new().unwrap().get_address();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let blockchain = BlockChain::create_temporary_with_clock(&address, clock.clone()).unwrap();
+
+    clock.advance(60);
+    let block = blockchain.mine_block(&address, &[]).unwrap();
+    assert_eq!(block.get_timestamp(), 1_000_060);
+
+    // a clock going backwards still gives a valid timestamp
+    clock.set(999_000);
+    let block = blockchain.mine_block(&address, &[]).unwrap();
+    assert!(block.get_timestamp() > 1_000_000);
+
+    let median_time_past = blockchain.get_median_time_past().unwrap();
+    let tip_hash = blockchain.get_tip_hash();
+    let block = Block::new(tip_hash, &[], 3, INITIAL_BITS, median_time_past);
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(
+            BlockValidationError::TimestampTooOld { .. }
+        ))
+    ));
+
+    let too_new = clock.now() + MAX_FUTURE_BLOCK_TIME + 1;
+    let block = Block::new(tip_hash, &[], 3, INITIAL_BITS, too_new);
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(
+            BlockValidationError::TimestampTooNew { .. }
+        ))
+    ));
+
+    let block = Block::new(tip_hash, &[], 3, INITIAL_BITS, median_time_past + 1);
+    blockchain.add_block(&block).unwrap();
+}
+
+#[test]
+fn test_merkle_proof() {
+    for count in 1..=7u8 {
+        let txids: Vec<Txid> = (0..count).map(|i| Txid::new([i; 32])).collect();
+        let tree = MerkleTree::new(&txids);
+        let root = tree.root();
+        for txid in &txids {
+            let proof = tree.proof(txid).unwrap();
+            assert!(proof.verify(&root));
+            assert!(!proof.verify(&MerkleRoot::default()));
+        }
+        assert!(tree.proof(&Txid::new([0xff; 32])).is_none());
+    }
+
+    // an odd leaf is not paired with itself, so appending a copy changes the root
+    let txids: Vec<Txid> = (0..3u8).map(|i| Txid::new([i; 32])).collect();
+    let mut duplicated = txids.clone();
+    duplicated.push(txids[2]);
+    assert_ne!(
+        MerkleTree::new(&txids).root(),
+        MerkleTree::new(&duplicated).root()
+    );
+}
+
+#[test]
+fn test_block_merkle_proof() {
+    let from = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&from.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let tx = Transaction::new_utxo_transactions(
+        &from,
+        &Wallet::new().unwrap().get_address(),
+        3,
+        &utxo_set,
+    )
+    .unwrap();
+    let block = blockchain
+        .mine_block(&from.get_address(), std::slice::from_ref(&tx))
+        .unwrap();
+
+    let proof = block.merkle_proof(&tx.get_id()).unwrap();
+    assert!(proof.verify(&block.hash_transactions()));
+    assert_eq!(proof.get_txid(), tx.get_id());
+
+    let genesis = blockchain.iterator().nth(1).unwrap().unwrap();
+    assert!(!proof.verify(&genesis.hash_transactions()));
+    assert!(genesis.merkle_proof(&tx.get_id()).is_none());
+}
+
+#[test]
+fn test_walk_headers() {
+    let address = Wallet::new().unwrap().get_address();
+    let blockchain = BlockChain::create_temporary(&address).unwrap();
+    for _ in 0..3 {
+        blockchain.mine_block(&address, &[]).unwrap();
+    }
+
+    let headers: Vec<BlockHeader> = blockchain
+        .header_iterator()
+        .map(|header| header.unwrap())
+        .collect();
+    let blocks: Vec<Block> = blockchain.iterator().map(|block| block.unwrap()).collect();
+    assert_eq!(headers.len(), 4);
+    for (header, block) in headers.iter().zip(&blocks) {
+        assert_eq!(header, block.get_header());
+        assert_eq!(header.hash(), block.get_hash());
+        assert_eq!(header.get_merkle_root(), block.hash_transactions());
+        assert!(header.validate_pow());
+    }
+    assert_eq!(headers[3].get_height(), 0);
+
+    // headers can be walked without the bodies
+    let bodies_tree = blockchain.get_db().open_tree(BODIES_TREE_NAME).unwrap();
+    bodies_tree.remove(blockchain.get_tip_hash()).unwrap();
+    assert_eq!(blockchain.header_iterator().count(), 4);
+    assert!(matches!(
+        blockchain.iterator().next(),
+        Some(Err(Error::Corruption(_)))
+    ));
+}
+
+#[test]
+fn test_reject_merkle_root_mismatch() {
+    let address = Wallet::new().unwrap().get_address();
+    let blockchain = BlockChain::create_temporary(&address).unwrap();
+    let timestamp = blockchain.get_median_time_past().unwrap() + 1;
+    let coinbase = Transaction::new_coinbase_tx(&address, 1, 0).unwrap();
+    let block = Block::new(
+        blockchain.get_tip_hash(),
+        &[coinbase],
+        1,
+        INITIAL_BITS,
+        timestamp,
+    );
+
+    // a valid header with another body
+    let other_coinbase = Transaction::new_coinbase_tx_with_extra_nonce(&address, 1, 0, 1).unwrap();
+    let block = Block::from_parts(block.get_header().clone(), vec![other_coinbase]);
+    assert!(block.validate_pow());
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(BlockValidationError::InvalidMerkleRoot))
+    ));
+    assert_eq!(blockchain.get_best_height().unwrap(), 0);
+}
+
+#[test]
+fn test_hash_types() {
+    let tx = Transaction::new_coinbase_tx(&Wallet::new().unwrap().get_address(), 0, 0).unwrap();
+    let txid = tx.get_id();
+    let text = txid.to_string();
+    assert_eq!(text.len(), 64);
+    assert_eq!(text, text.to_lowercase());
+    assert_eq!(text.parse::<Txid>().unwrap(), txid);
+    assert_eq!(text.to_uppercase().parse::<Txid>().unwrap(), txid);
+    assert!(matches!(
+        "0x1234".parse::<Txid>(),
+        Err(Error::InvalidHash(_))
+    ));
+    assert!(matches!(
+        "zz".repeat(32).parse::<BlockHash>(),
+        Err(Error::InvalidHash(_))
+    ));
+    assert!(BlockHash::from_slice(&[0; 31]).is_err());
+
+    // 32 raw bytes in the db, not a hex string
+    assert_eq!(bincode::serialize(&txid).unwrap().len(), 32);
+
+    let blockchain = BlockChain::create_temporary(&Wallet::new().unwrap().get_address()).unwrap();
+    let tip_hash = blockchain.get_tip_hash();
+    let block = blockchain.get_block(&tip_hash).unwrap().unwrap();
+    assert_eq!(block.get_hash(), tip_hash);
+    assert_eq!(tip_hash.to_string().parse::<BlockHash>().unwrap(), tip_hash);
+}
+
+/// Every output of `prev_tx`, as the outputs spent by a transaction
+fn outputs_of(prev_tx: &Transaction) -> PrevOutputs {
+    prev_tx
+        .get_vout()
+        .iter()
+        .enumerate()
+        .map(|(vout, out)| ((prev_tx.get_id(), vout), out.clone()))
+        .collect()
+}
+
+/// Spend output `vout` of `prev_tx` to `to`, signed by `owner`, without looking at the chain
+fn spend_output(owner: &Wallet, prev_tx: &Transaction, vout: usize, to: &str) -> Transaction {
+    let value = prev_tx.get_vout()[vout].get_value();
+    let input = TXInput::new(prev_tx.get_id(), vout, owner.get_public_key());
+    let mut tx = Transaction::new(vec![input], vec![TXOutput::new(value, to).unwrap()]);
+    tx.sign(owner, &outputs_of(prev_tx)).unwrap();
+    tx
+}
+
+#[test]
+fn test_mempool_accept_and_confirm() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let mempool = Mempool::new();
+
+    let tx1 = Transaction::new_utxo_transactions(&alice, &bob.get_address(), 4, &utxo_set).unwrap();
+    assert_eq!(
+        mempool.add_transaction(&blockchain, tx1.clone()).unwrap(),
+        tx1.get_id()
+    );
+    assert!(matches!(
+        mempool.add_transaction(&blockchain, tx1.clone()),
+        Err(Error::RejectedTransaction(
+            MempoolError::AlreadyInPool { .. }
+        ))
+    ));
+
+    // both spend the genesis coinbase
+    let tx2 = Transaction::new_utxo_transactions(&alice, &bob.get_address(), 5, &utxo_set).unwrap();
+    assert!(matches!(
+        mempool.add_transaction(&blockchain, tx2),
+        Err(Error::RejectedTransaction(MempoolError::Conflict { spent_by, .. })) if spent_by == tx1.get_id()
+    ));
+
+    let coinbase = Transaction::new_coinbase_tx(&alice.get_address(), 1, 0).unwrap();
+    assert!(matches!(
+        mempool.add_transaction(&blockchain, coinbase),
+        Err(Error::RejectedTransaction(MempoolError::Coinbase))
+    ));
+
+    // bob spends the unconfirmed output of tx1
+    let tx3 = spend_output(&bob, &tx1, 0, &alice.get_address());
+    mempool.add_transaction(&blockchain, tx3.clone()).unwrap();
+    assert_eq!(
+        mempool.get_depends(&tx3.get_id()).unwrap(),
+        vec![tx1.get_id()]
+    );
+    assert_eq!(mempool.len(), 2);
+
+    let template = mempool.get_block_template(1);
+    assert_eq!(template.len(), 1);
+    assert_eq!(template[0].get_id(), tx1.get_id());
+    let template = mempool.get_block_template(10);
+    let ids: Vec<Txid> = template.iter().map(|tx| tx.get_id()).collect();
+    assert_eq!(ids, vec![tx1.get_id(), tx3.get_id()]);
+
+    // parent and child in one block
+    let block = blockchain
+        .mine_block(&alice.get_address(), &template)
+        .unwrap();
+    mempool.remove_confirmed(&block);
+    assert!(mempool.is_empty());
+    assert!(utxo_set.get_output(&tx3.get_id(), 0).unwrap().is_some());
+}
+
+#[test]
+fn test_mempool_reject_and_evict() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let mempool = Mempool::new();
+    let genesis = blockchain.iterator().next().unwrap().unwrap();
+    let genesis_coinbase = genesis.get_transactions()[0].clone();
+
+    // bob can not spend the output of alice
+    let stolen = spend_output(&bob, &genesis_coinbase, 0, &bob.get_address());
+    assert!(matches!(
+        mempool.add_transaction(&blockchain, stolen),
+        Err(Error::RejectedTransaction(
+            MempoolError::InvalidSignature { .. }
+        ))
+    ));
+
+    // tx1 is not in the chain nor in the pool
+    let tx1 = Transaction::new_utxo_transactions(&alice, &bob.get_address(), 4, &utxo_set).unwrap();
+    let orphan = spend_output(&bob, &tx1, 0, &alice.get_address());
+    assert!(matches!(
+        mempool.add_transaction(&blockchain, orphan.clone()),
+        Err(Error::RejectedTransaction(
+            MempoolError::MissingInput { .. }
+        ))
+    ));
+
+    mempool.add_transaction(&blockchain, tx1.clone()).unwrap();
+    mempool
+        .add_transaction(&blockchain, orphan.clone())
+        .unwrap();
+
+    // a block spends the genesis coinbase with another transaction
+    let tx2 = Transaction::new_utxo_transactions(&alice, &bob.get_address(), 5, &utxo_set).unwrap();
+    let block = blockchain.mine_block(&alice.get_address(), &[tx2]).unwrap();
+    mempool.remove_confirmed(&block);
+    assert!(!mempool.contains(&tx1.get_id()));
+    assert!(!mempool.contains(&orphan.get_id()));
+    assert!(mempool.is_empty());
+
+    // the spent output is now missing
+    assert!(matches!(
+        mempool.add_transaction(&blockchain, tx1),
+        Err(Error::RejectedTransaction(
+            MempoolError::MissingInput { .. }
+        ))
+    ));
+}
+
+#[test]
+fn test_block_subsidy() {
+    assert_eq!(get_block_subsidy(0), INITIAL_SUBSIDY);
+    assert_eq!(get_block_subsidy(HALVING_INTERVAL - 1), INITIAL_SUBSIDY);
+    assert_eq!(get_block_subsidy(HALVING_INTERVAL), INITIAL_SUBSIDY / 2);
+    assert_eq!(get_block_subsidy(HALVING_INTERVAL * 2), INITIAL_SUBSIDY / 4);
+    assert_eq!(get_block_subsidy(HALVING_INTERVAL * 3), INITIAL_SUBSIDY / 8);
+    assert_eq!(get_block_subsidy(HALVING_INTERVAL * 4), 0);
+    assert_eq!(get_block_subsidy(usize::MAX), 0);
+}
+
+#[test]
+fn test_coinbase_reward() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let miner = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let miner_hash = hash_pub_key(miner.get_public_key());
+
+    // the same miner address at two heights gets two different coinbases
+    let block1 = blockchain.mine_block(&miner.get_address(), &[]).unwrap();
+    let block2 = blockchain.mine_block(&miner.get_address(), &[]).unwrap();
+    let coinbase1 = &block1.get_transactions()[0];
+    let coinbase2 = &block2.get_transactions()[0];
+    assert!(coinbase1.is_coinbase());
+    assert_eq!(coinbase1.get_coinbase_height(), Some(1));
+    assert_eq!(coinbase2.get_coinbase_height(), Some(2));
+    assert_ne!(coinbase1.get_id(), coinbase2.get_id());
+    assert_eq!(
+        utxo_set.find_spendable_outputs(&miner_hash, 100).unwrap().0,
+        2 * INITIAL_SUBSIDY
+    );
+
+    assert!(matches!(
+        blockchain.mine_block(&miner.get_address(), std::slice::from_ref(coinbase1)),
+        Err(Error::InvalidTransaction(_))
+    ));
+
+    // alice pays 7 to bob, the fee is 3
+    let genesis = blockchain.iterator().nth(2).unwrap().unwrap();
+    let genesis_coinbase = &genesis.get_transactions()[0];
+    let input = TXInput::new(genesis_coinbase.get_id(), 0, alice.get_public_key());
+    let mut tx = Transaction::new(
+        vec![input],
+        vec![TXOutput::new(7, &bob.get_address()).unwrap()],
+    );
+    let prev_outputs = outputs_of(genesis_coinbase);
+    tx.sign(&alice, &prev_outputs).unwrap();
+    assert_eq!(tx.get_fee(&prev_outputs), Some(3));
+
+    let height = 3;
+    let timestamp = blockchain.get_median_time_past().unwrap() + 1;
+    let greedy = Transaction::new_coinbase_tx(&miner.get_address(), height, 4).unwrap();
+    let block = Block::new(
+        blockchain.get_tip_hash(),
+        &[greedy, tx.clone()],
+        height,
+        INITIAL_BITS,
+        timestamp,
+    );
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(
+            BlockValidationError::InvalidCoinbaseValue { max: 13, found: 14 }
+        ))
+    ));
+
+    let wrong_height = Transaction::new_coinbase_tx(&miner.get_address(), height + 1, 3).unwrap();
+    let block = Block::new(
+        blockchain.get_tip_hash(),
+        &[wrong_height.clone(), tx.clone()],
+        height,
+        INITIAL_BITS,
+        timestamp,
+    );
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(
+            BlockValidationError::InvalidCoinbaseHeight {
+                expected: 3,
+                found: Some(4)
+            }
+        ))
+    ));
+
+    let coinbase = Transaction::new_coinbase_tx(&miner.get_address(), height, 3).unwrap();
+    let block = Block::new(
+        blockchain.get_tip_hash(),
+        &[tx.clone(), coinbase],
+        height,
+        INITIAL_BITS,
+        timestamp,
+    );
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(BlockValidationError::CoinbaseNotFirst))
+    ));
+
+    let block = blockchain.mine_block(&miner.get_address(), &[tx]).unwrap();
+    assert_eq!(block.get_transactions()[0].get_output_value(), 13);
+    assert_eq!(
+        utxo_set.find_spendable_outputs(&miner_hash, 100).unwrap().0,
+        3 * INITIAL_SUBSIDY + 3
+    );
+}
+
+/// Sign a transaction spending output `vout` of `prev_tx` into the given outputs
+fn spend_into(
+    owner: &Wallet,
+    prev_tx: &Transaction,
+    vout: usize,
+    outputs: &[(i32, &str)],
+) -> Transaction {
+    let input = TXInput::new(prev_tx.get_id(), vout, owner.get_public_key());
+    let outputs = outputs
+        .iter()
+        .map(|(value, to)| TXOutput::new(*value, to).unwrap())
+        .collect();
+    let mut tx = Transaction::new(vec![input], outputs);
+    tx.sign(owner, &outputs_of(prev_tx)).unwrap();
+    tx
+}
+
+#[test]
+fn test_transaction_fee() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let miner = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let balance = |wallet: &Wallet| {
+        let pub_key_hash = hash_pub_key(wallet.get_public_key());
+        utxo_set
+            .find_spendable_outputs(&pub_key_hash, i32::MAX)
+            .unwrap()
+            .0
+    };
+
+    // a fixed fee is taken from the change and paid to the miner
+    let tx = Transaction::new_utxo_transaction_with_fee(
+        &alice,
+        &bob.get_address(),
+        4,
+        Fee::Fixed(2),
+        &utxo_set,
+    )
+    .unwrap();
+    let genesis = blockchain.iterator().next().unwrap().unwrap();
+    let genesis_coinbase = genesis.get_transactions()[0].clone();
+    assert_eq!(tx.get_fee(&outputs_of(&genesis_coinbase)), Some(2));
+    let block = blockchain.mine_block(&miner.get_address(), &[tx]).unwrap();
+    assert_eq!(
+        block.get_transactions()[0].get_output_value(),
+        INITIAL_SUBSIDY as i64 + 2
+    );
+    assert_eq!(balance(&alice), 4);
+    assert_eq!(balance(&bob), 4);
+    assert_eq!(balance(&miner), INITIAL_SUBSIDY + 2);
+
+    // a fee per byte is computed from the size of the signed transaction
+    let fee = Fee::PerByte(0.02);
+    let tx =
+        Transaction::new_utxo_transaction_with_fee(&miner, &alice.get_address(), 1, fee, &utxo_set)
+            .unwrap();
+    assert_eq!(tx.get_signed_size(), tx.get_size());
+    let miner_coinbase = block.get_transactions()[0].clone();
+    let prev_outputs = outputs_of(&miner_coinbase);
+    let paid = tx.get_fee(&prev_outputs).unwrap();
+    assert_eq!(paid, fee.get_value(tx.get_size()) as i64);
+    assert!(paid > 0);
+    assert!(tx.get_fee_rate(&prev_outputs).unwrap() >= 0.02);
+    blockchain.mine_block(&bob.get_address(), &[tx]).unwrap();
+    assert_eq!(balance(&alice), 5);
+    assert_eq!(balance(&miner), INITIAL_SUBSIDY + 2 - 1 - paid as i32);
+    assert_eq!(balance(&bob), 4 + INITIAL_SUBSIDY + paid as i32);
+
+    assert!(matches!(
+        Transaction::new_utxo_transaction_with_fee(
+            &alice,
+            &bob.get_address(),
+            5,
+            Fee::Fixed(1),
+            &utxo_set
+        ),
+        Err(Error::InsufficientFunds { .. })
+    ));
+}
+
+#[test]
+fn test_reject_invalid_values() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let mempool = Mempool::new();
+    let genesis = blockchain.iterator().next().unwrap().unwrap();
+    let genesis_coinbase = genesis.get_transactions()[0].clone();
+    let alice_address = alice.get_address();
+    let bob_address = bob.get_address();
+
+    let negative = spend_into(
+        &alice,
+        &genesis_coinbase,
+        0,
+        &[(-1, &bob_address), (11, &alice_address)],
+    );
+    let too_much = spend_into(&alice, &genesis_coinbase, 0, &[(11, &bob_address)]);
+    let no_inputs = Transaction::new(vec![], vec![TXOutput::new(0, &bob_address).unwrap()]);
+    let no_outputs = spend_into(&alice, &genesis_coinbase, 0, &[]);
+
+    let mine = |tx: &Transaction| {
+        let coinbase = Transaction::new_coinbase_tx(&bob_address, 1, 0).unwrap();
+        let timestamp = blockchain.get_median_time_past().unwrap() + 1;
+        let block = Block::new(
+            blockchain.get_tip_hash(),
+            &[coinbase, tx.clone()],
+            1,
+            INITIAL_BITS,
+            timestamp,
+        );
+        blockchain.add_block(&block)
+    };
+    assert!(matches!(
+        mine(&negative),
+        Err(Error::InvalidBlock(BlockValidationError::NegativeOutput { txid }))
+            if txid == negative.get_id()
+    ));
+    assert!(matches!(
+        mine(&too_much),
+        Err(Error::InvalidBlock(BlockValidationError::OutputsExceedInputs { txid }))
+            if txid == too_much.get_id()
+    ));
+    for tx in [&no_inputs, &no_outputs] {
+        assert!(matches!(
+            mine(tx),
+            Err(Error::InvalidBlock(BlockValidationError::EmptyInputsOrOutputs { txid }))
+                if txid == tx.get_id()
+        ));
+    }
+
+    for tx in [&negative, &too_much, &no_inputs, &no_outputs] {
+        assert!(matches!(
+            blockchain.mine_block(&bob_address, std::slice::from_ref(tx)),
+            Err(Error::InvalidTransaction(_))
+        ));
+    }
+    assert_eq!(blockchain.get_best_height().unwrap(), 0);
+
+    assert!(matches!(
+        mempool.add_transaction(&blockchain, negative),
+        Err(Error::RejectedTransaction(
+            MempoolError::NegativeOutput { .. }
+        ))
+    ));
+    assert!(matches!(
+        mempool.add_transaction(&blockchain, too_much),
+        Err(Error::RejectedTransaction(
+            MempoolError::OutputsExceedInputs { .. }
+        ))
+    ));
+    for tx in [no_inputs, no_outputs] {
+        assert!(matches!(
+            mempool.add_transaction(&blockchain, tx),
+            Err(Error::RejectedTransaction(
+                MempoolError::EmptyInputsOrOutputs { .. }
+            ))
+        ));
+    }
+    assert!(mempool.is_empty());
+}
+
+#[test]
+fn test_mempool_fee_rate_order() {
+    let alice = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let alice_address = alice.get_address();
+    blockchain.mine_block(&alice_address, &[]).unwrap();
+    let coinbases: Vec<Transaction> = blockchain
+        .iterator()
+        .map(|block| block.unwrap().get_transactions()[0].clone())
+        .collect();
+    let mempool = Mempool::new();
+
+    let low = spend_into(&alice, &coinbases[0], 0, &[(9, &alice_address)]);
+    let high = spend_into(&alice, &coinbases[1], 0, &[(5, &alice_address)]);
+    let child = spend_into(&alice, &low, 0, &[(1, &alice_address)]);
+    for tx in [&low, &high, &child] {
+        mempool.add_transaction(&blockchain, tx.clone()).unwrap();
+    }
+    assert_eq!(mempool.get_total_fee(), 1 + 5 + 8);
+    assert!(
+        mempool.get_fee_rate(&high.get_id()).unwrap()
+            > mempool.get_fee_rate(&low.get_id()).unwrap()
+    );
+
+    // the child pays the most, but it still waits for its parent
+    let template: Vec<Txid> = mempool
+        .get_block_template(10)
+        .iter()
+        .map(|tx| tx.get_id())
+        .collect();
+    assert_eq!(template, vec![high.get_id(), low.get_id(), child.get_id()]);
+    let template: Vec<Txid> = mempool
+        .get_block_template(1)
+        .iter()
+        .map(|tx| tx.get_id())
+        .collect();
+    assert_eq!(template, vec![high.get_id()]);
+
+    let block = blockchain
+        .mine_block(&alice_address, &mempool.get_block_template(10))
+        .unwrap();
+    assert_eq!(
+        block.get_transactions()[0].get_output_value(),
+        INITIAL_SUBSIDY as i64 + 14
+    );
+    mempool.remove_confirmed(&block);
+    assert!(mempool.is_empty());
+}
+
+#[test]
+fn test_coin_selectors() {
+    let outputs: Vec<SpendableOutput> = [5, 1, 8, 3, 0]
+        .iter()
+        .enumerate()
+        .map(|(i, value)| SpendableOutput::new(Txid::digest(&[i as u8]), 0, *value))
+        .collect();
+    let values = |outpoints: &[(Txid, usize)]| -> Vec<i32> {
+        outpoints
+            .iter()
+            .map(|outpoint| {
+                outputs
+                    .iter()
+                    .find(|out| (out.get_txid(), out.get_vout()) == *outpoint)
+                    .unwrap()
+                    .get_value()
+            })
+            .collect()
+    };
+
+    let selection = LargestFirst.select(&outputs, 10).unwrap();
+    assert_eq!(values(selection.get_outpoints()), vec![8, 5]);
+    assert_eq!((selection.get_total(), selection.get_change()), (13, 3));
+
+    let selection = SmallestFirst.select(&outputs, 6).unwrap();
+    assert_eq!(values(selection.get_outpoints()), vec![1, 3, 5]);
+    assert_eq!(selection.get_change(), 3);
+
+    // 8 + 1 is the only exact match for 9, 4 has none
+    let selection = BranchAndBound::default().select(&outputs, 9).unwrap();
+    assert_eq!(values(selection.get_outpoints()), vec![8, 1]);
+    assert_eq!(selection.get_change(), 0);
+    let selection = BranchAndBound::default().select(&outputs, 17).unwrap();
+    assert_eq!(values(selection.get_outpoints()), vec![8, 5, 3, 1]);
+    assert!(BranchAndBound::default().select(&outputs, 10).is_none());
+    assert!(BranchAndBound::new(1).select(&outputs, 9).is_none());
+
+    // the same seed gives the same order, any order covers the target
+    for seed in 0..20 {
+        let selection = RandomDraw::with_seed(seed).select(&outputs, 12).unwrap();
+        let selected = values(selection.get_outpoints());
+        assert_eq!(selection.get_total(), selected.iter().sum::<i32>());
+        assert_eq!(selection.get_change(), selection.get_total() - 12);
+        assert!(!selected.contains(&0));
+        assert_eq!(
+            RandomDraw::with_seed(seed).select(&outputs, 12),
+            Some(selection)
+        );
+    }
+    assert!(RandomDraw::new().unwrap().select(&outputs, 17).is_some());
+
+    for selector in [
+        &LargestFirst as &dyn CoinSelector,
+        &SmallestFirst,
+        &BranchAndBound::default(),
+        &RandomDraw::with_seed(1),
+    ] {
+        assert!(selector.select(&outputs, 18).is_none());
+        // a payment of 0 still takes an input, and nothing pays it without outputs
+        assert_eq!(
+            selector.select(&outputs, 0).unwrap().get_outpoints().len(),
+            1
+        );
+        assert!(selector.select(&[], 0).is_none());
+    }
+}
 
-        println!("\n==========utxo transaction=================\n");
-        let transaction = Transaction::new_utxo_transactions(
-            "hegtsodoucahjsubxg",
-            "abxgtsunkodojahucd",
-            8,
+#[test]
+fn test_utxo_transaction_with_selector() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let alice_hash = hash_pub_key(alice.get_public_key());
+
+    // alice splits the genesis reward into 6 + 4, and gets another 10 from mining
+    let genesis = blockchain.iterator().next().unwrap().unwrap();
+    let split = spend_into(
+        &alice,
+        &genesis.get_transactions()[0],
+        0,
+        &[(6, &alice.get_address()), (4, &alice.get_address())],
+    );
+    blockchain
+        .mine_block(&alice.get_address(), &[split])
+        .unwrap();
+    let mut values: Vec<i32> = utxo_set
+        .get_spendable_outputs(&alice_hash)
+        .unwrap()
+        .iter()
+        .map(|out| out.get_value())
+        .collect();
+    values.sort();
+    assert_eq!(values, vec![4, 6, 10]);
+
+    let tx = Transaction::new_utxo_transaction_with_selector(
+        &alice,
+        &bob.get_address(),
+        13,
+        Fee::Fixed(1),
+        &BranchAndBound::default(),
+        &utxo_set,
+    )
+    .unwrap();
+    assert_eq!(tx.get_vin().len(), 2);
+    assert_eq!(tx.get_vout().len(), 1);
+
+    let tx = Transaction::new_utxo_transaction_with_selector(
+        &alice,
+        &bob.get_address(),
+        3,
+        Fee::Fixed(0),
+        &SmallestFirst,
+        &utxo_set,
+    )
+    .unwrap();
+    assert_eq!(tx.get_vin().len(), 1);
+    assert_eq!(tx.get_vout()[1].get_value(), 1);
+
+    let tx = Transaction::new_utxo_transaction_with_fee(
+        &alice,
+        &bob.get_address(),
+        3,
+        Fee::Fixed(0),
+        &utxo_set,
+    )
+    .unwrap();
+    assert_eq!(tx.get_vout()[1].get_value(), 7);
+
+    // sending 0 without a fee still spends an output, a wallet without outputs can not
+    let tx = Transaction::new_utxo_transaction_with_fee(
+        &alice,
+        &bob.get_address(),
+        0,
+        Fee::Fixed(0),
+        &utxo_set,
+    )
+    .unwrap();
+    assert_eq!(tx.get_vin().len(), 1);
+    assert!(matches!(
+        Transaction::new_utxo_transaction_with_fee(
+            &bob,
+            &alice.get_address(),
+            0,
+            Fee::Fixed(0),
             &utxo_set,
+        ),
+        Err(Error::InvalidTransaction(_))
+    ));
+
+    assert!(matches!(
+        utxo_set.select_coins(&alice_hash, 12, &BranchAndBound::default()),
+        Err(Error::InvalidTransaction(_))
+    ));
+    assert!(matches!(
+        utxo_set.select_coins(&alice_hash, 21, &LargestFirst),
+        Err(Error::InsufficientFunds {
+            needed: 21,
+            available: 20
+        })
+    ));
+    let selection = utxo_set
+        .select_coins(&alice_hash, 20, &LargestFirst)
+        .unwrap();
+    assert_eq!(selection.get_outpoints().len(), 3);
+    blockchain.mine_block(&alice.get_address(), &[tx]).unwrap();
+}
+
+#[test]
+fn test_address_index() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let alice_hash = hash_pub_key(alice.get_public_key());
+    let bob_hash = hash_pub_key(bob.get_public_key());
+
+    blockchain.mine_block(&bob.get_address(), &[]).unwrap();
+    let tx = Transaction::new_utxo_transactions(&alice, &bob.get_address(), 4, &utxo_set).unwrap();
+    blockchain
+        .mine_block(&alice.get_address(), std::slice::from_ref(&tx))
+        .unwrap();
+    blockchain.mine_block(&bob.get_address(), &[]).unwrap();
+    let best_height = blockchain.get_best_height().unwrap();
+    assert_eq!(best_height, 3);
+
+    assert_eq!(utxo_set.get_balance(&alice_hash).unwrap(), 16);
+    assert_eq!(utxo_set.get_balance(&bob_hash).unwrap(), 24);
+    assert_eq!(
+        utxo_set
+            .get_balance(&hash_pub_key(Wallet::new().unwrap().get_public_key()))
+            .unwrap(),
+        0
+    );
+
+    // the genesis output is spent, the change and the reward are confirmed at height 2
+    let alice_outputs = utxo_set.list_unspent(&alice_hash).unwrap();
+    assert_eq!(alice_outputs.len(), 2);
+    for out in &alice_outputs {
+        assert_eq!(out.get_height(), 2);
+        assert_eq!(out.get_confirmations(best_height), 2);
+        let utxo = utxo_set
+            .get_output(&out.get_txid(), out.get_vout())
+            .unwrap()
+            .unwrap();
+        assert_eq!(utxo.get_value(), out.get_value());
+        assert!(utxo.is_locked_with_key(&alice_hash));
+    }
+    let change = alice_outputs
+        .iter()
+        .find(|out| out.get_txid() == tx.get_id())
+        .unwrap();
+    assert_eq!((change.get_vout(), change.get_value()), (1, 6));
+
+    let mut bob_heights: Vec<usize> = utxo_set
+        .list_unspent(&bob_hash)
+        .unwrap()
+        .iter()
+        .map(|out| out.get_height())
+        .collect();
+    bob_heights.sort();
+    assert_eq!(bob_heights, vec![1, 2, 3]);
+
+    // reindex and the migration from schema version 2 build the same index
+    let bob_outputs = utxo_set.list_unspent(&bob_hash).unwrap();
+    utxo_set.reindex().unwrap();
+    assert_eq!(utxo_set.list_unspent(&alice_hash).unwrap(), alice_outputs);
+    assert_eq!(utxo_set.list_unspent(&bob_hash).unwrap(), bob_outputs);
+
+    let db = blockchain.get_db();
+    db.open_tree(UTXO_ADDRESS_TREE).unwrap().clear().unwrap();
+    db.open_tree(UTXO_META_TREE)
+        .unwrap()
+        .insert(UTXO_SCHEMA_VERSION_KEY, &2u32.to_be_bytes())
+        .unwrap();
+    assert!(utxo_set.list_unspent(&alice_hash).unwrap().is_empty());
+    assert!(utxo_set.migrate().unwrap());
+    assert!(!utxo_set.migrate().unwrap());
+    assert_eq!(utxo_set.list_unspent(&alice_hash).unwrap(), alice_outputs);
+    assert_eq!(utxo_set.get_balance(&bob_hash).unwrap(), 24);
+}
+
+#[test]
+fn test_address() {
+    let wallet = Wallet::new().unwrap();
+    let text = wallet.get_address();
+    let address: Address = text.parse().unwrap();
+    assert_eq!(address.get_network(), Network::Main);
+    assert_eq!(
+        address.get_pub_key_hash(),
+        hash_pub_key(wallet.get_public_key())
+    );
+    assert_eq!(address.to_string(), text);
+    assert_eq!(
+        Address::from_pub_key(Network::Main, wallet.get_public_key()),
+        address
+    );
+    assert!(validate_address(&text).is_ok());
+
+    let invalid = |text: &str| match validate_address(text) {
+        Err(Error::InvalidAddress(err)) => err,
+        other => panic!("{} should be invalid, got {:?}", text, other),
+    };
+
+    // change one character, the checksum catches it
+    let mut chars: Vec<char> = text.chars().collect();
+    let pos = chars.len() / 2;
+    chars[pos] = if chars[pos] == '2' { '3' } else { '2' };
+    let typo: String = chars.into_iter().collect();
+    assert_eq!(invalid(&typo), AddressError::InvalidChecksum);
+    assert_eq!(invalid("0OIl"), AddressError::InvalidBase58);
+    assert!(matches!(
+        invalid(&text[..text.len() - 2]),
+        AddressError::InvalidLength { .. }
+    ));
+
+    // a correct checksum over an unknown version byte
+    let mut payload = vec![0x05];
+    payload.extend([7u8; PUB_KEY_HASH_LENGTH]);
+    payload.extend(crate::address::checksum(&payload));
+    let unknown = bs58::encode(&payload).into_string();
+    assert_eq!(
+        invalid(&unknown),
+        AddressError::UnknownVersion { version: 0x05 }
+    );
+
+    // a test network address parses, but it can not be used in our chain
+    let test_address = Address::new(Network::Test, [7u8; PUB_KEY_HASH_LENGTH]).to_string();
+    let parsed: Address = test_address.parse().unwrap();
+    assert_eq!(parsed.get_network(), Network::Test);
+    assert_eq!(
+        invalid(&test_address),
+        AddressError::WrongNetwork {
+            expected: Network::Main,
+            found: Network::Test
+        }
+    );
+    assert!(matches!(
+        TXOutput::new(1, &test_address),
+        Err(Error::InvalidAddress(AddressError::WrongNetwork { .. }))
+    ));
+    assert!(matches!(
+        Transaction::new_coinbase_tx(&typo, 0, 0),
+        Err(Error::InvalidAddress(AddressError::InvalidChecksum))
+    ));
+}
+
+/// Mine a block on `parent`, which does not have to be the tip.
+/// The coinbase pays the subsidy plus `fees` to `miner`.
+fn mine_on(parent: &Block, miner: &str, fees: i32, transactions: &[Transaction]) -> Block {
+    let height = parent.get_height() + 1;
+    let mut block_txs = vec![Transaction::new_coinbase_tx(miner, height, fees).unwrap()];
+    block_txs.extend_from_slice(transactions);
+    Block::new(
+        parent.get_hash(),
+        &block_txs,
+        height,
+        INITIAL_BITS,
+        parent.get_timestamp() + 1,
+    )
+}
+
+fn block_hashes(blocks: &[Block]) -> Vec<BlockHash> {
+    blocks.iter().map(|block| block.get_hash()).collect()
+}
+
+#[test]
+fn test_reorganize() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let carol = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let balance = |wallet: &Wallet| {
+        utxo_set
+            .get_balance(&hash_pub_key(wallet.get_public_key()))
+            .unwrap()
+    };
+    let genesis = blockchain.iterator().next().unwrap().unwrap();
+
+    // the best chain: genesis <- a1, where alice pays bob
+    let tx = spend_output(
+        &alice,
+        &genesis.get_transactions()[0],
+        0,
+        &bob.get_address(),
+    );
+    let a1 = blockchain
+        .mine_block(&alice.get_address(), std::slice::from_ref(&tx))
+        .unwrap();
+
+    // b1 has the same work as a1, the chain seen first stays the best
+    let b1 = mine_on(&genesis, &carol.get_address(), 0, &[]);
+    assert!(matches!(
+        blockchain.add_block(&b1),
+        Ok(BlockStatus::SideChain)
+    ));
+    assert!(matches!(
+        blockchain.add_block(&b1),
+        Ok(BlockStatus::AlreadyKnown)
+    ));
+    assert_eq!(blockchain.get_tip_hash(), a1.get_hash());
+    assert_eq!(
+        blockchain.get_chain_work(&b1.get_hash()).unwrap(),
+        blockchain.get_chain_work(&a1.get_hash()).unwrap()
+    );
+    assert_eq!((balance(&bob), balance(&carol)), (10, 0));
+
+    // b2 gives the side chain more work, a1 is disconnected and alice's payment undone
+    let b2 = mine_on(&b1, &carol.get_address(), 0, &[]);
+    match blockchain.add_block(&b2).unwrap() {
+        BlockStatus::Reorganized {
+            disconnected,
+            connected,
+        } => {
+            assert_eq!(block_hashes(&disconnected), vec![a1.get_hash()]);
+            assert_eq!(block_hashes(&connected), vec![b1.get_hash(), b2.get_hash()]);
+        }
+        status => panic!("expected a reorg, got {:?}", status),
+    }
+    assert_eq!(blockchain.get_tip_hash(), b2.get_hash());
+    assert_eq!(blockchain.get_best_height().unwrap(), 2);
+    assert_eq!(
+        (balance(&alice), balance(&bob), balance(&carol)),
+        (10, 0, 20)
+    );
+    assert!(blockchain.find_transaction(&tx.get_id()).unwrap().is_none());
+
+    // the old chain comes back with more work
+    let a2 = mine_on(&a1, &alice.get_address(), 0, &[]);
+    assert!(matches!(
+        blockchain.add_block(&a2),
+        Ok(BlockStatus::SideChain)
+    ));
+    let a3 = mine_on(&a2, &alice.get_address(), 0, &[]);
+    match blockchain.add_block(&a3).unwrap() {
+        BlockStatus::Reorganized {
+            disconnected,
+            connected,
+        } => {
+            assert_eq!(
+                block_hashes(&disconnected),
+                vec![b2.get_hash(), b1.get_hash()]
+            );
+            assert_eq!(
+                block_hashes(&connected),
+                vec![a1.get_hash(), a2.get_hash(), a3.get_hash()]
+            );
+        }
+        status => panic!("expected a reorg, got {:?}", status),
+    }
+    assert_eq!(
+        (balance(&alice), balance(&bob), balance(&carol)),
+        (30, 10, 0)
+    );
+    let a4 = blockchain.mine_block(&bob.get_address(), &[]).unwrap();
+    assert_eq!(a4.get_pre_block_hash(), a3.get_hash());
+
+    let alice_outputs = utxo_set
+        .list_unspent(&hash_pub_key(alice.get_public_key()))
+        .unwrap();
+    utxo_set.reindex().unwrap();
+    assert_eq!(
+        utxo_set
+            .list_unspent(&hash_pub_key(alice.get_public_key()))
+            .unwrap(),
+        alice_outputs
+    );
+
+    // the work of blocks stored without it is computed again
+    let work = 1u128 << INITIAL_BITS;
+    assert_eq!(blockchain.get_chain_work(&a4.get_hash()).unwrap(), 5 * work);
+    blockchain
+        .get_db()
+        .open_tree(CHAIN_WORK_TREE_NAME)
+        .unwrap()
+        .clear()
+        .unwrap();
+    assert_eq!(blockchain.get_chain_work(&b2.get_hash()).unwrap(), 3 * work);
+    assert_eq!(blockchain.get_chain_work(&a4.get_hash()).unwrap(), 5 * work);
+}
+
+#[test]
+fn test_mempool_reorganize() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let carol = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let mempool = Mempool::new();
+    let genesis = blockchain.iterator().next().unwrap().unwrap();
+
+    // a1 confirms alice's payment to bob, and pays its reward to alice
+    let payment = spend_output(
+        &alice,
+        &genesis.get_transactions()[0],
+        0,
+        &bob.get_address(),
+    );
+    let a1 = blockchain
+        .mine_block(&alice.get_address(), std::slice::from_ref(&payment))
+        .unwrap();
+    // bob spends the payment, alice spends the reward of a1, carol spends alice's transaction
+    let from_payment = spend_output(&bob, &payment, 0, &carol.get_address());
+    let from_reward = spend_output(&alice, &a1.get_transactions()[0], 0, &carol.get_address());
+    let child = spend_output(&carol, &from_reward, 0, &bob.get_address());
+    for tx in [&from_payment, &from_reward, &child] {
+        mempool.add_transaction(&blockchain, tx.clone()).unwrap();
+    }
+
+    let b1 = mine_on(&genesis, &carol.get_address(), 0, &[]);
+    blockchain.add_block(&b1).unwrap();
+    let b2 = mine_on(&b1, &carol.get_address(), 0, &[]);
+    let (disconnected, connected) = match blockchain.add_block(&b2).unwrap() {
+        BlockStatus::Reorganized {
+            disconnected,
+            connected,
+        } => (disconnected, connected),
+        status => panic!("expected a reorg, got {:?}", status),
+    };
+
+    // the reward of a1 is gone with a1, the payment is pending again
+    let mut removed = mempool
+        .reorganize(&blockchain, &disconnected, &connected)
+        .unwrap();
+    removed.sort();
+    let mut expected = vec![from_reward.get_id(), child.get_id()];
+    expected.sort();
+    assert_eq!(removed, expected);
+    assert_eq!(mempool.len(), 2);
+    assert!(mempool.contains(&payment.get_id()));
+    assert_eq!(
+        mempool.get_depends(&from_payment.get_id()).unwrap(),
+        vec![payment.get_id()]
+    );
+    let template: Vec<Txid> = mempool
+        .get_block_template(10)
+        .iter()
+        .map(|tx| tx.get_id())
+        .collect();
+    assert_eq!(template, vec![payment.get_id(), from_payment.get_id()]);
+}
+
+#[test]
+fn test_mempool_reorganize_block_without_coinbase() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let mempool = Mempool::new();
+    let genesis = blockchain.iterator().next().unwrap().unwrap();
+
+    // a1 has no coinbase, its only transaction is alice's payment to bob
+    let payment = spend_output(
+        &alice,
+        &genesis.get_transactions()[0],
+        0,
+        &bob.get_address(),
+    );
+    let a1 = Block::new(
+        genesis.get_hash(),
+        std::slice::from_ref(&payment),
+        1,
+        INITIAL_BITS,
+        genesis.get_timestamp() + 1,
+    );
+    blockchain.add_block(&a1).unwrap();
+
+    let b1 = mine_on(&genesis, &bob.get_address(), 0, &[]);
+    blockchain.add_block(&b1).unwrap();
+    let b2 = mine_on(&b1, &bob.get_address(), 0, &[]);
+    let (disconnected, connected) = match blockchain.add_block(&b2).unwrap() {
+        BlockStatus::Reorganized {
+            disconnected,
+            connected,
+        } => (disconnected, connected),
+        status => panic!("expected a reorg, got {:?}", status),
+    };
+
+    // the first transaction of a1 is not a coinbase, so it is pending again
+    let removed = mempool
+        .reorganize(&blockchain, &disconnected, &connected)
+        .unwrap();
+    assert!(removed.is_empty());
+    assert_eq!(mempool.len(), 1);
+    assert!(mempool.contains(&payment.get_id()));
+}
+
+#[test]
+fn test_reject_invalid_branch() {
+    let alice = Wallet::new().unwrap();
+    let carol = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let alice_hash = hash_pub_key(alice.get_public_key());
+    let genesis = blockchain.iterator().next().unwrap().unwrap();
+    let a1 = blockchain.mine_block(&alice.get_address(), &[]).unwrap();
+
+    // a side block is checked against its parent
+    let coinbase = Transaction::new_coinbase_tx(&carol.get_address(), 2, 0).unwrap();
+    let block = Block::new(
+        genesis.get_hash(),
+        &[coinbase],
+        2,
+        INITIAL_BITS,
+        genesis.get_timestamp() + 1,
+    );
+    assert!(matches!(
+        blockchain.add_block(&block),
+        Err(Error::InvalidBlock(BlockValidationError::InvalidHeight {
+            expected: 1,
+            found: 2
+        }))
+    ));
+
+    // c2 pays too much, which is only found when the reorg connects it
+    let c1 = mine_on(&genesis, &carol.get_address(), 0, &[]);
+    let c2 = mine_on(&c1, &carol.get_address(), 5, &[]);
+    assert!(matches!(
+        blockchain.add_block(&c1),
+        Ok(BlockStatus::SideChain)
+    ));
+    assert!(matches!(
+        blockchain.add_block(&c2),
+        Err(Error::InvalidBlock(
+            BlockValidationError::InvalidCoinbaseValue { .. }
+        ))
+    ));
+    assert_eq!(blockchain.get_tip_hash(), a1.get_hash());
+    assert_eq!(utxo_set.get_balance(&alice_hash).unwrap(), 20);
+    assert_eq!(
+        utxo_set
+            .get_balance(&hash_pub_key(carol.get_public_key()))
+            .unwrap(),
+        0
+    );
+    assert!(blockchain.is_invalid_block(&c2.get_hash()).unwrap());
+    assert!(!blockchain.is_invalid_block(&c1.get_hash()).unwrap());
+
+    // neither c2 nor anything built on it is accepted again
+    let c3 = mine_on(&c2, &carol.get_address(), 0, &[]);
+    for block in [&c2, &c3] {
+        assert!(matches!(
+            blockchain.add_block(block),
+            Err(Error::InvalidBlock(BlockValidationError::InvalidParent { hash }))
+                if hash == c2.get_hash()
+        ));
+    }
+
+    // the valid part of the branch can still win
+    let d2 = mine_on(&c1, &carol.get_address(), 0, &[]);
+    assert!(matches!(
+        blockchain.add_block(&d2),
+        Ok(BlockStatus::Reorganized { .. })
+    ));
+    assert_eq!(blockchain.get_tip_hash(), d2.get_hash());
+    assert_eq!(utxo_set.get_balance(&alice_hash).unwrap(), 10);
+}
+
+/// Everything in the UTXO set and the address index
+fn chainstate(blockchain: &BlockChain) -> Vec<(sled::IVec, sled::IVec)> {
+    let db = blockchain.get_db();
+    let mut items = vec![];
+    for name in [UTXO_TREE, UTXO_ADDRESS_TREE] {
+        for item in db.open_tree(name).unwrap().iter() {
+            items.push(item.unwrap());
+        }
+    }
+    items
+}
+
+#[test]
+fn test_disconnect_with_undo() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let carol = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let utxo_set = UtxoSet::new(&blockchain);
+    let genesis = blockchain.iterator().next().unwrap().unwrap();
+    let state0 = chainstate(&blockchain);
+
+    // block 1 has a parent and a child, the child spends an output created in the same block
+    let parent = spend_output(
+        &alice,
+        &genesis.get_transactions()[0],
+        0,
+        &bob.get_address(),
+    );
+    let child = spend_output(&bob, &parent, 0, &carol.get_address());
+    let block1 = blockchain
+        .mine_block(&bob.get_address(), &[parent, child])
+        .unwrap();
+    let state1 = chainstate(&blockchain);
+    let block2 = blockchain.mine_block(&carol.get_address(), &[]).unwrap();
+    assert!(utxo_set.has_undo(&block2.get_hash()).unwrap());
+
+    let disconnected = blockchain.disconnect_tip().unwrap().unwrap();
+    assert_eq!(disconnected.get_hash(), block2.get_hash());
+    assert_eq!(blockchain.get_tip_hash(), block1.get_hash());
+    assert_eq!(chainstate(&blockchain), state1);
+    assert!(!utxo_set.has_undo(&block2.get_hash()).unwrap());
+
+    blockchain.disconnect_tip().unwrap().unwrap();
+    assert_eq!(blockchain.get_tip_hash(), genesis.get_hash());
+    assert_eq!(chainstate(&blockchain), state0);
+    assert_eq!(
+        utxo_set
+            .get_balance(&hash_pub_key(alice.get_public_key()))
+            .unwrap(),
+        10
+    );
+    assert!(blockchain.disconnect_tip().unwrap().is_none());
+
+    // the stored blocks are connected again when they are added again
+    assert!(matches!(
+        blockchain.add_block(&block1),
+        Ok(BlockStatus::Reorganized { .. })
+    ));
+    assert!(matches!(
+        blockchain.add_block(&block2),
+        Ok(BlockStatus::Reorganized { .. })
+    ));
+    assert!(matches!(
+        blockchain.add_block(&block2),
+        Ok(BlockStatus::AlreadyKnown)
+    ));
+    assert_eq!(blockchain.get_tip_hash(), block2.get_hash());
+    let state2 = chainstate(&blockchain);
+
+    // without an undo record, e.g. a block connected by an older version,
+    // the UTXO set is rebuilt from the chain
+    blockchain
+        .get_db()
+        .open_tree(UTXO_UNDO_TREE)
+        .unwrap()
+        .remove(block2.get_hash())
+        .unwrap();
+    blockchain.disconnect_tip().unwrap().unwrap();
+    assert_eq!(chainstate(&blockchain), state1);
+    blockchain.add_block(&block2).unwrap();
+    assert_eq!(chainstate(&blockchain), state2);
+
+    // a corrupted undo record is reported, and nothing is changed
+    blockchain
+        .get_db()
+        .open_tree(UTXO_UNDO_TREE)
+        .unwrap()
+        .insert(block2.get_hash(), b"garbage".to_vec())
+        .unwrap();
+    assert!(matches!(
+        blockchain.disconnect_tip(),
+        Err(Error::Corruption(_))
+    ));
+    assert_eq!(blockchain.get_tip_hash(), block2.get_hash());
+    assert_eq!(chainstate(&blockchain), state2);
+}
+
+#[test]
+fn test_network_message_framing() {
+    let address = Wallet::new().unwrap().get_address();
+    let blockchain = BlockChain::create_temporary(&address).unwrap();
+    let block = blockchain.mine_block(&address, &[]).unwrap();
+
+    let mut data = vec![];
+    write_message(&mut data, &Message::Ping(7)).unwrap();
+    write_message(&mut data, &Message::Block(block.clone())).unwrap();
+    let mut reader = data.as_slice();
+    assert!(matches!(read_message(&mut reader), Ok(Message::Ping(7))));
+    match read_message(&mut reader) {
+        Ok(Message::Block(received)) => assert_eq!(received.get_hash(), block.get_hash()),
+        other => panic!("expected a block, found {:?}", other),
+    }
+    // the stream is at its end
+    assert!(matches!(read_message(&mut reader), Err(Error::Io(_))));
+
+    let frame = Message::Pong(7).encode().unwrap();
+    let mut bad_magic = frame.clone();
+    bad_magic[0] ^= 0xff;
+    assert!(matches!(
+        read_message(&mut bad_magic.as_slice()),
+        Err(Error::Network(NetworkError::InvalidMagic))
+    ));
+    let mut bad_checksum = frame.clone();
+    *bad_checksum.last_mut().unwrap() ^= 0xff;
+    assert!(matches!(
+        read_message(&mut bad_checksum.as_slice()),
+        Err(Error::Network(NetworkError::InvalidChecksum))
+    ));
+    // the length is refused before the payload is read
+    let mut too_large = frame[..FRAME_HEADER_LENGTH].to_vec();
+    too_large[4..8].copy_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes());
+    assert!(matches!(
+        read_message(&mut too_large.as_slice()),
+        Err(Error::Network(NetworkError::MessageTooLarge { .. }))
+    ));
+    let truncated = &frame[..frame.len() - 1];
+    assert!(matches!(
+        read_message(&mut &truncated[..]),
+        Err(Error::Io(_))
+    ));
+}
+
+#[test]
+fn test_block_locator() {
+    let address = Wallet::new().unwrap().get_address();
+    let blockchain = BlockChain::create_temporary(&address).unwrap();
+    let mut hashes = vec![blockchain.get_tip_hash()];
+    for _ in 0..30 {
+        hashes.push(blockchain.mine_block(&address, &[]).unwrap().get_hash());
+    }
+
+    // 10 consecutive hashes from the tip, then the gaps double, and the genesis block last
+    let locator = blockchain.get_block_locator().unwrap();
+    let heights: Vec<usize> = locator
+        .iter()
+        .map(|hash| hashes.iter().position(|h| h == hash).unwrap())
+        .collect();
+    assert_eq!(
+        heights,
+        vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]
+    );
+    assert_eq!(blockchain.get_genesis_hash().unwrap(), hashes[0]);
+
+    let after = |locator: &[BlockHash], stop: &BlockHash, max_count: usize| {
+        blockchain
+            .get_block_hashes_after(locator, stop, max_count)
+            .unwrap()
+    };
+    let none = BlockHash::default();
+    assert_eq!(after(&[hashes[25]], &none, MAX_INV_SIZE), hashes[26..]);
+    // the first locator hash on the chain is the fork, unknown hashes are skipped
+    assert_eq!(
+        after(
+            &[BlockHash::new([7; 32]), hashes[28], hashes[3]],
+            &none,
+            MAX_INV_SIZE
+        ),
+        hashes[29..]
+    );
+    assert_eq!(
+        after(&[hashes[25]], &hashes[27], MAX_INV_SIZE),
+        hashes[26..28]
+    );
+    assert_eq!(after(&[hashes[25]], &none, 2), hashes[26..28]);
+    // without a common block, start after the genesis block
+    assert_eq!(after(&[], &none, MAX_INV_SIZE), hashes[1..]);
+    assert!(after(&[hashes[30]], &none, MAX_INV_SIZE).is_empty());
+}
+
+fn test_node_config() -> NodeConfig {
+    NodeConfig {
+        connect_timeout: Duration::from_secs(5),
+        handshake_timeout: Duration::from_secs(5),
+        ping_interval: Duration::from_millis(100),
+        ping_timeout: Duration::from_secs(5),
+        headers_timeout: Duration::from_secs(2),
+        block_timeout: Duration::from_secs(2),
+        max_queued_headers: MAX_QUEUED_HEADERS,
+        miner: MinerConfig {
+            threads: 2,
+            max_nonce: i64::MAX,
+        },
+        max_inbound_peers: MAX_INBOUND_PEERS,
+        events: None,
+    }
+}
+
+/// A node on a free localhost port.
+/// Nodes with the same genesis address and clock have the same genesis block.
+fn start_node(genesis_address: &str, clock: &Arc<MockClock>) -> Node {
+    let blockchain =
+        BlockChain::create_temporary_with_clock(genesis_address, clock.clone()).unwrap();
+    Node::start_with_config(
+        Arc::new(blockchain),
+        Arc::new(Mempool::new()),
+        "127.0.0.1:0",
+        test_node_config(),
+    )
+    .unwrap()
+}
+
+/// Poll until `condition` holds, the nodes work in their own threads
+fn wait_until(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    condition()
+}
+
+#[test]
+fn test_node_relay() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    // a line of nodes: a - b - c, a and c only hear of each other through b
+    let node_a = start_node(&alice.get_address(), &clock);
+    let node_b = start_node(&alice.get_address(), &clock);
+    let node_c = start_node(&alice.get_address(), &clock);
+    node_a.connect(node_b.get_local_addr()).unwrap();
+    node_c.connect(node_b.get_local_addr()).unwrap();
+    assert!(wait_until(|| node_b.get_peer_count() == 2));
+    assert_eq!(node_a.get_peer_count(), 1);
+    assert!(node_b.get_peers().iter().all(|peer| peer.is_inbound()));
+
+    // a block mined by a reaches c
+    clock.advance(TARGET_BLOCK_INTERVAL);
+    let (block, stats) = node_a.mine_block(&alice.get_address()).unwrap();
+    assert!(stats.get_hashes() > 0);
+    assert!(wait_until(
+        || node_c.get_blockchain().get_tip_hash() == block.get_hash()
+    ));
+    assert_eq!(node_b.get_blockchain().get_tip_hash(), block.get_hash());
+
+    // a transaction sent to c reaches the mempool of a, which mines it
+    let utxo_set = UtxoSet::new(node_c.get_blockchain());
+    let tx = Transaction::new_utxo_transactions(&alice, &bob.get_address(), 3, &utxo_set).unwrap();
+    let txid = node_c.submit_transaction(tx).unwrap();
+    assert!(wait_until(|| node_a.get_mempool().contains(&txid)));
+    clock.advance(TARGET_BLOCK_INTERVAL);
+    let (block, _) = node_a.mine_block(&alice.get_address()).unwrap();
+    assert_eq!(block.get_transactions()[1].get_id(), txid);
+    assert!(node_a.get_mempool().is_empty());
+    assert!(wait_until(
+        || node_c.get_blockchain().get_tip_hash() == block.get_hash()
+    ));
+    assert!(wait_until(
+        || node_b.get_mempool().is_empty() && node_c.get_mempool().is_empty()
+    ));
+    let bob_balance = UtxoSet::new(node_c.get_blockchain())
+        .get_balance(&hash_pub_key(bob.get_public_key()))
+        .unwrap();
+    assert_eq!(bob_balance, 3);
+
+    // c learned the address of a from b
+    assert!(wait_until(|| node_c
+        .get_known_addrs()
+        .contains(&node_a.get_local_addr())));
+}
+
+#[test]
+fn test_node_sync_and_reorg() {
+    let alice = Wallet::new().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let node_a = start_node(&alice.get_address(), &clock);
+    let node_b = start_node(&alice.get_address(), &clock);
+
+    // the nodes mine apart from each other, b has the longer chain
+    for _ in 0..2 {
+        node_a.mine_block(&alice.get_address()).unwrap();
+    }
+    for _ in 0..5 {
+        node_b.mine_block(&alice.get_address()).unwrap();
+    }
+    let tip_b = node_b.get_blockchain().get_tip_hash();
+
+    // a asks b for the blocks after the fork in the handshake, and switches to b's chain
+    node_a.connect(node_b.get_local_addr()).unwrap();
+    assert!(wait_until(
+        || node_a.get_blockchain().get_tip_hash() == tip_b
+    ));
+    assert_eq!(node_a.get_blockchain().get_best_height().unwrap(), 5);
+
+    // a block mined on top of b's chain by a is connected by b
+    let (block, _) = node_a.mine_block(&alice.get_address()).unwrap();
+    assert!(wait_until(
+        || node_b.get_blockchain().get_tip_hash() == block.get_hash()
+    ));
+
+    // a node which connects later syncs the whole chain
+    let node_c = start_node(&alice.get_address(), &clock);
+    node_c.connect(node_a.get_local_addr()).unwrap();
+    assert!(wait_until(
+        || node_c.get_blockchain().get_tip_hash() == block.get_hash()
+    ));
+}
+
+#[test]
+fn test_node_refuses_peers() {
+    let alice = Wallet::new().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let node_a = start_node(&alice.get_address(), &clock);
+    let node_b = start_node(&alice.get_address(), &clock);
+
+    // another genesis block is another chain
+    let node_other = start_node(&Wallet::new().unwrap().get_address(), &clock);
+    assert!(matches!(
+        node_other.connect(node_a.get_local_addr()),
+        Err(Error::Network(NetworkError::GenesisMismatch { .. }))
+    ));
+    assert!(matches!(
+        node_a.connect(node_a.get_local_addr()),
+        Err(Error::Network(NetworkError::SelfConnection))
+    ));
+    assert!(wait_until(|| node_a.get_peer_count() == 0));
+
+    // a connection which does not speak the protocol is closed
+    let mut stream = TcpStream::connect(node_a.get_local_addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    // the node sends its version, then closes the connection, which may also be a reset
+    let mut buf = vec![];
+    if let Err(err) = stream.read_to_end(&mut buf) {
+        assert!(!matches!(
+            err.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+    }
+    assert_eq!(node_a.get_peer_count(), 0);
+
+    // peers ping each other, and a closed peer is removed on both sides
+    let id = node_a.connect(node_b.get_local_addr()).unwrap();
+    assert!(wait_until(|| node_a
+        .get_peers()
+        .iter()
+        .all(|peer| peer.get_ping_time().is_some())));
+    assert!(node_a.disconnect_peer(id));
+    assert!(wait_until(
+        || node_a.get_peer_count() == 0 && node_b.get_peer_count() == 0
+    ));
+    assert!(!node_a.disconnect_peer(id));
+}
+
+#[test]
+fn test_node_inbound_limit() {
+    let alice = Wallet::new().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let blockchain =
+        BlockChain::create_temporary_with_clock(&alice.get_address(), clock.clone()).unwrap();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let config = NodeConfig {
+        max_inbound_peers: 1,
+        events: Some(sender),
+        ..test_node_config()
+    };
+    let node = Node::start_with_config(
+        Arc::new(blockchain),
+        Arc::new(Mempool::new()),
+        "127.0.0.1:0",
+        config,
+    )
+    .unwrap();
+    // the events of the node, until one matches
+    let wait_for_event = |matches: &dyn Fn(&NodeEvent) -> bool| loop {
+        let event = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        if matches(&event) {
+            return event;
+        }
+    };
+
+    let first = connect_fake_peer(&node, 0, 0);
+    wait_for_event(&|event| matches!(event, NodeEvent::PeerConnected { inbound: true, .. }));
+
+    // the second connection is not accepted while the first one is connected
+    let mut second = TcpStream::connect(node.get_local_addr()).unwrap();
+    let genesis_hash = node.get_blockchain().get_genesis_hash().unwrap();
+    let version = VersionMessage::new(genesis_hash, 0, 0, None, 8);
+    write_message(&mut second, &Message::Version(version)).unwrap();
+    second
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    match read_message(&mut second) {
+        Err(Error::Io(err)) => assert!(matches!(
+            err.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        )),
+        result => panic!("expected no answer, got {:?}", result),
+    }
+
+    // it is accepted once the first one is closed
+    drop(first);
+    wait_for_event(&|event| matches!(event, NodeEvent::PeerDisconnected { .. }));
+    second
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    assert!(matches!(
+        read_message(&mut second).unwrap(),
+        Message::Version(_)
+    ));
+}
+
+#[test]
+fn test_block_sync() {
+    let alice = Wallet::new().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let ours =
+        BlockChain::create_temporary_with_clock(&alice.get_address(), clock.clone()).unwrap();
+    let theirs =
+        BlockChain::create_temporary_with_clock(&alice.get_address(), clock.clone()).unwrap();
+    let blocks: Vec<Block> = (0..20)
+        .map(|_| theirs.mine_block(&alice.get_address(), &[]).unwrap())
+        .collect();
+    let work = |height: usize| {
+        theirs
+            .get_chain_work(&blocks[height - 1].get_hash())
+            .unwrap()
+    };
+    let our_work = ours.get_chain_work(&ours.get_tip_hash()).unwrap();
+    let timeout = Duration::from_secs(10);
+    let now = Instant::now();
+
+    // headers are asked from the peer with the most work, not the one with the highest tip
+    let mut by_work = BlockSync::new(timeout, timeout, MAX_QUEUED_HEADERS);
+    by_work.add_peer(1, 20, work(20));
+    by_work.add_peer(3, 30, our_work);
+    let (peer, _) = by_work.request_headers(&ours, now).unwrap().unwrap();
+    assert_eq!(peer, 1);
+    by_work.remove_peer(1);
+    assert!(by_work.request_headers(&ours, now).unwrap().is_none());
+
+    let mut sync = BlockSync::new(timeout, timeout, MAX_QUEUED_HEADERS);
+    sync.add_peer(1, 20, work(20));
+    sync.add_peer(2, 5, work(5));
+
+    // one request at a time
+    let (peer, request) = sync.request_headers(&ours, now).unwrap().unwrap();
+    assert_eq!(peer, 1);
+    assert!(sync.request_headers(&ours, now).unwrap().is_none());
+    let locator = match request {
+        Message::GetHeaders { locator, .. } => locator,
+        message => panic!("unexpected {}", message.get_command()),
+    };
+    let headers = theirs
+        .get_headers_after(&locator, &BlockHash::default(), MAX_HEADERS_SIZE)
+        .unwrap();
+    assert_eq!(headers.len(), 20);
+
+    // headers from another peer are ignored, bad headers are refused
+    assert!(sync
+        .on_headers(&ours, 2, headers.clone(), now)
+        .unwrap()
+        .is_none());
+    let mut swapped = headers.clone();
+    swapped.swap(3, 4);
+    assert!(matches!(
+        sync.on_headers(&ours, 1, swapped, now),
+        Err(Error::InvalidBlock(
+            BlockValidationError::PrevHashMismatch { .. }
+        ))
+    ));
+    assert!(matches!(
+        sync.on_headers(&ours, 1, headers[1..].to_vec(), now),
+        Err(Error::Network(NetworkError::UnexpectedMessage(_)))
+    ));
+    assert_eq!(sync.get_progress(&ours).unwrap().get_queued_blocks(), 0);
+
+    assert!(sync.on_headers(&ours, 1, headers, now).unwrap().is_none());
+    let progress = sync.get_progress(&ours).unwrap();
+    assert_eq!(progress.get_header_height(), 20);
+    assert_eq!(progress.get_queued_blocks(), 20);
+    assert!(progress.is_syncing());
+    // peer 1 sent all its headers
+    assert!(sync.request_headers(&ours, now).unwrap().is_none());
+
+    // each block goes to the peer with the fewest blocks in flight which has it
+    let requested = |requests: &[(u64, Message)], peer: u64| -> Vec<usize> {
+        let items = requests
+            .iter()
+            .find(|(id, _)| *id == peer)
+            .map(|(_, message)| match message {
+                Message::GetData(items) => items.clone(),
+                message => panic!("unexpected {}", message.get_command()),
+            })
+            .unwrap_or_default();
+        items
+            .iter()
+            .map(|item| {
+                let position = blocks
+                    .iter()
+                    .position(|block| Inventory::Block(block.get_hash()) == *item)
+                    .unwrap();
+                blocks[position].get_height()
+            })
+            .collect()
+    };
+    let requests = sync.request_blocks(now);
+    let mut expected: Vec<usize> = vec![1, 3, 5];
+    expected.extend(6..6 + MAX_BLOCKS_IN_FLIGHT - 3);
+    assert_eq!(requested(&requests, 1), expected);
+    assert_eq!(requested(&requests, 2), vec![2, 4]);
+    assert_eq!(
+        sync.get_progress(&ours).unwrap().get_blocks_in_flight(),
+        MAX_BLOCKS_IN_FLIGHT + 2
+    );
+    assert!(sync.request_blocks(now).is_empty());
+
+    // a block must match its header, blocks are taken in height order
+    let fake = Block::from_parts(
+        blocks[1].get_header().clone(),
+        blocks[2].get_transactions().to_vec(),
+    );
+    assert_eq!(sync.on_block(2, &fake), BlockReceipt::Rejected);
+    assert_eq!(sync.on_block(2, &blocks[1]), BlockReceipt::Accepted);
+    assert!(sync.take_next_block().is_none());
+    assert_eq!(sync.on_block(1, &blocks[0]), BlockReceipt::Accepted);
+    for block in &blocks[0..2] {
+        let (next, source) = sync.take_next_block().unwrap();
+        assert_eq!(next.get_hash(), block.get_hash());
+        assert_eq!(source, 1);
+        ours.add_block(&next).unwrap();
+    }
+    assert!(sync.take_next_block().is_none());
+    assert_eq!(sync.on_block(1, &blocks[0]), BlockReceipt::NotRequested);
+
+    // peer 2 does not send block 4 in time, it is dropped and block 4 goes to peer 1
+    for height in expected.iter().skip(1) {
+        assert_eq!(
+            sync.on_block(1, &blocks[height - 1]),
+            BlockReceipt::Accepted
         );
-        transaction.print();
-        let _ = blockchain.mine_block(&[transaction]);
-        println!("\n=====Find Spendable=========================\n");
+    }
+    assert!(sync.check_stalls(now + timeout).is_empty());
+    let later = now + timeout * 2;
+    assert_eq!(sync.check_stalls(later), vec![2]);
+    let requests = sync.request_blocks(later);
+    assert_eq!(requested(&requests, 1), vec![4, 19, 20]);
+    for height in [4, 19, 20] {
+        assert_eq!(
+            sync.on_block(1, &blocks[height - 1]),
+            BlockReceipt::Accepted
+        );
+    }
+    while let Some((block, _)) = sync.take_next_block() {
+        ours.add_block(&block).unwrap();
+    }
+    assert_eq!(ours.get_tip_hash(), theirs.get_tip_hash());
+    assert!(!sync.get_progress(&ours).unwrap().is_syncing());
+}
+
+#[test]
+fn test_block_sync_limits() {
+    let alice = Wallet::new().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let ours =
+        BlockChain::create_temporary_with_clock(&alice.get_address(), clock.clone()).unwrap();
+    let theirs =
+        BlockChain::create_temporary_with_clock(&alice.get_address(), clock.clone()).unwrap();
+    let blocks: Vec<Block> = (0..20)
+        .map(|_| theirs.mine_block(&alice.get_address(), &[]).unwrap())
+        .collect();
+    let our_work = ours.get_chain_work(&ours.get_tip_hash()).unwrap();
+    let timeout = Duration::from_secs(10);
+    let now = Instant::now();
+    let answer = |request: Message| match request {
+        Message::GetHeaders { locator, stop } => theirs
+            .get_headers_after(&locator, &stop, MAX_HEADERS_SIZE)
+            .unwrap(),
+        message => panic!("unexpected {}", message.get_command()),
+    };
 
-        utxo_set.reindex();
-        let addr = "hegtsodoucahjsubxg".as_bytes();
-        let decode = bs58::decode(addr).into_vec().unwrap();
-        let pub_key_hash = &decode[1..decode.len() - 4];
-        let spendable_outputs = utxo_set.find_spendable_outputs(pub_key_hash, 8);
-        let pub_key_hash = pub_key_hash.to_vec();
-        println!("pub_key_hash: {:?}", hex_encode(&pub_key_hash));
-        println!("spendable_outputs: {:?}", spendable_outputs);
+    // a peer is only asked for headers once it showed a block with valid proof of work
+    let mut sync = BlockSync::new(timeout, timeout, 8);
+    sync.add_peer(1, 0, our_work);
+    assert!(sync.request_headers(&ours, now).unwrap().is_none());
+    sync.on_orphan_block(&ours, 1, &blocks[19]).unwrap();
+    let (peer, request) = sync.request_headers(&ours, now).unwrap().unwrap();
+    assert_eq!(peer, 1);
 
-        // unlock guard
-        drop(_guard);
-        clean_db();
+    // only 8 headers are queued, the rest is asked for when blocks are connected
+    let headers = answer(request);
+    assert_eq!(headers.len(), 20);
+    assert!(sync.on_headers(&ours, 1, headers, now).unwrap().is_none());
+    let progress = sync.get_progress(&ours).unwrap();
+    assert_eq!(progress.get_queued_blocks(), 8);
+    assert_eq!(progress.get_header_height(), 8);
+    assert!(sync.request_headers(&ours, now).unwrap().is_none());
+
+    sync.request_blocks(now);
+    for block in &blocks[..8] {
+        assert_eq!(sync.on_block(1, block), BlockReceipt::Accepted);
+    }
+    while let Some((block, _)) = sync.take_next_block() {
+        ours.add_block(&block).unwrap();
+    }
+    let (peer, request) = sync.request_headers(&ours, now).unwrap().unwrap();
+    assert_eq!(peer, 1);
+    let headers = answer(request);
+    assert_eq!(headers.len(), 12);
+    assert!(sync.on_headers(&ours, 1, headers, now).unwrap().is_none());
+    assert_eq!(sync.get_progress(&ours).unwrap().get_header_height(), 16);
+}
+
+/// Do the handshake by hand, the stream then does only what the test says
+fn connect_fake_peer(node: &Node, best_height: usize, chain_work: u128) -> TcpStream {
+    let mut stream = TcpStream::connect(node.get_local_addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let genesis_hash = node.get_blockchain().get_genesis_hash().unwrap();
+    let version = VersionMessage::new(genesis_hash, best_height, chain_work, None, 7);
+    write_message(&mut stream, &Message::Version(version)).unwrap();
+    assert!(matches!(
+        read_message(&mut stream).unwrap(),
+        Message::Version(_)
+    ));
+    write_message(&mut stream, &Message::Verack).unwrap();
+    assert!(matches!(
+        read_message(&mut stream).unwrap(),
+        Message::Verack
+    ));
+    stream
+}
+
+/// Read the messages of the node until it asks for headers, return the locator
+fn read_get_headers(stream: &mut TcpStream) -> Vec<BlockHash> {
+    loop {
+        if let Message::GetHeaders { locator, .. } = read_message(stream).unwrap() {
+            return locator;
+        }
+    }
+}
+
+/// The node closes the connection, which may also be a reset
+fn assert_closed(stream: &mut TcpStream) {
+    loop {
+        match read_message(stream) {
+            Ok(_) => {}
+            Err(Error::Io(err)) => {
+                assert!(!matches!(
+                    err.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ));
+                return;
+            }
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+}
+
+#[test]
+fn test_node_sync_from_bad_peers() {
+    let alice = Wallet::new().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let node_a = start_node(&alice.get_address(), &clock);
+    let node_b = start_node(&alice.get_address(), &clock);
+    for _ in 0..20 {
+        node_b.mine_block(&alice.get_address()).unwrap();
     }
 
-    #[test]
-    fn test_pub_key_hash() {
-        let w1 = Wallet::new();
-        let w2 = Wallet::new();
+    // a peer which sends headers out of order is disconnected
+    let mut bad_peer = connect_fake_peer(&node_a, 100, u128::MAX);
+    let locator = read_get_headers(&mut bad_peer);
+    let mut headers = node_b
+        .get_blockchain()
+        .get_headers_after(&locator, &BlockHash::default(), MAX_HEADERS_SIZE)
+        .unwrap();
+    headers.swap(5, 6);
+    write_message(&mut bad_peer, &Message::Headers(headers)).unwrap();
+    assert_closed(&mut bad_peer);
+
+    // a peer which does not answer is disconnected after `headers_timeout`,
+    // and the headers come from b instead
+    let mut silent_peer = connect_fake_peer(&node_a, 100, u128::MAX);
+    read_get_headers(&mut silent_peer);
+    node_a.connect(node_b.get_local_addr()).unwrap();
+    assert_closed(&mut silent_peer);
+    let tip_b = node_b.get_blockchain().get_tip_hash();
+    assert!(wait_until(
+        || node_a.get_blockchain().get_tip_hash() == tip_b
+    ));
+    assert!(wait_until(|| !node_a
+        .get_sync_progress()
+        .unwrap()
+        .is_syncing()));
+    assert_eq!(node_a.get_peer_count(), 1);
+}
+
+/// Send an HTTP request, return the status code and the body of the answer
+fn http_request(server: &RpcServer, request: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(server.get_local_addr()).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response.split_whitespace().nth(1).unwrap().parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, body.to_string())
+}
 
-        let addr1 = w1.get_address();
-        let addr2 = w2.get_address();
+fn rpc_post(server: &RpcServer, body: &str) -> Value {
+    let request = format!(
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let (status, answer) = http_request(server, &request);
+    assert_eq!(status, 200);
+    serde_json::from_str(&answer).unwrap()
+}
 
-        let pub_key1 = w1.get_public_key();
-        let pub_key2 = w2.get_public_key();
+/// Call a method, return the result, or the error code
+fn rpc_call(server: &RpcServer, method: &str, params: Value) -> Result<Value, i64> {
+    let call = json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params });
+    let answer = rpc_post(server, &call.to_string());
+    assert_eq!(answer["id"], 7);
+    match answer.get("error") {
+        Some(error) => Err(error["code"].as_i64().unwrap()),
+        None => Ok(answer["result"].clone()),
+    }
+}
 
-        let pub_key_hash1 = hash_pub_key(&pub_key1);
-        let pub_key_hash2 = hash_pub_key(&pub_key2);
+#[test]
+fn test_rpc_server() {
+    let alice = Wallet::new().unwrap();
+    let bob = Wallet::new().unwrap();
+    let blockchain = Arc::new(BlockChain::create_temporary(&alice.get_address()).unwrap());
+    let mempool = Arc::new(Mempool::new());
+    let block = blockchain.mine_block(&alice.get_address(), &[]).unwrap();
+    let server =
+        RpcServer::start(blockchain.clone(), mempool.clone(), "127.0.0.1:0", None).unwrap();
 
-        let pub_key_hash_from_addr1 = bs58::decode(&addr1).into_vec().unwrap();
-        let pub_key_hash_from_addr1 =
-            &pub_key_hash_from_addr1[1..pub_key_hash_from_addr1.len() - 4].to_vec();
+    // blocks
+    assert_eq!(rpc_call(&server, "getblockcount", json!([])), Ok(json!(1)));
+    assert_eq!(
+        rpc_call(&server, "getblockhash", json!([1])),
+        Ok(json!(block.get_hash().to_string()))
+    );
+    assert_eq!(
+        rpc_call(&server, "getblockhash", json!([2])),
+        Err(INVALID_PARAMS)
+    );
+    let result = rpc_call(&server, "getblock", json!([block.get_hash()])).unwrap();
+    assert_eq!(result["height"], 1);
+    assert_eq!(
+        result["previousblockhash"],
+        json!(block.get_pre_block_hash())
+    );
+    assert_eq!(result["tx"], json!([block.get_transactions()[0].get_id()]));
+    assert_eq!(result["merkleroot"], json!(block.hash_transactions()));
+    assert_eq!(
+        rpc_call(&server, "getblock", json!([BlockHash::default()])),
+        Err(NOT_FOUND)
+    );
+    assert_eq!(
+        rpc_call(&server, "getblock", json!(["xyz"])),
+        Err(INVALID_PARAMS)
+    );
+    assert_eq!(
+        rpc_call(&server, "getblock", json!([])),
+        Err(INVALID_PARAMS)
+    );
 
-        let pub_key_hash_from_addr2 = bs58::decode(&addr2).into_vec().unwrap();
-        let pub_key_hash_from_addr2 =
-            &pub_key_hash_from_addr2[1..pub_key_hash_from_addr2.len() - 4].to_vec();
+    // a raw transaction from a wallet goes to the mempool, once
+    let tx = {
+        let utxo_set = UtxoSet::new(&blockchain);
+        Transaction::new_utxo_transactions(&alice, &bob.get_address(), 3, &utxo_set).unwrap()
+    };
+    let raw = HEXLOWER.encode(&tx.serialize());
+    assert_eq!(
+        Transaction::deserialize(&tx.serialize()).unwrap().get_id(),
+        tx.get_id()
+    );
+    assert_eq!(
+        rpc_call(&server, "sendrawtransaction", json!([raw])),
+        Ok(json!(tx.get_id()))
+    );
+    assert_eq!(
+        rpc_call(&server, "sendrawtransaction", json!([raw])),
+        Err(TRANSACTION_REJECTED)
+    );
+    assert_eq!(
+        rpc_call(&server, "sendrawtransaction", json!(["00ff"])),
+        Err(INVALID_PARAMS)
+    );
+    assert_eq!(
+        rpc_call(&server, "getmempoolinfo", json!([])),
+        Ok(json!({ "size": 1, "bytes": tx.get_size(), "total_fee": 0 }))
+    );
+    assert_eq!(
+        rpc_call(&server, "getrawtransaction", json!([tx.get_id()])),
+        Ok(json!(raw))
+    );
+    let result = rpc_call(&server, "getrawtransaction", json!([tx.get_id(), true])).unwrap();
+    assert!(result["vout"]
+        .as_array()
+        .unwrap()
+        .contains(&json!({ "value": 3, "address": bob.get_address() })));
 
-        let pub_key_hash1_str = hex_encode(&pub_key_hash1);
-        let pub_key_hash2_str = hex_encode(&pub_key_hash2);
+    // balances follow the chain, not the mempool
+    assert_eq!(
+        rpc_call(&server, "getbalance", json!([bob.get_address()])),
+        Ok(json!(0))
+    );
+    let block = blockchain
+        .mine_block(&alice.get_address(), &mempool.get_block_template(10))
+        .unwrap();
+    mempool.remove_confirmed(&block);
+    assert_eq!(
+        rpc_call(&server, "getbalance", json!([bob.get_address()])),
+        Ok(json!(3))
+    );
+    assert_eq!(
+        rpc_call(&server, "listunspent", json!([bob.get_address()])),
+        Ok(json!([{
+            "txid": tx.get_id(),
+            "vout": 0,
+            "value": 3,
+            "height": 2,
+            "confirmations": 1,
+        }]))
+    );
+    assert_eq!(
+        rpc_call(&server, "getbalance", json!(["not an address"])),
+        Err(INVALID_PARAMS)
+    );
+    assert_eq!(
+        rpc_call(&server, "getrawtransaction", json!([tx.get_id()])),
+        Ok(json!(raw))
+    );
 
-        let pub_key_hash_from_addr1_str = hex_encode(&pub_key_hash_from_addr1);
-        let pub_key_hash_from_addr2_str = hex_encode(&pub_key_hash_from_addr2);
+    // requests which are not calls
+    assert_eq!(rpc_call(&server, "stop", json!([])), Err(METHOD_NOT_FOUND));
+    assert_eq!(rpc_post(&server, "{")["error"]["code"], PARSE_ERROR);
+    let batch = rpc_post(
+        &server,
+        r#"[{"id": 1, "method": "getblockcount"}, {"id": 2, "method": "getmempoolinfo"}]"#,
+    );
+    assert_eq!(batch[0]["result"], 2);
+    assert_eq!(batch[1]["result"]["size"], 0);
+
+    // notifications are handled but not answered, a call without a method is answered
+    let notification = r#"{"method": "getblockcount"}"#;
+    let request = format!(
+        "POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        notification.len(),
+        notification
+    );
+    assert_eq!(http_request(&server, &request), (204, String::new()));
+    let batch = rpc_post(
+        &server,
+        r#"[{"method": "getblockcount"}, {"id": 3, "method": "getblockcount"}, {"params": []}]"#,
+    );
+    assert_eq!(batch.as_array().unwrap().len(), 2);
+    assert_eq!(batch[0]["id"], 3);
+    assert_eq!(batch[1]["id"], Value::Null);
+    assert_eq!(batch[1]["error"]["code"], INVALID_REQUEST);
+    let (status, _) = http_request(&server, "GET / HTTP/1.1\r\n\r\n");
+    assert_eq!(status, 405);
+
+    // only JSON is accepted, e.g. not a form a web page can post
+    let body = r#"{"id": 1, "method": "getblockcount"}"#;
+    let post = |content_type: &str| {
+        format!(
+            "POST / HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        )
+    };
+    for content_type in ["", "Content-Type: application/x-www-form-urlencoded\r\n"] {
+        let (status, _) = http_request(&server, &post(content_type));
+        assert_eq!(status, 415);
+    }
+    let (status, answer) = http_request(
+        &server,
+        &post("content-type: Application/JSON; charset=utf-8\r\n"),
+    );
+    assert_eq!(status, 200);
+    assert_eq!(serde_json::from_str::<Value>(&answer).unwrap()["result"], 2);
+}
+
+#[test]
+fn test_rpc_connection_limit() {
+    let alice = Wallet::new().unwrap();
+    let blockchain = Arc::new(BlockChain::create_temporary(&alice.get_address()).unwrap());
+    let server =
+        RpcServer::start(blockchain, Arc::new(Mempool::new()), "127.0.0.1:0", None).unwrap();
+
+    // clients which send nothing hold all the connections
+    let mut idle: Vec<TcpStream> = (0..MAX_RPC_CONNECTIONS)
+        .map(|_| TcpStream::connect(server.get_local_addr()).unwrap())
+        .collect();
+    let body = r#"{"id": 1, "method": "getblockcount"}"#;
+    let mut stream = TcpStream::connect(server.get_local_addr()).unwrap();
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    let mut byte = [0u8; 1];
+    let err = stream.read(&mut byte).unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut
+    ));
+
+    // the request is answered once a connection is closed
+    idle.pop();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+}
+
+#[test]
+fn test_rpc_deadline() {
+    // the deadline is for the whole request, not for each read
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let mut stream = DeadlineStream::new(stream, Duration::from_millis(300));
+    let started = Instant::now();
+    let trickle = std::thread::spawn(move || {
+        for _ in 0..20 {
+            if client.write_all(b"x").is_err() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    });
+    let mut byte = [0u8; 1];
+    let err = loop {
+        match stream.read(&mut byte) {
+            Ok(0) => panic!("the client stopped before the deadline"),
+            Ok(_) => {}
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(started.elapsed() < Duration::from_millis(600));
+    assert!(stream.write(b"x").is_err());
+    drop(stream);
+    trickle.join().unwrap();
+
+    // the failed requests go to the caller
+    let alice = Wallet::new().unwrap();
+    let blockchain = Arc::new(BlockChain::create_temporary(&alice.get_address()).unwrap());
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let server = RpcServer::start(
+        blockchain,
+        Arc::new(Mempool::new()),
+        "127.0.0.1:0",
+        Some(sender),
+    )
+    .unwrap();
+    let mut stream = TcpStream::connect(server.get_local_addr()).unwrap();
+    stream
+        .write_all(
+            b"POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 10\r\n\r\n{",
+        )
+        .unwrap();
+    drop(stream);
+    match receiver.recv_timeout(Duration::from_secs(5)).unwrap() {
+        RpcEvent::RequestFailed { .. } => {}
+        event => panic!("expected a failed request, got {}", event),
+    }
+}
+
+#[test]
+fn test_miner() {
+    // the nonces are split without gaps or overlaps, up to i64::MAX
+    let ranges = split_nonces(i64::MAX, 3);
+    assert_eq!(ranges.len(), 3);
+    assert_eq!(*ranges[0].start(), 0);
+    assert_eq!(*ranges[2].end(), i64::MAX);
+    for pair in ranges.windows(2) {
+        assert_eq!(*pair[0].end() + 1, *pair[1].start());
+    }
+    assert_eq!(split_nonces(1, 4), vec![0..=0, 1..=1]);
+    assert_eq!(split_nonces(4, 2), vec![0..=2, 3..=4]);
+
+    // with one nonce per header, the miner rolls the extra nonce until a header works
+    let alice = Wallet::new().unwrap();
+    let blockchain = BlockChain::create_temporary(&alice.get_address()).unwrap();
+    let template = blockchain
+        .create_block_template(&alice.get_address(), &[])
+        .unwrap();
+    let config = MinerConfig {
+        threads: 4,
+        max_nonce: 0,
+    };
+    let job = MiningJob::start(template, config);
+    let block = job.wait().unwrap().unwrap();
+    assert_eq!(block.get_header().get_nonce(), 0);
+    let stats = job.get_stats();
+    assert_eq!(stats.get_hashes(), stats.get_extra_nonce() + 1);
+    let coinbase_data = block.get_transactions()[0].get_vin()[0].get_signature();
+    assert_eq!(coinbase_data[8..], stats.get_extra_nonce().to_be_bytes());
+    assert!(matches!(
+        blockchain.add_block(&block).unwrap(),
+        BlockStatus::Connected
+    ));
+
+    // a target nobody reaches: the job runs until it is cancelled
+    let template = BlockTemplate::new(
+        blockchain.get_tip_hash(),
+        2,
+        MAX_BITS,
+        block.get_timestamp() + 1,
+        &alice.get_address(),
+        0,
+        vec![],
+    );
+    let job = MiningJob::start(template, MinerConfig::default());
+    assert!(wait_until(|| job.get_stats().get_hashes() > 0));
+    job.get_cancel_handle().cancel();
+    assert!(job.wait().unwrap().is_none());
+    let stats = job.get_stats();
+    assert!(stats.get_hashrate() > 0.0);
+    assert_eq!(job.get_stats(), stats);
+
+    // a bad miner address is reported by the job
+    let template = blockchain.create_block_template("bad", &[]).unwrap();
+    assert!(matches!(
+        MiningJob::start(template, MinerConfig::default()).wait(),
+        Err(Error::InvalidAddress(_))
+    ));
+}
+
+#[test]
+fn test_parse_args() {
+    let args = |line: &str| -> Vec<String> { line.split_whitespace().map(String::from).collect() };
+    let addr = |address: &str| String::from(address);
+    let cases = [
+        (
+            "createblockchain alice",
+            None,
+            Command::CreateBlockchain {
+                address: addr("alice"),
+            },
+        ),
+        ("createwallet", None, Command::CreateWallet),
+        ("listaddresses", None, Command::ListAddresses),
+        (
+            "getbalance alice",
+            None,
+            Command::GetBalance {
+                address: addr("alice"),
+            },
+        ),
+        (
+            "--datadir /tmp/chain listunspent alice",
+            Some(addr("/tmp/chain")),
+            Command::ListUnspent {
+                address: addr("alice"),
+            },
+        ),
+        (
+            "send alice bob 5",
+            None,
+            Command::Send {
+                from: addr("alice"),
+                to: addr("bob"),
+                amount: 5,
+                fee: 0,
+            },
+        ),
+        (
+            "send alice bob 5 2 --datadir data",
+            Some(addr("data")),
+            Command::Send {
+                from: addr("alice"),
+                to: addr("bob"),
+                amount: 5,
+                fee: 2,
+            },
+        ),
+        ("printchain", None, Command::PrintChain),
+        ("reindexutxo", None, Command::ReindexUtxo),
+        ("disconnecttip", None, Command::DisconnectTip),
+        (
+            "startnode 127.0.0.1:2001",
+            None,
+            Command::StartNode {
+                listen_addr: addr("127.0.0.1:2001"),
+                peers: vec![],
+                rpc_addr: addr(DEFAULT_RPC_ADDR),
+            },
+        ),
+        (
+            "startnode 127.0.0.1:2001 --rpc 127.0.0.1:9000 127.0.0.1:2002 127.0.0.1:2003",
+            None,
+            Command::StartNode {
+                listen_addr: addr("127.0.0.1:2001"),
+                peers: vec![addr("127.0.0.1:2002"), addr("127.0.0.1:2003")],
+                rpc_addr: addr("127.0.0.1:9000"),
+            },
+        ),
+    ];
+    for (line, datadir, command) in cases {
+        assert_eq!(parse_args(args(line)), Ok((datadir, command)), "{}", line);
+    }
 
-        assert_eq!(pub_key_hash1_str, pub_key_hash_from_addr1_str);
-        assert_eq!(pub_key_hash2_str, pub_key_hash_from_addr2_str);
+    let errors = [
+        ("", "no command"),
+        ("mine", "unknown command: mine"),
+        ("createblockchain", "createblockchain needs 1 argument(s)"),
+        ("createwallet alice", "createwallet needs 0 argument(s)"),
+        ("getbalance", "getbalance needs 1 argument(s)"),
+        ("listunspent alice bob", "listunspent needs 1 argument(s)"),
+        ("send alice bob", "send needs 3 argument(s)"),
+        ("send alice bob 1 2 3", "send needs 3 argument(s)"),
+        ("send alice bob five", "invalid amount: five"),
+        ("send alice bob 0", "invalid amount: 0"),
+        ("send alice bob -1", "invalid amount: -1"),
+        ("send alice bob 1 -1", "invalid fee: -1"),
+        ("printchain --datadir", "--datadir needs a directory"),
+        ("startnode", "startnode needs 1 argument(s)"),
+        ("startnode 127.0.0.1:2001 --rpc", "--rpc needs an address"),
+    ];
+    for (line, err) in errors {
+        assert_eq!(parse_args(args(line)), Err(String::from(err)), "{}", line);
     }
 }
@@ -16,23 +16,68 @@ use core::fmt;
  *  So if she want to transfer 10 coins to Bob and 5 coins to Charlie, she can use two UTXOs at the same time
  *  That is why you can see the Transaction struct has two fields: vin and vout, which are vectors, not just an addrss
  */
+use crate::coin_selection::{CoinSelector, LargestFirst};
+use crate::error::{Error, Result};
+use crate::hash::Txid;
 use crate::utils::hex_encode;
-use crate::utils::sha256_digest;
+use crate::utils::{
+    ecdsa_p256_sha256_sign_digest, ecdsa_p256_sha256_sign_verify, ECDSA_P256_SIGNATURE_LENGTH,
+};
 use crate::utxo_set::UtxoSet;
-use data_encoding::HEXLOWER;
+use crate::wallet::{decode_address, hash_pub_key, Wallet};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The outputs spent by the inputs of a transaction, by txid and vout.
+/// They come from the UTXO set, or from the transactions before it in a block or the mempool.
+pub type PrevOutputs = HashMap<(Txid, usize), TXOutput>;
+
+/// Reward of the coinbase before the first halving
+pub const INITIAL_SUBSIDY: i32 = 10;
+/// The subsidy is halved every `HALVING_INTERVAL` blocks
+pub const HALVING_INTERVAL: usize = 100;
+
+/// New coins a miner can create in the block at `height`, without the fees.
+/// It starts at `INITIAL_SUBSIDY`, and is halved (rounded down) every `HALVING_INTERVAL` blocks.
+pub fn get_block_subsidy(height: usize) -> i32 {
+    let halvings = height / HALVING_INTERVAL;
+    if halvings >= 31 {
+        return 0;
+    }
+    INITIAL_SUBSIDY >> halvings
+}
+
+/// The fee of a new transaction
+///   - Fixed: this many coins
+///   - PerByte: this many coins for each byte of the serialized transaction, rounded up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fee {
+    Fixed(i32),
+    PerByte(f64),
+}
+
+impl Fee {
+    /// The fee of a transaction of `size` bytes
+    pub fn get_value(&self, size: usize) -> i32 {
+        match self {
+            Fee::Fixed(value) => *value,
+            Fee::PerByte(rate) => (rate * size as f64).ceil() as i32,
+        }
+    }
+}
 
 /// UTXO input
 /// fields:
-///   - txid: Previous transaction ID, Notice that this is `Vec<u8>` instead of String
-///           Because in rust, char is 4 bytes rather than 1 byte like C
+///   - txid: Previous transaction ID
 ///   - vout: Previous transaction output index
-///   - signature: Signature of the transaction
-///   - pub_key: Public key of the transaction
+///   - signature: Signature of the transaction.
+///     A coinbase input has no signature, it holds the block height and an extra nonce instead
+///   - pub_key: Public key of the sender, not the public key hash.
+///     The verifier hashes it and compares with the `pub_key_hash` of the spent output
 ///
 #[derive(Default, Clone, Serialize, Deserialize)]
 pub struct TXInput {
-    txid: Vec<u8>,
+    txid: Txid,
     vout: usize,
     signature: Vec<u8>,
     pub_key: Vec<u8>,
@@ -43,7 +88,7 @@ impl fmt::Debug for TXInput {
         write!(
             f,
             "TXInput {{ txid: {:?}, vout: {:?}, signature: {:?}, pub_key: {:?} }}",
-            hex_encode(&self.txid),
+            self.txid.to_string(),
             self.vout,
             hex_encode(&self.signature),
             hex_encode(&self.pub_key)
@@ -74,44 +119,99 @@ impl fmt::Debug for TXOutput {
 
 /// Transaction Struct
 /// fields:
-///   - id: Transaction ID
+///   - id: Transaction ID, the hash of the transaction without the signatures.
+///     It is checked when a transaction is deserialized, see `TransactionData`
 ///   - vin: Vector of UTXO input
 ///   - vout: Vector of UTXO output
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "TransactionData")]
 pub struct Transaction {
-    id: Vec<u8>,
+    id: Txid,
+    vin: Vec<TXInput>,
+    vout: Vec<TXOutput>,
+}
+
+/// A transaction as it is decoded, before its id is checked.
+/// The signatures do not cover the id, so a transaction with a wrong id could still
+/// carry valid signatures, and take the place of another transaction.
+#[derive(Deserialize)]
+struct TransactionData {
+    id: Txid,
     vin: Vec<TXInput>,
     vout: Vec<TXOutput>,
 }
 
+impl TryFrom<TransactionData> for Transaction {
+    type Error = Error;
+
+    fn try_from(data: TransactionData) -> Result<Transaction> {
+        let tx = Transaction {
+            id: data.id,
+            vin: data.vin,
+            vout: data.vout,
+        };
+        if !tx.has_valid_id() {
+            return Err(Error::InvalidTransaction(format!(
+                "id {} is not the hash of the transaction",
+                tx.id
+            )));
+        }
+        Ok(tx)
+    }
+}
+
 impl TXInput {
-    pub fn get_txid(&self) -> &[u8] {
-        self.txid.as_slice()
+    /// An unsigned input which spends output `vout` of transaction `txid`,
+    /// `pub_key` is the public key of the owner of that output
+    pub fn new(txid: Txid, vout: usize, pub_key: &[u8]) -> TXInput {
+        TXInput {
+            txid,
+            vout,
+            signature: vec![],
+            pub_key: pub_key.to_vec(),
+        }
+    }
+
+    pub fn get_txid(&self) -> Txid {
+        self.txid
     }
 
     pub fn get_vout(&self) -> usize {
         self.vout
     }
+
+    pub fn get_pub_key(&self) -> &[u8] {
+        self.pub_key.as_slice()
+    }
+
+    pub fn get_signature(&self) -> &[u8] {
+        self.signature.as_slice()
+    }
+
+    /// Check whether this input is spent by the owner of `pub_key_hash`
+    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
+        let locking_hash = hash_pub_key(self.pub_key.as_slice());
+        locking_hash.eq(pub_key_hash)
+    }
 }
 
 impl TXOutput {
     /// Note: the parameter is `address`
     /// The `new` function will extract the public key hash from the address
-    pub fn new(value: i32, address: &str) -> TXOutput {
+    pub fn new(value: i32, address: &str) -> Result<TXOutput> {
         let mut output = TXOutput {
             value,
             pub_key_hash: Vec::new(),
         };
         // use bs58 to decode pub_key_hash from address
         // Note: it is pub_key_hash, not pub_key, so don't feel confused
-        output.lock(address);
-        output
+        output.lock(address)?;
+        Ok(output)
     }
 
-    fn lock(&mut self, address: &str) {
-        let decode = bs58::decode(address).into_vec().unwrap();
-        let pub_key_hash = &decode[1..decode.len() - 4];
-        self.pub_key_hash = pub_key_hash.to_vec();
+    fn lock(&mut self, address: &str) -> Result<()> {
+        self.pub_key_hash = decode_address(address)?;
+        Ok(())
     }
 
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
@@ -121,85 +221,301 @@ impl TXOutput {
     pub fn get_value(&self) -> i32 {
         self.value
     }
+
+    pub fn get_pub_key_hash(&self) -> &[u8] {
+        self.pub_key_hash.as_slice()
+    }
 }
 
 impl Transaction {
-    /// function `new_coinbase_tx` is used when miner mined a new block, the root would reward the miner
-    /// Since it has no input, so there is only one parameter, `to`
-    pub fn new_coinbase_tx(to: &str) -> Transaction {
-        let txout = TXOutput::new(10, to); // TODO: replace 10 with a variable
-        let tx_input = TXInput::default(); // use default, because there is no input
+    /// Build a transaction from its inputs and outputs, the inputs still need to be signed
+    pub fn new(vin: Vec<TXInput>, vout: Vec<TXOutput>) -> Transaction {
         let mut tx = Transaction {
-            id: Vec::new(),
-            vin: vec![tx_input],
-            vout: vec![txout],
+            id: Txid::default(),
+            vin,
+            vout,
         };
         tx.id = tx.hash();
         tx
     }
 
+    /// function `new_coinbase_tx` is used when miner mined a new block, the root would reward the miner
+    /// The reward is the subsidy of the block at `height` plus the `fees` of its transactions.
+    /// The height is part of the coinbase, so two coinbases never have the same txid.
+    pub fn new_coinbase_tx(to: &str, height: usize, fees: i32) -> Result<Transaction> {
+        Self::new_coinbase_tx_with_extra_nonce(to, height, fees, 0)
+    }
+
+    /// Same as `new_coinbase_tx`, the miner changes `extra_nonce` to get a new merkle root
+    /// once it has tried every nonce of the header
+    pub fn new_coinbase_tx_with_extra_nonce(
+        to: &str,
+        height: usize,
+        fees: i32,
+        extra_nonce: u64,
+    ) -> Result<Transaction> {
+        let txout = TXOutput::new(get_block_subsidy(height) + fees, to)?;
+        // there is no input, the input only carries the coinbase data
+        let mut coinbase_data = (height as u64).to_be_bytes().to_vec();
+        coinbase_data.extend(extra_nonce.to_be_bytes());
+        let tx_input = TXInput {
+            signature: coinbase_data,
+            ..TXInput::default()
+        };
+        Ok(Transaction::new(vec![tx_input], vec![txout]))
+    }
+
+    /// Create a transaction which sends `amount` coins from the `from` wallet to the address `to`.
+    /// Each input is signed with the private key of the `from` wallet.
+    /// It pays no fee, see `new_utxo_transaction_with_fee`.
     pub fn new_utxo_transactions(
-        from: &str,
+        from: &Wallet,
+        to: &str,
+        amount: i32,
+        utxo_set: &UtxoSet,
+    ) -> Result<Transaction> {
+        Self::new_utxo_transaction_with_fee(from, to, amount, Fee::Fixed(0), utxo_set)
+    }
+
+    /// Same as `new_utxo_transactions`, and the inputs also cover the `fee`, which is left
+    /// to the miner by not sending it back as change.
+    /// The inputs are the largest outputs of the wallet, see `new_utxo_transaction_with_selector`.
+    pub fn new_utxo_transaction_with_fee(
+        from: &Wallet,
         to: &str,
         amount: i32,
+        fee: Fee,
         utxo_set: &UtxoSet,
-    ) -> Transaction {
-        // TODO: fix this, when `Wallet` is implemented
-        let from_decode = bs58::decode(from).into_vec().unwrap();
-        let from_pub_key_hash = &from_decode[1..from_decode.len() - 4].to_vec();
-
-        // Find Spendable outputs
-        let (accumulated, spendable_outputs) =
-            utxo_set.find_spendable_outputs(from_pub_key_hash.as_slice(), amount);
-        if accumulated < amount {
-            panic!("ERROR: Not enough funds");
+    ) -> Result<Transaction> {
+        Self::new_utxo_transaction_with_selector(from, to, amount, fee, &LargestFirst, utxo_set)
+    }
+
+    /// Same as `new_utxo_transaction_with_fee`, and the inputs are picked by `selector`.
+    /// For `Fee::PerByte`, the fee depends on the size, and the size on the selected inputs,
+    /// so the inputs are selected again until the fee is covered.
+    pub fn new_utxo_transaction_with_selector(
+        from: &Wallet,
+        to: &str,
+        amount: i32,
+        fee: Fee,
+        selector: &dyn CoinSelector,
+        utxo_set: &UtxoSet,
+    ) -> Result<Transaction> {
+        let from_pub_key_hash = hash_pub_key(from.get_public_key());
+        let mut fee_value = match fee {
+            Fee::Fixed(value) => value,
+            Fee::PerByte(_) => 0,
+        };
+        if amount < 0 || fee_value < 0 {
+            return Err(Error::InvalidTransaction(String::from(
+                "amount and fee can not be negative",
+            )));
         }
 
-        let mut inputs = vec![];
-        for (txid, outs) in spendable_outputs {
-            let txid = HEXLOWER.decode(txid.as_bytes()).unwrap();
-            for out in outs {
-                let input = TXInput {
-                    txid: txid.to_vec(),
-                    vout: out,
-                    signature: vec![],
-                    pub_key: from_pub_key_hash.to_vec(),
-                };
-                inputs.push(input);
+        loop {
+            let needed = amount.checked_add(fee_value).ok_or_else(|| {
+                Error::InvalidTransaction(String::from("amount plus fee overflows"))
+            })?;
+            let selection =
+                utxo_set.select_coins(from_pub_key_hash.as_slice(), needed, selector)?;
+            // a selector may pick nothing for a payment of 0
+            if selection.get_outpoints().is_empty() {
+                return Err(Error::InvalidTransaction(String::from(
+                    "no outputs are selected, a transaction needs an input",
+                )));
+            }
+
+            let mut inputs = vec![];
+            for (txid, vout) in selection.get_outpoints() {
+                inputs.push(TXInput::new(*txid, *vout, from.get_public_key()));
             }
-        }
 
-        let mut outputs = vec![TXOutput::new(amount, to)];
-        if (accumulated - amount) > 0 {
-            outputs.push(TXOutput::new(accumulated - amount, from));
+            let mut outputs = vec![TXOutput::new(amount, to)?];
+            if selection.get_change() > 0 {
+                outputs.push(TXOutput::new(selection.get_change(), &from.get_address())?);
+            }
+
+            let mut tx = Transaction::new(inputs, outputs);
+            let required_fee = fee.get_value(tx.get_signed_size());
+            if required_fee > fee_value {
+                fee_value = required_fee;
+                continue;
+            }
+            utxo_set.get_blockchain().sign_transaction(&mut tx, from)?;
+            return Ok(tx);
         }
+    }
 
-        let mut tx = Transaction {
-            id: vec![],
+    /// A trimmed copy has the same inputs and outputs, but without signatures and public keys.
+    /// It is what each input actually signs.
+    fn trimmed_copy(&self) -> Transaction {
+        let mut inputs = vec![];
+        for input in &self.vin {
+            inputs.push(TXInput {
+                txid: input.txid,
+                vout: input.vout,
+                signature: vec![],
+                pub_key: vec![],
+            });
+        }
+        Transaction {
+            id: self.id,
             vin: inputs,
-            vout: outputs,
-        };
+            vout: self.vout.clone(),
+        }
+    }
 
-        tx.id = tx.hash();
-        // TODO: fix this, need `sign` when Wallet is implemented
-        return tx;
+    /// Sign every input of the transaction.
+    /// `prev_outputs` has the output spent by each input.
+    /// For input `i`, we put the `pub_key_hash` of the spent output into the trimmed copy,
+    /// hash it, and sign the hash with the private key of `wallet`.
+    pub fn sign(&mut self, wallet: &Wallet, prev_outputs: &PrevOutputs) -> Result<()> {
+        if self.is_coinbase() {
+            return Ok(());
+        }
+        let mut tx_copy = self.trimmed_copy();
+        for (idx, vin) in self.vin.iter_mut().enumerate() {
+            let prev_out = prev_outputs.get(&(vin.txid, vin.vout)).ok_or_else(|| {
+                Error::InvalidTransaction(format!(
+                    "previous output {}:{} not found",
+                    vin.txid, vin.vout
+                ))
+            })?;
+            tx_copy.vin[idx].pub_key = prev_out.pub_key_hash.clone();
+            tx_copy.id = tx_copy.hash();
+            tx_copy.vin[idx].pub_key = vec![];
+            vin.signature = ecdsa_p256_sha256_sign_digest(wallet.get_pkcs8(), tx_copy.id.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Verify the signature of every input against the outputs it spends.
+    /// Returns false if a spent output is missing from `prev_outputs`.
+    pub fn verify(&self, prev_outputs: &PrevOutputs) -> bool {
+        if self.is_coinbase() {
+            return true;
+        }
+        let mut tx_copy = self.trimmed_copy();
+        for (idx, vin) in self.vin.iter().enumerate() {
+            let prev_out = match prev_outputs.get(&(vin.txid, vin.vout)) {
+                Some(prev_out) => prev_out,
+                None => return false,
+            };
+            if !vin.uses_key(prev_out.pub_key_hash.as_slice()) {
+                return false;
+            }
+            tx_copy.vin[idx].pub_key = prev_out.pub_key_hash.clone();
+            tx_copy.id = tx_copy.hash();
+            tx_copy.vin[idx].pub_key = vec![];
+            if !ecdsa_p256_sha256_sign_verify(
+                vin.pub_key.as_slice(),
+                vin.signature.as_slice(),
+                tx_copy.id.as_ref(),
+            ) {
+                return false;
+            }
+        }
+        true
     }
 
     pub fn is_coinbase(&self) -> bool {
-        self.vin.len() == 1 && self.vin[0].pub_key.len() == 0
+        self.vin.len() == 1 && self.vin[0].pub_key.is_empty()
+    }
+
+    /// The block height written in a coinbase, None if it is not a coinbase or has no height
+    pub fn get_coinbase_height(&self) -> Option<usize> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let height = self.vin[0].signature.get(..8)?;
+        let mut height_bytes = [0u8; 8];
+        height_bytes.copy_from_slice(height);
+        Some(u64::from_be_bytes(height_bytes) as usize)
+    }
+
+    /// Inputs minus outputs, it is what the miner of the block gets on top of the subsidy.
+    /// `prev_outputs` must have every output spent by the inputs, or None is returned.
+    /// A coinbase has no fee.
+    pub fn get_fee(&self, prev_outputs: &PrevOutputs) -> Option<i64> {
+        if self.is_coinbase() {
+            return Some(0);
+        }
+        let mut input_value: i64 = 0;
+        for vin in &self.vin {
+            let prev_out = prev_outputs.get(&(vin.txid, vin.vout))?;
+            input_value += prev_out.value as i64;
+        }
+        Some(input_value - self.get_output_value())
+    }
+
+    /// Size of the serialized transaction in bytes, it is what the fee rate is measured against
+    pub fn get_size(&self) -> usize {
+        self.serialize().len()
+    }
+
+    /// Size of the transaction once every input is signed.
+    /// A P-256 signature always has the same length, so the size is known before signing.
+    pub fn get_signed_size(&self) -> usize {
+        if self.is_coinbase() {
+            return self.get_size();
+        }
+        let mut tx = self.clone();
+        for vin in tx.vin.iter_mut() {
+            vin.signature = vec![0; ECDSA_P256_SIGNATURE_LENGTH];
+        }
+        tx.get_size()
+    }
+
+    /// Fee per byte, None if a spent output is missing from `prev_outputs`
+    pub fn get_fee_rate(&self, prev_outputs: &PrevOutputs) -> Option<f64> {
+        Some(self.get_fee(prev_outputs)? as f64 / self.get_size() as f64)
+    }
+
+    pub fn has_negative_output(&self) -> bool {
+        self.vout.iter().any(|out| out.value < 0)
+    }
+
+    /// Sum of the values of all outputs
+    pub fn get_output_value(&self) -> i64 {
+        self.vout.iter().map(|out| out.value as i64).sum()
+    }
+
+    /// The id of the transaction, the hash of it before the inputs were signed like in `new`
+    pub fn compute_id(&self) -> Txid {
+        let mut unsigned = self.clone();
+        if !unsigned.is_coinbase() {
+            for vin in unsigned.vin.iter_mut() {
+                vin.signature = vec![];
+            }
+        }
+        unsigned.hash()
+    }
+
+    /// True if the id is the hash of the transaction, see `compute_id`
+    pub fn has_valid_id(&self) -> bool {
+        self.id == self.compute_id()
+    }
+
+    /// The id field is cleared before hashing, so the hash does not depend on the old id
+    fn hash(&self) -> Txid {
+        let mut tx_clone = self.clone();
+        tx_clone.id = Txid::default();
+        Txid::digest(tx_clone.serialize().as_slice())
     }
 
-    fn hash(&self) -> Vec<u8> {
-        let tx_clone = self.clone();
-        sha256_digest(&tx_clone.serialize().as_slice())
+    /// Serializing a transaction in memory can not fail, it only has vectors and integers
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(&self).expect("transaction is always serializable")
     }
 
-    fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(&self).unwrap()
+    /// Decode a transaction from `serialize`, e.g. one sent by a wallet.
+    /// It fails if the id in the data is not the hash of the transaction, see `compute_id`.
+    pub fn deserialize(data: &[u8]) -> Result<Transaction> {
+        Ok(bincode::deserialize(data)?)
     }
 
-    pub fn get_id(&self) -> &[u8] {
-        self.id.as_slice()
+    pub fn get_id(&self) -> Txid {
+        self.id
     }
 
     pub fn get_vout(&self) -> &[TXOutput] {
@@ -211,11 +527,11 @@ impl Transaction {
     }
 
     pub fn print(&self) {
-        println!("tx.id: {:?}", hex_encode(&self.id));
+        println!("tx.id: {:?}", self.id.to_string());
         println!("tx.vin: [");
         for item in self.vin.iter() {
             println!("  {{");
-            println!("    txid: {:?}", hex_encode(&item.txid));
+            println!("    txid: {:?}", item.txid.to_string());
             println!("    vout: {:?}", item.vout);
             println!("    signature: {:?}", hex_encode(&item.signature));
             println!("    pub_key: {:?}", hex_encode(&item.pub_key));
@@ -236,12 +552,12 @@ impl Transaction {
 
 impl fmt::Debug for Transaction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "tx.id: {:?}", hex_encode(&self.id))?;
+        write!(f, "tx.id: {:?}", self.id.to_string())?;
 
         f.write_str("tx.vin: [\n")?;
         for item in &self.vin {
             f.write_str("  {\n")?;
-            write!(f, "    txid: {:?}", hex_encode(&item.txid))?;
+            write!(f, "    txid: {:?}", item.txid.to_string())?;
             write!(f, "    vout: {:?}", item.vout)?;
             write!(f, "    signature: {:?}", hex_encode(&item.signature))?;
             write!(f, "    pub_key: {:?}", hex_encode(&item.pub_key))?;
@@ -1,9 +1,11 @@
 // Sha256 is a hashing algorithm
+use crate::error::{Error, Result};
 use crypto::digest::Digest;
 use ring::digest::{Context, SHA256};
-use ring::rand::SystemRandom;
-use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
-use std::iter::repeat;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{
+    EcdsaKeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING,
+};
 
 pub fn sha256_digest(data: &[u8]) -> Vec<u8> {
     let mut context = Context::new(&SHA256);
@@ -15,8 +17,8 @@ pub fn sha256_digest(data: &[u8]) -> Vec<u8> {
 // vec<u8> to hex string
 pub fn hex_encode(data: &Vec<u8>) -> String {
     let mut hex = String::from("0x");
-    if data.len() == 0 {
-        hex.push_str("0");
+    if data.is_empty() {
+        hex.push('0');
         return hex;
     }
     for byte in data {
@@ -25,16 +27,37 @@ pub fn hex_encode(data: &Vec<u8>) -> String {
     hex
 }
 
-pub fn new_key_pair() -> Vec<u8> {
+pub fn new_key_pair() -> Result<Vec<u8>> {
     let rng = SystemRandom::new();
-    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
-    pkcs8.as_ref().to_vec()
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+        .map_err(|_| Error::Crypto(String::from("failed to generate a key pair")))?;
+    Ok(pkcs8.as_ref().to_vec())
+}
+
+/// Length of a signature in the fixed (r, s) encoding
+pub const ECDSA_P256_SIGNATURE_LENGTH: usize = 64;
+
+// sign message with the private key in pkcs8 format
+pub fn ecdsa_p256_sha256_sign_digest(pkcs8: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8)
+        .map_err(|err| Error::Crypto(format!("invalid pkcs8 key: {}", err)))?;
+    let rng = SystemRandom::new();
+    let signature = key_pair
+        .sign(&rng, message)
+        .map_err(|_| Error::Crypto(String::from("failed to sign message")))?;
+    Ok(signature.as_ref().to_vec())
+}
+
+// verify the signature of message with the public key
+pub fn ecdsa_p256_sha256_sign_verify(public_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
+    let peer_public_key = UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key);
+    peer_public_key.verify(message, signature).is_ok()
 }
 
 pub fn ripemd160_digest(data: &[u8]) -> Vec<u8> {
     let mut ripemd160 = crypto::ripemd160::Ripemd160::new();
     ripemd160.input(data);
-    let mut buf: Vec<u8> = repeat(0).take(ripemd160.output_bytes()).collect();
+    let mut buf: Vec<u8> = vec![0; ripemd160.output_bytes()];
     ripemd160.result(&mut buf);
     buf
 }
@@ -42,3 +65,12 @@ pub fn ripemd160_digest(data: &[u8]) -> Vec<u8> {
 pub fn base58_encode(data: &[u8]) -> String {
     bs58::encode(data).into_string()
 }
+
+/// A random number from the system's secure generator
+pub fn random_u64() -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::Crypto(String::from("failed to generate random bytes")))?;
+    Ok(u64::from_be_bytes(bytes))
+}
@@ -1,60 +1,498 @@
 use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-use crate::blockchain::BlockChain;
+use crate::block::Block;
+use crate::blockchain::{BlockChain, HEADERS_TREE_NAME, TIP_BLOCK_HASH_KEY};
+use crate::coin_selection::{CoinSelection, CoinSelector, SpendableOutput};
+use crate::error::{Error, Result};
+use crate::hash::{BlockHash, Txid, HASH_LENGTH};
 use crate::transaction::TXOutput;
+use sled::transaction::{ConflictableTransactionError, Transactional};
 
 pub const UTXO_TREE: &str = "chainstate";
+pub const UTXO_META_TREE: &str = "chainstate_meta";
+/// The unspent outputs by the public key hash they are locked with, see `address_key`
+pub const UTXO_ADDRESS_TREE: &str = "chainstate_address";
+/// The outputs each connected block spent, by block hash, see `update` and `disconnect`
+pub const UTXO_UNDO_TREE: &str = "chainstate_undo";
+pub const UTXO_SCHEMA_VERSION_KEY: &str = "schema_version";
+/// The block the UTXO set is built for, in `chainstate_meta`.
+/// It is the tip of the chain, unless the node stopped in the middle of a `reindex`.
+pub const UTXO_BEST_BLOCK_KEY: &str = "best_block";
+
+/// Version 1: key is the txid, value is `Vec<TXOutput>` without the original indices.
+/// Version 2: key is txid + vout, value is one `TXOutput`.
+/// Version 3: adds the `chainstate_address` index.
+pub const UTXO_SCHEMA_VERSION: u32 = 3;
+
+/// The key of an unspent output in the `chainstate` tree: txid (32 bytes) + vout (8 bytes, big endian).
+/// Big endian keeps the outputs of one transaction together, sorted by vout.
+pub fn utxo_key(txid: &Txid, vout: usize) -> Vec<u8> {
+    let mut key = txid.as_bytes().to_vec();
+    key.extend((vout as u64).to_be_bytes());
+    key
+}
+
+/// Split a `chainstate` key into txid and vout
+pub fn parse_utxo_key(key: &[u8]) -> Result<(Txid, usize)> {
+    if key.len() != HASH_LENGTH + 8 {
+        return Err(Error::Corruption(format!(
+            "invalid UTXO key: {}",
+            HEXLOWER.encode(key)
+        )));
+    }
+    let (txid, vout) = key.split_at(HASH_LENGTH);
+    let mut vout_bytes = [0u8; 8];
+    vout_bytes.copy_from_slice(vout);
+    Ok((
+        Txid::from_slice(txid)?,
+        u64::from_be_bytes(vout_bytes) as usize,
+    ))
+}
+
+/// The key of an unspent output in the `chainstate_address` tree:
+/// length of the public key hash (1 byte) + public key hash + `utxo_key`.
+/// The outputs of one public key hash are together, so they can be found by a prefix scan.
+pub fn address_key(pub_key_hash: &[u8], txid: &Txid, vout: usize) -> Vec<u8> {
+    let mut key = address_prefix(pub_key_hash);
+    key.extend(utxo_key(txid, vout));
+    key
+}
+
+fn address_prefix(pub_key_hash: &[u8]) -> Vec<u8> {
+    let mut prefix = vec![pub_key_hash.len() as u8];
+    prefix.extend(pub_key_hash);
+    prefix
+}
+
+// The value of an output in the `chainstate_address` tree
+#[derive(Serialize, Deserialize)]
+struct AddressIndexEntry {
+    value: i32,
+    height: usize,
+}
+
+// An output spent by a block, in the undo record of the block.
+// The height is the height of the block which created the output, for the address index.
+#[derive(Serialize, Deserialize)]
+struct SpentOutput {
+    txid: Txid,
+    vout: usize,
+    output: TXOutput,
+    height: usize,
+}
+
+/// An unspent output of an address, with the height of the block which confirmed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnspentOutput {
+    txid: Txid,
+    vout: usize,
+    value: i32,
+    height: usize,
+}
+
+impl UnspentOutput {
+    pub fn get_txid(&self) -> Txid {
+        self.txid
+    }
+
+    pub fn get_vout(&self) -> usize {
+        self.vout
+    }
+
+    pub fn get_value(&self) -> i32 {
+        self.value
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    /// 1 if the output is in the tip block, 2 if there is one block after it, ...
+    pub fn get_confirmations(&self, best_height: usize) -> usize {
+        (best_height + 1).saturating_sub(self.height)
+    }
+}
 
 pub struct UtxoSet<'a> {
     blockchain: &'a BlockChain,
 }
 
 impl<'a> UtxoSet<'a> {
-    pub fn new(blockchain: &'a BlockChain) -> UtxoSet {
+    pub fn new(blockchain: &'a BlockChain) -> UtxoSet<'a> {
         UtxoSet { blockchain }
     }
 
-    pub fn reindex(&self) {
+    pub fn get_blockchain(&self) -> &BlockChain {
+        self.blockchain
+    }
+
+    /// Rebuild the UTXO set and the address index from the chain of the tip
+    pub fn reindex(&self) -> Result<()> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        let address_tree = db.open_tree(UTXO_ADDRESS_TREE)?;
+        let meta_tree = db.open_tree(UTXO_META_TREE)?;
+        // the UTXO set is built for no block until the reindex is done
+        meta_tree.remove(UTXO_BEST_BLOCK_KEY)?;
+        utxo_tree.clear()?;
+        address_tree.clear()?;
+
+        let utxo_map = self.blockchain.find_utxo_with_height()?;
+        for (txid, (height, outs)) in utxo_map {
+            for (vout, out) in outs {
+                let value = bincode::serialize(&out)?;
+                utxo_tree.insert(utxo_key(&txid, vout), value)?;
+                let entry = AddressIndexEntry {
+                    value: out.get_value(),
+                    height,
+                };
+                address_tree.insert(
+                    address_key(out.get_pub_key_hash(), &txid, vout),
+                    bincode::serialize(&entry)?,
+                )?;
+            }
+        }
+        meta_tree.insert(UTXO_BEST_BLOCK_KEY, self.blockchain.get_tip_hash().as_ref())?;
+        self.set_schema_version()
+    }
+
+    /// The block the UTXO set is built for, see `UTXO_BEST_BLOCK_KEY`
+    pub fn get_best_block(&self) -> Result<Option<BlockHash>> {
+        let db = self.blockchain.get_db();
+        let meta_tree = db.open_tree(UTXO_META_TREE)?;
+        match meta_tree.get(UTXO_BEST_BLOCK_KEY)? {
+            Some(data) => Ok(Some(BlockHash::from_slice(&data).map_err(|err| {
+                Error::Corruption(format!("invalid best block of the UTXO set: {}", err))
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Convert the `chainstate` tree from an older schema.
+    /// Version 1 lost the original output indices, and version 2 has no address index,
+    /// so they can not be converted in place, the trees are rebuilt from the chain instead.
+    ///
+    /// A UTXO set of the current schema is rebuilt too if it is not built for the tip,
+    /// e.g. the node stopped in the middle of a `reindex`.
+    /// Return true if the UTXO set was rebuilt.
+    pub fn migrate(&self) -> Result<bool> {
+        let db = self.blockchain.get_db();
+        let meta_tree = db.open_tree(UTXO_META_TREE)?;
+        let version = meta_tree.get(UTXO_SCHEMA_VERSION_KEY)?;
+        if let Some(version) = &version {
+            if version.as_ref() == UTXO_SCHEMA_VERSION.to_be_bytes() {
+                if self.get_best_block()? == Some(self.blockchain.get_tip_hash()) {
+                    return Ok(false);
+                }
+                self.reindex()?;
+                return Ok(true);
+            }
+        }
+
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        let mut has_old_keys = false;
+        for key in utxo_tree.iter().keys() {
+            if key?.len() == HASH_LENGTH {
+                has_old_keys = true;
+                break;
+            }
+        }
+        if has_old_keys || version.is_some() {
+            self.reindex()?;
+            Ok(true)
+        } else {
+            self.set_schema_version()?;
+            Ok(false)
+        }
+    }
+
+    fn set_schema_version(&self) -> Result<()> {
+        let db = self.blockchain.get_db();
+        let meta_tree = db.open_tree(UTXO_META_TREE)?;
+        meta_tree.insert(UTXO_SCHEMA_VERSION_KEY, &UTXO_SCHEMA_VERSION.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Apply a new block to the UTXO set, instead of walking the whole chain like `reindex`:
+    ///   1. the outputs spent by the inputs of the block are removed
+    ///   2. the outputs of the block's transactions are added
+    ///
+    /// The address index is changed the same way, and the spent outputs are kept
+    /// in the undo record of the block, so `disconnect` can restore them.
+    /// All changes are done in one sled transaction, which also makes the block the tip
+    /// in the db, so the tip and the UTXO set always match.
+    pub fn update(&self, block: &Block) -> Result<()> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        let address_tree = db.open_tree(UTXO_ADDRESS_TREE)?;
+        let undo_tree = db.open_tree(UTXO_UNDO_TREE)?;
+        let meta_tree = db.open_tree(UTXO_META_TREE)?;
+        let headers_tree = db.open_tree(HEADERS_TREE_NAME)?;
+        let trees = (
+            &utxo_tree,
+            &address_tree,
+            &undo_tree,
+            &meta_tree,
+            &headers_tree,
+        );
+        trees.transaction(|(utxo_tx, address_tx, undo_tx, meta_tx, headers_tx)| {
+            let abort = |err: Error| ConflictableTransactionError::Abort(err);
+            let mut spent_outputs = vec![];
+            for tx in block.get_transactions() {
+                if !tx.is_coinbase() {
+                    for vin in tx.get_vin() {
+                        let key = utxo_key(&vin.get_txid(), vin.get_vout());
+                        let data = utxo_tx.remove(key)?.ok_or_else(|| {
+                            abort(Error::Corruption(format!(
+                                "spent output {}:{} is not in the UTXO set",
+                                vin.get_txid(),
+                                vin.get_vout()
+                            )))
+                        })?;
+                        let out: TXOutput =
+                            bincode::deserialize(&data).map_err(|err| abort(err.into()))?;
+                        let entry = address_tx
+                            .remove(address_key(
+                                out.get_pub_key_hash(),
+                                &vin.get_txid(),
+                                vin.get_vout(),
+                            ))?
+                            .ok_or_else(|| {
+                                abort(Error::Corruption(format!(
+                                    "spent output {}:{} is not in the address index",
+                                    vin.get_txid(),
+                                    vin.get_vout()
+                                )))
+                            })?;
+                        let entry: AddressIndexEntry =
+                            bincode::deserialize(&entry).map_err(|err| abort(err.into()))?;
+                        spent_outputs.push(SpentOutput {
+                            txid: vin.get_txid(),
+                            vout: vin.get_vout(),
+                            output: out,
+                            height: entry.height,
+                        });
+                    }
+                }
+                for (vout, out) in tx.get_vout().iter().enumerate() {
+                    let value = bincode::serialize(out).map_err(|err| abort(err.into()))?;
+                    utxo_tx.insert(utxo_key(&tx.get_id(), vout), value)?;
+                    let entry = AddressIndexEntry {
+                        value: out.get_value(),
+                        height: block.get_height(),
+                    };
+                    let entry = bincode::serialize(&entry).map_err(|err| abort(err.into()))?;
+                    address_tx.insert(
+                        address_key(out.get_pub_key_hash(), &tx.get_id(), vout),
+                        entry,
+                    )?;
+                }
+            }
+            let undo = bincode::serialize(&spent_outputs).map_err(|err| abort(err.into()))?;
+            undo_tx.insert(block.get_hash().as_ref(), undo)?;
+            meta_tx.insert(UTXO_BEST_BLOCK_KEY, block.get_hash().as_ref())?;
+            headers_tx.insert(TIP_BLOCK_HASH_KEY, block.get_hash().as_ref())?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Undo `update` for a block, which must be the last block applied to the UTXO set:
+    ///   1. the outputs of the block's transactions are removed
+    ///   2. the outputs spent by the block are restored from its undo record
+    ///
+    /// The transactions are undone from the last one, so an output created and spent
+    /// in the same block is removed in the end.
+    /// All changes are done in one sled transaction, which also makes the parent of the block
+    /// the tip in the db, and the undo record is removed.
+    pub fn disconnect(&self, block: &Block) -> Result<()> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        let address_tree = db.open_tree(UTXO_ADDRESS_TREE)?;
+        let undo_tree = db.open_tree(UTXO_UNDO_TREE)?;
+        let meta_tree = db.open_tree(UTXO_META_TREE)?;
+        let headers_tree = db.open_tree(HEADERS_TREE_NAME)?;
+        let block_hash = block.get_hash();
+        let undo = undo_tree.get(block_hash)?.ok_or_else(|| {
+            Error::Corruption(format!("undo record of block {} is missing", block_hash))
+        })?;
+        let spent_outputs: Vec<SpentOutput> = bincode::deserialize(&undo)
+            .map_err(|err| Error::Corruption(format!("invalid undo record: {}", err)))?;
+
+        let trees = (
+            &utxo_tree,
+            &address_tree,
+            &undo_tree,
+            &meta_tree,
+            &headers_tree,
+        );
+        trees.transaction(|(utxo_tx, address_tx, undo_tx, meta_tx, headers_tx)| {
+            let abort = |err: Error| ConflictableTransactionError::Abort(err);
+            let mut spent_outputs = spent_outputs.iter().rev();
+            for tx in block.get_transactions().iter().rev() {
+                for (vout, out) in tx.get_vout().iter().enumerate() {
+                    utxo_tx.remove(utxo_key(&tx.get_id(), vout))?;
+                    address_tx.remove(address_key(out.get_pub_key_hash(), &tx.get_id(), vout))?;
+                }
+                if tx.is_coinbase() {
+                    continue;
+                }
+                for vin in tx.get_vin().iter().rev() {
+                    let spent = spent_outputs
+                        .next()
+                        .filter(|spent| {
+                            spent.txid == vin.get_txid() && spent.vout == vin.get_vout()
+                        })
+                        .ok_or_else(|| {
+                            abort(Error::Corruption(format!(
+                                "undo record of block {} does not match its inputs",
+                                block_hash
+                            )))
+                        })?;
+                    let value =
+                        bincode::serialize(&spent.output).map_err(|err| abort(err.into()))?;
+                    utxo_tx.insert(utxo_key(&spent.txid, spent.vout), value)?;
+                    let entry = AddressIndexEntry {
+                        value: spent.output.get_value(),
+                        height: spent.height,
+                    };
+                    let entry = bincode::serialize(&entry).map_err(|err| abort(err.into()))?;
+                    address_tx.insert(
+                        address_key(spent.output.get_pub_key_hash(), &spent.txid, spent.vout),
+                        entry,
+                    )?;
+                }
+            }
+            if spent_outputs.next().is_some() {
+                return Err(abort(Error::Corruption(format!(
+                    "undo record of block {} has more outputs than its inputs",
+                    block_hash
+                ))));
+            }
+            undo_tx.remove(block_hash.as_ref())?;
+            let pre_block_hash = block.get_pre_block_hash();
+            meta_tx.insert(UTXO_BEST_BLOCK_KEY, pre_block_hash.as_ref())?;
+            headers_tx.insert(TIP_BLOCK_HASH_KEY, pre_block_hash.as_ref())?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// True if the block has an undo record, blocks connected by an older version have none
+    pub fn has_undo(&self, block_hash: &BlockHash) -> Result<bool> {
+        let db = self.blockchain.get_db();
+        let undo_tree = db.open_tree(UTXO_UNDO_TREE)?;
+        Ok(undo_tree.contains_key(block_hash)?)
+    }
+
+    /// Return the output if it is unspent
+    pub fn get_output(&self, txid: &Txid, vout: usize) -> Result<Option<TXOutput>> {
         let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        let _ = utxo_tree.clear().unwrap();
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        match utxo_tree.get(utxo_key(txid, vout))? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
 
-        let utxo_map = self.blockchain.find_utxo();
-        for (txid, outs) in utxo_map {
-            let txid = HEXLOWER.decode(txid.as_bytes()).unwrap();
-            let value = bincode::serialize(&outs).unwrap();
-            let _ = utxo_tree.insert(txid.as_slice(), value).unwrap();
+    /// Number of transactions which still have unspent outputs
+    pub fn count_transactions(&self) -> Result<usize> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        let mut count = 0;
+        let mut last_txid = None;
+        for key in utxo_tree.iter().keys() {
+            let (txid, _) = parse_utxo_key(&key?)?;
+            if last_txid != Some(txid) {
+                count += 1;
+                last_txid = Some(txid);
+            }
         }
+        Ok(count)
     }
 
+    /// Return the accumulated value, and the real `vout` indices of the selected outputs by txid
     pub fn find_spendable_outputs(
         &self,
         pub_key_hash: &[u8],
         amount: i32,
-    ) -> (i32, HashMap<String, Vec<usize>>) {
-        let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
+    ) -> Result<(i32, HashMap<Txid, Vec<usize>>)> {
+        let mut unspent_outputs: HashMap<Txid, Vec<usize>> = HashMap::new();
         let mut accumulated = 0;
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        for item in utxo_tree.iter() {
-            let (k, v) = item.unwrap();
-            let txid_hex = HEXLOWER.encode(k.to_vec().as_slice());
-            let outs: Vec<TXOutput> = bincode::deserialize(v.to_vec().as_slice()).unwrap();
-            for (idx, out) in outs.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += out.get_value();
-                    if unspent_outputs.contains_key(txid_hex.as_str()) {
-                        unspent_outputs
-                            .get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(idx);
-                    } else {
-                        unspent_outputs.insert(txid_hex.clone(), vec![idx]);
-                    }
-                }
+        for out in self.list_unspent(pub_key_hash)? {
+            if accumulated >= amount {
+                break;
             }
+            accumulated += out.value;
+            unspent_outputs.entry(out.txid).or_default().push(out.vout);
+        }
+        Ok((accumulated, unspent_outputs))
+    }
+
+    /// All unspent outputs locked with `pub_key_hash`, sorted by txid and vout.
+    /// They are read from the address index, not by scanning the whole UTXO set.
+    pub fn list_unspent(&self, pub_key_hash: &[u8]) -> Result<Vec<UnspentOutput>> {
+        let db = self.blockchain.get_db();
+        let address_tree = db.open_tree(UTXO_ADDRESS_TREE)?;
+        let prefix = address_prefix(pub_key_hash);
+        let mut outputs = vec![];
+        for item in address_tree.scan_prefix(&prefix) {
+            let (k, v) = item?;
+            let (txid, vout) = parse_utxo_key(&k[prefix.len()..])?;
+            let entry: AddressIndexEntry = bincode::deserialize(&v)?;
+            outputs.push(UnspentOutput {
+                txid,
+                vout,
+                value: entry.value,
+                height: entry.height,
+            });
+        }
+        Ok(outputs)
+    }
+
+    /// Sum of the unspent outputs locked with `pub_key_hash`
+    pub fn get_balance(&self, pub_key_hash: &[u8]) -> Result<i64> {
+        Ok(self
+            .list_unspent(pub_key_hash)?
+            .iter()
+            .map(|out| out.value as i64)
+            .sum())
+    }
+
+    /// All unspent outputs locked with `pub_key_hash`, for coin selection
+    pub fn get_spendable_outputs(&self, pub_key_hash: &[u8]) -> Result<Vec<SpendableOutput>> {
+        Ok(self
+            .list_unspent(pub_key_hash)?
+            .into_iter()
+            .map(|out| SpendableOutput::new(out.txid, out.vout, out.value))
+            .collect())
+    }
+
+    /// Pick the outputs of `pub_key_hash` which pay `amount` with the given strategy.
+    /// Fails with `Error::InsufficientFunds` if all the outputs together are not enough,
+    /// or with `Error::InvalidTransaction` if the strategy finds no selection.
+    pub fn select_coins(
+        &self,
+        pub_key_hash: &[u8],
+        amount: i32,
+        selector: &dyn CoinSelector,
+    ) -> Result<CoinSelection> {
+        let outputs = self.get_spendable_outputs(pub_key_hash)?;
+        let available: i64 = outputs
+            .iter()
+            .filter(|out| out.get_value() > 0)
+            .map(|out| out.get_value() as i64)
+            .sum();
+        if available < amount as i64 {
+            return Err(Error::InsufficientFunds {
+                needed: amount,
+                available: i32::try_from(available).unwrap_or(i32::MAX),
+            });
         }
-        (accumulated, unspent_outputs)
+        selector.select(&outputs, amount).ok_or_else(|| {
+            Error::InvalidTransaction(format!("no selection of outputs pays {}", amount))
+        })
     }
 }
@@ -1,26 +1,27 @@
-use crate::utils::base58_encode;
+use crate::address::{Address, DEFAULT_NETWORK};
+use crate::error::{Error, Result};
 use crate::utils::new_key_pair;
 use crate::utils::ripemd160_digest;
 use crate::utils::sha256_digest;
 use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
 use serde::{Deserialize, Serialize};
 
-const VERSION: u8 = 0x00;
-pub const ADDRESS_CHECK_SUM_LENGTH: usize = 4;
+pub use crate::address::{ADDRESS_CHECK_SUM_LENGTH, PUB_KEY_HASH_LENGTH};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Wallet {
     pkcs8: Vec<u8>,
     public_key: Vec<u8>,
 }
 
 impl Wallet {
-    pub fn new() -> Wallet {
-        let pkcs8 = new_key_pair();
-        let key_pair =
-            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+    /// A wallet with a new random key pair
+    pub fn new() -> Result<Wallet> {
+        let pkcs8 = new_key_pair()?;
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref())
+            .map_err(|err| Error::Crypto(format!("invalid pkcs8 key: {}", err)))?;
         let public_key = key_pair.public_key().as_ref().to_vec();
-        Wallet { pkcs8, public_key }
+        Ok(Wallet { pkcs8, public_key })
     }
 
     pub fn get_public_key(&self) -> &[u8] {
@@ -31,14 +32,9 @@ impl Wallet {
         self.pkcs8.as_slice()
     }
 
+    /// The address of the wallet on the `DEFAULT_NETWORK`
     pub fn get_address(&self) -> String {
-        let pub_key_hash = hash_pub_key(self.public_key.as_slice());
-        let mut payload = vec![];
-        payload.push(VERSION);
-        payload.extend(pub_key_hash.as_slice());
-        let checksum = checksum(payload.as_slice());
-        payload.extend(checksum.as_slice());
-        base58_encode(payload.as_slice())
+        Address::from_pub_key(DEFAULT_NETWORK, self.public_key.as_slice()).to_string()
     }
 }
 
@@ -47,8 +43,11 @@ pub fn hash_pub_key(pub_key: &[u8]) -> Vec<u8> {
     ripemd160_digest(&pub_key_sha256)
 }
 
-fn checksum(payload: &[u8]) -> Vec<u8> {
-    let first_sha = sha256_digest(payload);
-    let second_sha = sha256_digest(first_sha.as_slice());
-    second_sha[0..ADDRESS_CHECK_SUM_LENGTH].to_vec()
+/// Extract the public key hash from an address.
+/// The address is checked like `validate_address`: length, version byte, checksum and network.
+pub fn decode_address(address: &str) -> Result<Vec<u8>> {
+    let address = address
+        .parse::<Address>()?
+        .require_network(DEFAULT_NETWORK)?;
+    Ok(address.get_pub_key_hash().to_vec())
 }
@@ -0,0 +1,107 @@
+use crate::error::{Error, Result};
+use crate::wallet::Wallet;
+use std::collections::HashMap;
+use std::env::current_dir;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+pub const WALLET_FILE: &str = "wallet.dat";
+
+/// Wallets keeps all the wallets of a node, the key is the address of the wallet.
+/// The whole map is saved into one file with bincode, so the keys are kept between runs.
+pub struct Wallets {
+    wallets: HashMap<String, Wallet>,
+    path: PathBuf,
+}
+
+impl Wallets {
+    /// Load the wallets from `current_dir()/wallet.dat`
+    pub fn new() -> Result<Wallets> {
+        Wallets::open(current_dir()?.join(WALLET_FILE))
+    }
+
+    /// Load the wallets from the given file, if the file does not exist, start with no wallet
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Wallets> {
+        let mut wallets = Wallets {
+            wallets: HashMap::new(),
+            path: path.as_ref().to_path_buf(),
+        };
+        wallets.load_from_file()?;
+        Ok(wallets)
+    }
+
+    /// Create a new wallet, save it into the file and return its address
+    pub fn create_wallet(&mut self) -> Result<String> {
+        let wallet = Wallet::new()?;
+        let address = wallet.get_address();
+        self.wallets.insert(address.clone(), wallet);
+        self.save_to_file()?;
+        Ok(address)
+    }
+
+    pub fn get_addresses(&self) -> Vec<String> {
+        let mut addresses: Vec<String> = self.wallets.keys().cloned().collect();
+        addresses.sort();
+        addresses
+    }
+
+    pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
+        self.wallets.get(address)
+    }
+
+    fn load_from_file(&mut self) -> Result<()> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+        let data = fs::read(&self.path)?;
+        self.wallets = bincode::deserialize(data.as_slice()).map_err(|err| {
+            Error::Corruption(format!("failed to deserialize wallet file: {}", err))
+        })?;
+        Ok(())
+    }
+
+    /// Write the wallets into a temporary file next to the wallet file, then rename it.
+    /// The rename is atomic, so the wallet file is never left half written.
+    /// The file has the private keys, on unix only the owner can read it.
+    fn save_to_file(&self) -> Result<()> {
+        let data = bincode::serialize(&self.wallets)?;
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        // a temporary file left by a crash may have other permissions
+        match fs::remove_file(&tmp_path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(data.as_slice())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        Self::sync_parent_dir(&self.path)
+    }
+
+    /// Make the rename durable, it is an entry of the directory.
+    /// Directories can not be opened as files on other systems, there it is left out.
+    #[cfg(unix)]
+    fn sync_parent_dir(path: &Path) -> Result<()> {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn sync_parent_dir(_path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
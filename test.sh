@@ -1,5 +1,3 @@
 #!/bin/bash
 
-rm -rf ./blockchain_data/
 cargo test
-rm -rf ./blockchain_data/